@@ -1,5 +1,5 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
@@ -13,7 +13,61 @@ fn main() {
 
     println!("cargo:rerun-if-changed=memory.x");
 
+    if env::var_os("CARGO_FEATURE_BUNDLED_FILES").is_some() {
+        write_bundle_manifest(out);
+    }
+
+    if env::var_os("CARGO_FEATURE_SD_ONLY").is_none() {
+        check_wifi_firmware();
+    }
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Fails the build with a pointer to the missing blobs, instead of leaving it to an
+/// `include_bytes!` error in main.rs.
+fn check_wifi_firmware() {
+    let dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("cyw43-firmware");
+    for name in ["43439A0.bin", "43439A0_clm.bin"] {
+        let path = dir.join(name);
+        println!("cargo:rerun-if-changed={}", path.display());
+        if !path.is_file() {
+            panic!(
+                "\n\nWiFi firmware blob {} is missing.\n\
+                 Download 43439A0.bin and 43439A0_clm.bin from\n\
+                 https://github.com/embassy-rs/embassy/tree/main/cyw43-firmware\n\
+                 into {}/, or build with --features sd-only to leave WiFi out.\n",
+                path.display(),
+                dir.display()
+            );
+        }
+    }
+}
+
+/// Generates the `FILES` table for `src/bundle.rs` from the files in `bundle/`.
+fn write_bundle_manifest(out: &PathBuf) {
+    let dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("bundle");
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dir)
+        .expect("the bundled-files feature needs a bundle/ directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut manifest = String::from("pub static FILES: &[crate::store::MemoryFile] = &[\n");
+    for path in &paths {
+        let name = path.file_name().unwrap().to_str().expect("bundle file names must be UTF-8");
+        assert!(name.len() <= 12, "bundle/{} is longer than an 8.3 name", name);
+        manifest.push_str(&format!(
+            "    crate::store::MemoryFile {{ name: {:?}, data: include_bytes!({:?}), is_dir: false, fat_date: 0, fat_time: 0 }},\n",
+            name.to_ascii_uppercase(),
+            path.to_str().unwrap()
+        ));
+    }
+    manifest.push_str("];\n");
+    fs::write(out.join("bundle.rs"), manifest).unwrap();
+}
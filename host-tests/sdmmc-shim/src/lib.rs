@@ -0,0 +1,43 @@
+//! The block-device types `src/fat.rs` takes from `embedded-sdmmc` 0.8, with the same
+//! names and signatures. The host tests can't fetch the real crate, and only need these.
+
+#![no_std]
+
+/// One 512-byte block on the card.
+#[derive(Clone)]
+pub struct Block {
+    pub contents: [u8; Block::LEN],
+}
+
+impl Block {
+    pub const LEN: usize = 512;
+    pub const LEN_U32: u32 = 512;
+
+    pub const fn new() -> Block {
+        Block { contents: [0; Block::LEN] }
+    }
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Block::new()
+    }
+}
+
+/// The number of a block on the card.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockIdx(pub u32);
+
+/// A number of blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockCount(pub u32);
+
+pub trait BlockDevice {
+    type Error: core::fmt::Debug;
+
+    fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error>;
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error>;
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error>;
+}
@@ -0,0 +1,22 @@
+//! The firmware's modules that don't touch the hardware, built for the host so their
+//! `#[cfg(test)]` tests can run with `cargo test` in this directory.
+
+// Only the tests call into these
+#![allow(dead_code)]
+
+#[path = "../../src/fat.rs"]
+mod fat;
+#[path = "../../src/gzip.rs"]
+mod gzip;
+#[path = "../../src/i18n.rs"]
+mod i18n;
+#[path = "../../src/json.rs"]
+mod json;
+#[path = "../../src/mock_sd.rs"]
+mod mock_sd;
+#[path = "../../src/store.rs"]
+mod store;
+#[path = "../../src/text.rs"]
+mod text;
+#[path = "../../src/wifi_config.rs"]
+mod wifi_config;
@@ -0,0 +1,105 @@
+//! Just enough BMP handling to downscale uncompressed images into thumbnails.
+//!
+//! Only 24- and 32-bit `BI_RGB` files are read, which covers what most tools write by
+//! default. Thumbnails are always written as bottom-up 24-bit BMPs.
+
+/// Size of the file header plus a `BITMAPINFOHEADER`.
+pub const HEADER_LEN: usize = 54;
+
+const MAX_DIMENSION: i32 = 16384;
+
+/// Layout of a source image, from its headers.
+#[derive(Clone, Copy)]
+pub struct BmpInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bytes_per_pixel: u32,
+    pixel_offset: u32,
+    // Rows are stored bottom row first unless the height field is negative
+    bottom_up: bool,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+/// Parses the first `HEADER_LEN` bytes of a file. Returns `None` for anything this module
+/// can't scale: other bit depths, compressed or palette images, or absurd dimensions.
+pub fn parse_header(bytes: &[u8]) -> Option<BmpInfo> {
+    if bytes.len() < HEADER_LEN || &bytes[..2] != b"BM" || u32_at(bytes, 14) < 40 {
+        return None;
+    }
+
+    let width = u32_at(bytes, 18) as i32;
+    let height = u32_at(bytes, 22) as i32;
+    let bytes_per_pixel = match u16_at(bytes, 28) {
+        24 => 3,
+        32 => 4,
+        _ => return None,
+    };
+    if u16_at(bytes, 26) != 1 || u32_at(bytes, 30) != 0 {
+        return None;
+    }
+    // Keeps all offset arithmetic comfortably inside u32
+    if !(1..=MAX_DIMENSION).contains(&width) || !(1..=MAX_DIMENSION).contains(&height.abs()) {
+        return None;
+    }
+
+    Some(BmpInfo {
+        width: width as u32,
+        height: height.unsigned_abs(),
+        bytes_per_pixel,
+        pixel_offset: u32_at(bytes, 10),
+        bottom_up: height > 0,
+    })
+}
+
+/// Bytes per row of a 24-bit image, padded to a multiple of four as BMP requires.
+pub const fn row_stride_24(width: u32) -> u32 {
+    (width * 3 + 3) & !3
+}
+
+impl BmpInfo {
+    fn row_stride(&self) -> u32 {
+        (self.width * self.bytes_per_pixel + 3) & !3
+    }
+
+    /// File offset of source row `y`, counted from the top of the image.
+    pub fn row_offset(&self, y: u32) -> u32 {
+        let stored = if self.bottom_up { self.height - 1 - y } else { y };
+        self.pixel_offset + stored * self.row_stride()
+    }
+
+    /// Thumbnail dimensions that fit in `max` x `max` and keep the aspect ratio. Images
+    /// that already fit are left at their own size.
+    pub fn thumb_size(&self, max: u32) -> (u32, u32) {
+        if self.width <= max && self.height <= max {
+            return (self.width, self.height);
+        }
+        if self.width >= self.height {
+            (max, (self.height * max / self.width).max(1))
+        } else {
+            ((self.width * max / self.height).max(1), max)
+        }
+    }
+}
+
+/// Headers for a bottom-up 24-bit image of the given size.
+pub fn header(width: u32, height: u32) -> [u8; HEADER_LEN] {
+    let image_len = row_stride_24(width) * height;
+    let mut header = [0u8; HEADER_LEN];
+    header[0..2].copy_from_slice(b"BM");
+    header[2..6].copy_from_slice(&(HEADER_LEN as u32 + image_len).to_le_bytes());
+    header[10..14].copy_from_slice(&(HEADER_LEN as u32).to_le_bytes());
+    header[14..18].copy_from_slice(&40u32.to_le_bytes());
+    header[18..22].copy_from_slice(&width.to_le_bytes());
+    header[22..26].copy_from_slice(&height.to_le_bytes());
+    header[26..28].copy_from_slice(&1u16.to_le_bytes());
+    header[28..30].copy_from_slice(&24u16.to_le_bytes());
+    header[34..38].copy_from_slice(&image_len.to_le_bytes());
+    header
+}
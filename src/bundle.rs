@@ -0,0 +1,8 @@
+//! Files compiled into the firmware with the `bundled-files` feature, listed and served
+//! in place of the card's root directory while no card can be read.
+//!
+//! `build.rs` generates `FILES` from the `bundle/` directory, upper-casing names to match
+//! how the card's short names are listed. They have no date, like files written by
+//! something without a clock.
+
+include!(concat!(env!("OUT_DIR"), "/bundle.rs"));
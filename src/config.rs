@@ -0,0 +1,120 @@
+//! Tunables for the HTTP server, gathered so memory can be traded against robustness in
+//! one place.
+//!
+//! Every HTTP server task (`LISTEN_SOCKETS` per network interface) owns its own socket
+//! buffers and request buffer, so the RAM cost of the sizes below is multiplied by the
+//! number of tasks.
+
+use embassy_time::Duration;
+
+/// TCP receive window per server socket. A single socket read never returns more than
+/// this, so it also bounds how much of a request the first read can see.
+#[cfg(not(feature = "sd-only"))]
+pub const SOCKET_RX_BUFFER: usize = 8192;
+
+/// TCP transmit buffer per server socket. Larger values let more of a response queue up
+/// before a write has to wait for the client to acknowledge.
+#[cfg(not(feature = "sd-only"))]
+pub const SOCKET_TX_BUFFER: usize = 8192;
+
+/// How long a connection may sit without any traffic before the stack drops it. This is
+/// the backstop while a response is being sent; the two timeouts below cover reading.
+#[cfg(not(feature = "sd-only"))]
+pub const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes read for the request line and headers. Anything past this in the first read is
+/// ignored, so it must hold the longest request line we expect plus typical browser
+/// headers (roughly 500-800 bytes).
+#[cfg(not(feature = "sd-only"))]
+pub const REQUEST_BUFFER: usize = 2048;
+
+/// How long a connection may stay open without starting a request. Browsers open spare
+/// connections they may never use, and each one holds a server socket, so this is short.
+#[cfg(not(feature = "sd-only"))]
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long to wait for each further piece of a request that has started arriving (the
+/// body chunks of an upload) before giving up.
+#[cfg(not(feature = "sd-only"))]
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request line we intend to support: `POST /copy?from=...&to=...&force=1`
+/// with both names fully percent-encoded (64 characters, 3 bytes each).
+#[cfg(not(feature = "sd-only"))]
+pub const MAX_REQUEST_LINE: usize = 512;
+
+/// Sockets listening on port 80 per interface, each with its own server task and
+/// `SOCKET_RX_BUFFER + SOCKET_TX_BUFFER` (16 KB) of buffers, plus room in that task for
+/// the request buffer and the largest handler's locals. With more than one, a request
+/// no longer waits for a slow one ahead of it, though handlers still take turns on the
+/// SD card. 1 to 4.
+#[cfg(not(feature = "sd-only"))]
+pub const LISTEN_SOCKETS: usize = 1;
+
+/// Largest body accepted by `POST /append`. The body is buffered on the handler's stack
+/// before the SD bus is taken, so this is also that handler's stack cost.
+#[cfg(not(feature = "sd-only"))]
+pub const MAX_APPEND_SIZE: usize = 4096;
+
+/// Most files `POST /download-selected` will put in one archive, and the largest
+/// form body it accepts for them (every name fully percent-encoded, plus `name=&`).
+#[cfg(not(feature = "sd-only"))]
+pub const MAX_SELECTED_FILES: usize = 16;
+#[cfg(not(feature = "sd-only"))]
+pub const MAX_SELECTION_BODY: usize = MAX_SELECTED_FILES * (64 * 3 + 6);
+
+/// Port for `GET /api/stream`. The event feed holds its connection open, so it gets a
+/// socket of its own rather than tying up the page server; port 80 redirects there.
+#[cfg(not(feature = "sd-only"))]
+pub const STREAM_PORT: u16 = 8080;
+
+/// Socket buffers for the event feed. Its requests are one short line and its events a
+/// few dozen bytes each, so these are far smaller than the page server's.
+#[cfg(not(feature = "sd-only"))]
+pub const STREAM_RX_BUFFER: usize = 1024;
+#[cfg(not(feature = "sd-only"))]
+pub const STREAM_TX_BUFFER: usize = 2048;
+
+/// Longest an event feed connection is kept; clients reconnect to carry on. Also how
+/// often an idle feed sends a `ping` line, so a vanished client is noticed.
+#[cfg(not(feature = "sd-only"))]
+pub const STREAM_MAX_DURATION: Duration = Duration::from_secs(10 * 60);
+#[cfg(not(feature = "sd-only"))]
+pub const STREAM_PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// With every listening socket on an interface busy, a client that connects waits at the
+/// TCP level. An accept that completes within
+/// `BACKLOG_ACCEPT_WINDOW` of listening again means someone was already waiting; if the
+/// request before it took at least `BACKLOG_WARN_AFTER`, that wait is logged.
+#[cfg(not(feature = "sd-only"))]
+pub const BACKLOG_ACCEPT_WINDOW: Duration = Duration::from_millis(20);
+#[cfg(not(feature = "sd-only"))]
+pub const BACKLOG_WARN_AFTER: Duration = Duration::from_secs(2);
+
+/// A warning that keeps recurring unchanged (no card, accept failing) is logged once,
+/// then only summarised with its count this often until something else happens.
+pub const WARN_REPEAT_SUMMARY: Duration = Duration::from_secs(60);
+
+/// What a download does when a file's size disagrees with its cluster chain, as on a
+/// damaged card. Checking walks the chain in the FAT first: one block read per 128
+/// clusters on FAT32 (256 on FAT16), so a few tenths of a second for a 100 MB file.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum ChainCheck {
+    /// Serve without checking
+    Off,
+    /// Count and log the mismatch, then serve the file anyway
+    Warn,
+    /// Count and log it, and answer `422` instead of serving the wrong length
+    Refuse,
+}
+
+#[cfg(not(feature = "sd-only"))]
+pub const DOWNLOAD_CHAIN_CHECK: ChainCheck = ChainCheck::Warn;
+
+#[cfg(not(feature = "sd-only"))]
+const _: () = assert!(LISTEN_SOCKETS >= 1 && LISTEN_SOCKETS <= 4, "LISTEN_SOCKETS must be 1 to 4");
+#[cfg(not(feature = "sd-only"))]
+const _: () = assert!(REQUEST_BUFFER >= MAX_REQUEST_LINE, "request buffer can't hold the longest request line");
+#[cfg(not(feature = "sd-only"))]
+const _: () = assert!(REQUEST_BUFFER <= SOCKET_RX_BUFFER, "request buffer larger than one socket read can fill");
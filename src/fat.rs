@@ -0,0 +1,890 @@
+//! Raw parsing of the on-card structures that `embedded-sdmmc` keeps private:
+//! the MBR partition table, the FAT boot sector, the FAT32 FSInfo sector and FAT16 tables,
+//! plus a read-only consistency check of the root directory's cluster chains, and a
+//! minimal FAT32 formatter, since `embedded-sdmmc` can't create a filesystem.
+
+use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+/// One of the four primary partition entries in the MBR.
+#[derive(Clone, Copy)]
+pub struct Partition {
+    pub kind: u8,
+    pub start_lba: u32,
+    pub num_blocks: u32,
+}
+
+/// The subset of the BIOS parameter block needed for space accounting.
+// Everything that reads the fields serves a web page, which sd-only leaves out
+#[cfg_attr(feature = "sd-only", allow(dead_code))]
+#[derive(Clone, Copy)]
+pub struct BootSector {
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    pub root_entries: u16,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    pub fs_info_sector: u16,
+    // First cluster of the FAT32 root directory; zero for FAT12/16's fixed root region
+    pub root_cluster: u32,
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_block<D: BlockDevice>(device: &D, lba: u32) -> Option<Block> {
+    let mut blocks = [Block::new()];
+    device.read(&mut blocks, BlockIdx(lba)).ok()?;
+    let [block] = blocks;
+    Some(block)
+}
+
+/// Parses the partition table from block 0. Returns `None` without a 0x55AA signature.
+pub fn parse_mbr(block: &Block) -> Option<[Partition; 4]> {
+    let bytes = &block.contents;
+    if bytes[510] != 0x55 || bytes[511] != 0xAA {
+        return None;
+    }
+
+    Some(core::array::from_fn(|i| {
+        let entry = &bytes[446 + i * 16..446 + (i + 1) * 16];
+        Partition {
+            kind: entry[4],
+            start_lba: u32_at(entry, 8),
+            num_blocks: u32_at(entry, 12),
+        }
+    }))
+}
+
+/// Reads the partition table from block 0 of `device`.
+#[cfg(not(feature = "sd-only"))]
+pub fn read_mbr<D: BlockDevice>(device: &D) -> Option<[Partition; 4]> {
+    parse_mbr(&read_block(device, 0)?)
+}
+
+/// Human-readable name for the common MBR partition type bytes.
+#[cfg(not(feature = "sd-only"))]
+pub fn partition_type_name(kind: u8) -> &'static str {
+    match kind {
+        0x00 => "empty",
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x0B | 0x0C => "FAT32",
+        0x07 => "exFAT/NTFS",
+        0x83 => "Linux",
+        0xEE => "GPT protective",
+        _ => "other",
+    }
+}
+
+pub fn parse_boot_sector(block: &Block) -> Option<BootSector> {
+    let bytes = &block.contents;
+    let bytes_per_sector = u16_at(bytes, 11);
+    let sectors_per_cluster = bytes[13];
+    if bytes_per_sector == 0 || sectors_per_cluster == 0 {
+        return None;
+    }
+
+    let total_sectors = match u16_at(bytes, 19) {
+        0 => u32_at(bytes, 32),
+        small => small as u32,
+    };
+    // FAT12/16 store the FAT size at 22; FAT32 leaves it zero and uses the extended field
+    let (fat_size, fs_info_sector, root_cluster) = match u16_at(bytes, 22) {
+        0 => (u32_at(bytes, 36), u16_at(bytes, 48), u32_at(bytes, 44)),
+        small => (small as u32, 0, 0),
+    };
+
+    Some(BootSector {
+        bytes_per_sector,
+        sectors_per_cluster,
+        reserved_sectors: u16_at(bytes, 14),
+        num_fats: bytes[16],
+        root_entries: u16_at(bytes, 17),
+        total_sectors,
+        fat_size,
+        fs_info_sector,
+        root_cluster,
+    })
+}
+
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy, PartialEq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl FatType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FatType::Fat12 => "FAT12",
+            FatType::Fat16 => "FAT16",
+            FatType::Fat32 => "FAT32",
+        }
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl BootSector {
+    pub fn cluster_bytes(&self) -> u32 {
+        self.bytes_per_sector as u32 * self.sectors_per_cluster as u32
+    }
+
+    /// Sectors before the data region: reserved area, FATs and the fixed FAT12/16 root directory.
+    pub fn data_start_sector(&self) -> u32 {
+        let root_dir_sectors = (self.root_entries as u32 * 32).div_ceil(self.bytes_per_sector as u32);
+        self.reserved_sectors as u32 + self.num_fats as u32 * self.fat_size + root_dir_sectors
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.total_sectors.saturating_sub(self.data_start_sector()) / self.sectors_per_cluster as u32
+    }
+
+    /// The FAT variant is defined purely by the cluster count, per the Microsoft spec.
+    pub fn fat_type(&self) -> FatType {
+        match self.cluster_count() {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        }
+    }
+}
+
+// The exFAT check is only made by the card scan, which the mock card replaces
+
+/// exFAT writes this name where FAT keeps the BIOS parameter block, which it zeroes, so a
+/// FAT volume can't carry it.
+#[cfg(not(feature = "mock-sd"))]
+const EXFAT_NAME: &[u8; 8] = b"EXFAT   ";
+
+#[cfg(not(feature = "mock-sd"))]
+fn is_exfat(block: &Block) -> bool {
+    &block.contents[3..11] == EXFAT_NAME
+}
+
+/// Whether the card's first volume is exFAT, which `embedded-sdmmc` can't open. Block 0 is
+/// checked too, for cards formatted without a partition table: an exFAT boot sector also
+/// ends in 0x55AA and would otherwise be read as an MBR.
+#[cfg(not(feature = "mock-sd"))]
+pub fn first_volume_is_exfat<D: BlockDevice>(device: &D) -> bool {
+    let Some(block_0) = read_block(device, 0) else {
+        return false;
+    };
+    if is_exfat(&block_0) {
+        return true;
+    }
+    parse_mbr(&block_0)
+        .and_then(|partitions| read_block(device, partitions[0].start_lba))
+        .is_some_and(|boot| is_exfat(&boot))
+}
+
+/// Finds the first volume the same way `VolumeIdx(0)` does: partition 0 of the MBR.
+pub fn first_volume<D: BlockDevice>(device: &D) -> Option<(u32, BootSector)> {
+    let mbr = read_block(device, 0)?;
+    let start_lba = parse_mbr(&mbr)?[0].start_lba;
+    let boot = parse_boot_sector(&read_block(device, start_lba)?)?;
+    Some((start_lba, boot))
+}
+
+/// Clusters kept free beyond a write's own needs, so the directory can still grow by a
+/// cluster and the FAT is never left with nowhere to put a new chain.
+#[cfg(not(feature = "sd-only"))]
+const RESERVED_CLUSTERS: u64 = 2;
+
+/// Free space on the first volume, in whole clusters.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy)]
+pub struct FreeSpace {
+    pub free_clusters: u64,
+    pub cluster_bytes: u32,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl FreeSpace {
+    /// Whether `bytes` of new data fit, rounded up to whole clusters plus the reserve.
+    pub fn fits(&self, bytes: u64) -> bool {
+        let needed = bytes.div_ceil(self.cluster_bytes as u64) + RESERVED_CLUSTERS;
+        needed <= self.free_clusters
+    }
+}
+
+/// Free space on the first volume.
+///
+/// FAT32 volumes use the FSInfo free-cluster count, a hint maintained by whoever last
+/// wrote the card, so treat it as approximate. FAT16 volumes have no such hint, but their
+/// FAT is at most 256 blocks, so the free entries are counted directly. Returns `None`
+/// for FAT12 or when the FAT32 hint is unset.
+#[cfg(not(feature = "sd-only"))]
+pub fn free_space<D: BlockDevice>(device: &D) -> Option<FreeSpace> {
+    let (start_lba, boot) = first_volume(device)?;
+    let free_clusters = match boot.fat_type() {
+        FatType::Fat32 => fs_info_free_clusters(device, start_lba, &boot)?,
+        FatType::Fat16 => count_free_fat16(device, start_lba, &boot)?,
+        FatType::Fat12 => return None,
+    };
+    Some(FreeSpace {
+        free_clusters: free_clusters as u64,
+        cluster_bytes: boot.cluster_bytes(),
+    })
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn fs_info_free_clusters<D: BlockDevice>(device: &D, start_lba: u32, boot: &BootSector) -> Option<u32> {
+    if boot.fs_info_sector == 0 {
+        return None;
+    }
+
+    let fs_info = read_block(device, start_lba + boot.fs_info_sector as u32)?;
+    let bytes = &fs_info.contents;
+    if u32_at(bytes, 0) != 0x4161_5252 || u32_at(bytes, 484) != 0x6141_7272 {
+        return None;
+    }
+
+    match u32_at(bytes, 488) {
+        0xFFFF_FFFF => None,
+        // The hint can be stale; never report more than the volume holds
+        free_clusters => Some(free_clusters.min(boot.cluster_count())),
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn count_free_fat16<D: BlockDevice>(device: &D, start_lba: u32, boot: &BootSector) -> Option<u32> {
+    // Block reads are 512 bytes; other sector sizes would need offset translation
+    if boot.bytes_per_sector as usize != Block::LEN {
+        return None;
+    }
+
+    // Entries 0 and 1 are reserved; data clusters are numbered from 2
+    let entries = boot.cluster_count() + 2;
+    let per_block = (Block::LEN / 2) as u32;
+    let fat_lba = start_lba + boot.reserved_sectors as u32;
+    let mut free = 0;
+    for block_idx in 0..entries.div_ceil(per_block) {
+        let block = read_block(device, fat_lba + block_idx)?;
+        let first = block_idx * per_block;
+        for i in 0..per_block {
+            let cluster = first + i;
+            if (2..entries).contains(&cluster) && u16_at(&block.contents, i as usize * 2) == 0 {
+                free += 1;
+            }
+        }
+    }
+    Some(free)
+}
+
+/// Something wrong with a directory entry's cluster chain.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy)]
+pub enum Problem {
+    /// The first cluster isn't a data cluster on this volume
+    BadStart,
+    /// The chain points past the end of the volume
+    OutOfRange,
+    /// The chain runs into a free or bad-cluster entry before its end marker
+    Broken,
+    /// The chain is longer than the file's size needs, or loops back on itself
+    TooLong,
+    /// The chain ends before covering the file's size
+    TooShort,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl Problem {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Problem::BadStart => "first cluster outside the volume",
+            Problem::OutOfRange => "chain points outside the volume",
+            Problem::Broken => "chain hits a free or bad cluster",
+            Problem::TooLong => "chain longer than the file (or looping)",
+            Problem::TooShort => "chain shorter than the file",
+        }
+    }
+}
+
+/// Most problems `check_root_dir` lists by name; the rest are only counted.
+#[cfg(not(feature = "sd-only"))]
+pub const MAX_LISTED_PROBLEMS: usize = 16;
+
+/// Results of `check_root_dir`.
+#[cfg(not(feature = "sd-only"))]
+pub struct CheckReport {
+    pub fat_type: FatType,
+    pub files: u32,
+    pub dirs: u32,
+    pub bad_entries: u32,
+    /// 8.3 name and problem of the first `MAX_LISTED_PROBLEMS` bad entries
+    pub problems: heapless::Vec<([u8; 11], Problem), MAX_LISTED_PROBLEMS>,
+}
+
+/// Reads FAT entries, keeping the last FAT block so walking a chain doesn't re-read it
+/// for every cluster.
+#[cfg(not(feature = "sd-only"))]
+struct FatReader<'a, D: BlockDevice> {
+    device: &'a D,
+    fat_lba: u32,
+    fat_type: FatType,
+    cached: Option<(u32, Block)>,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl<D: BlockDevice> FatReader<'_, D> {
+    fn entry(&mut self, cluster: u32) -> Option<u32> {
+        let width = if self.fat_type == FatType::Fat32 { 4 } else { 2 };
+        let offset = cluster as usize * width;
+        let lba = self.fat_lba + (offset / Block::LEN) as u32;
+        if self.cached.as_ref().is_none_or(|(cached, _)| *cached != lba) {
+            self.cached = Some((lba, read_block(self.device, lba)?));
+        }
+        let bytes = &self.cached.as_ref()?.1.contents;
+        let within = offset % Block::LEN;
+        Some(match self.fat_type {
+            FatType::Fat32 => u32_at(bytes, within) & 0x0FFF_FFFF,
+            _ => u16_at(bytes, within) as u32,
+        })
+    }
+
+    fn is_end(&self, entry: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat32 => entry >= 0x0FFF_FFF8,
+            _ => entry >= 0xFFF8,
+        }
+    }
+
+    /// Follows the chain from `start` for at most `limit` clusters. Returns its length, or
+    /// the first problem found.
+    fn chain_len(&mut self, start: u32, last_cluster: u32, limit: u32) -> Result<u32, Option<Problem>> {
+        if !(2..=last_cluster).contains(&start) {
+            return Err(Some(Problem::BadStart));
+        }
+        let mut cluster = start;
+        let mut len = 1;
+        loop {
+            let next = self.entry(cluster).ok_or(None)?;
+            if self.is_end(next) {
+                return Ok(len);
+            }
+            if next < 2 || (self.fat_type != FatType::Fat32 && next == 0xFFF7) || next == 0x0FFF_FFF7 {
+                return Err(Some(Problem::Broken));
+            }
+            if next > last_cluster {
+                return Err(Some(Problem::OutOfRange));
+            }
+            if len == limit {
+                return Err(Some(Problem::TooLong));
+            }
+            cluster = next;
+            len += 1;
+        }
+    }
+
+    /// Checks a directory entry's cluster chain against the FAT and, for a file, its
+    /// size. `Err(None)` means the FAT couldn't be read.
+    fn check_entry(&mut self, entry: &[u8], last_cluster: u32, cluster_bytes: u32) -> Result<(), Option<Problem>> {
+        let high = if self.fat_type == FatType::Fat32 { u16_at(entry, 20) as u32 } else { 0 };
+        let start = (high << 16) | u16_at(entry, 26) as u32;
+        if is_dir_entry(entry) {
+            return self.chain_len(start, last_cluster, last_cluster).map(|_| ());
+        }
+        let needed = u32_at(entry, 28).div_ceil(cluster_bytes);
+        match (start, needed) {
+            (0, 0) => Ok(()),
+            (0, _) => Err(Some(Problem::BadStart)),
+            _ => match self.chain_len(start, last_cluster, needed.max(1)) {
+                Ok(len) if len < needed => Err(Some(Problem::TooShort)),
+                Ok(_) => Ok(()),
+                Err(problem) => Err(problem),
+            },
+        }
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn is_dir_entry(entry: &[u8]) -> bool {
+    entry[11] & 0x10 != 0
+}
+
+/// The first volume's root directory, read straight from the card.
+#[cfg(not(feature = "sd-only"))]
+struct RootDir<'a, D: BlockDevice> {
+    device: &'a D,
+    boot: BootSector,
+    fat: FatReader<'a, D>,
+    start_lba: u32,
+    last_cluster: u32,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl<'a, D: BlockDevice> RootDir<'a, D> {
+    /// `None` if the volume can't be read, for FAT12, or for sectors other than 512 bytes
+    /// (the same limits as the FAT16 free-space count).
+    fn open(device: &'a D) -> Option<Self> {
+        let (start_lba, boot) = first_volume(device)?;
+        let fat_type = boot.fat_type();
+        if fat_type == FatType::Fat12 || boot.bytes_per_sector as usize != Block::LEN {
+            return None;
+        }
+        Some(RootDir {
+            device,
+            fat: FatReader {
+                device,
+                fat_lba: start_lba + boot.reserved_sectors as u32,
+                fat_type,
+                cached: None,
+            },
+            last_cluster: boot.cluster_count() + 1,
+            start_lba,
+            boot,
+        })
+    }
+
+    /// Calls `f` with each entry that has data of its own (files and folders, but not
+    /// long-name fragments, the volume label or deleted entries) until it returns `true`.
+    /// `None` if the directory couldn't be read to the end.
+    fn each_entry(&mut self, mut f: impl FnMut(&mut FatReader<'a, D>, &[u8]) -> bool) -> Option<()> {
+        let boot = &self.boot;
+        let data_lba = self.start_lba + boot.data_start_sector();
+
+        // The root directory's own blocks: a fixed region on FAT16, a cluster chain on FAT32
+        let (mut block_lba, mut blocks_left, mut root_cluster) = match self.fat.fat_type {
+            FatType::Fat32 => {
+                self.fat.chain_len(boot.root_cluster, self.last_cluster, self.last_cluster).ok()?;
+                let first = data_lba + (boot.root_cluster - 2) * boot.sectors_per_cluster as u32;
+                (first, boot.sectors_per_cluster as u32, boot.root_cluster)
+            }
+            _ => {
+                let root_lba = self.start_lba + boot.reserved_sectors as u32 + boot.num_fats as u32 * boot.fat_size;
+                (root_lba, (boot.root_entries as u32 * 32).div_ceil(Block::LEN as u32), 0)
+            }
+        };
+
+        loop {
+            if blocks_left == 0 {
+                if root_cluster == 0 {
+                    return Some(());
+                }
+                // Next cluster of the FAT32 root; its chain was checked above
+                let next = self.fat.entry(root_cluster)?;
+                if self.fat.is_end(next) {
+                    return Some(());
+                }
+                root_cluster = next;
+                block_lba = data_lba + (root_cluster - 2) * boot.sectors_per_cluster as u32;
+                blocks_left = boot.sectors_per_cluster as u32;
+            }
+
+            let block = read_block(self.device, block_lba)?;
+            for entry in block.contents.chunks_exact(32) {
+                match entry[0] {
+                    0x00 => return Some(()),
+                    0xE5 => continue,
+                    _ => {}
+                }
+                let attributes = entry[11];
+                // Long-name fragments and the volume label have no data of their own
+                if attributes & 0x0F == 0x0F || attributes & 0x08 != 0 {
+                    continue;
+                }
+                if f(&mut self.fat, entry) {
+                    return Some(());
+                }
+            }
+
+            block_lba += 1;
+            blocks_left -= 1;
+        }
+    }
+}
+
+/// Walks the first volume's root directory and checks every entry's cluster chain
+/// against the FAT and the entry's size. Only reads from the card.
+///
+/// Returns `None` if the volume can't be read, for FAT12, or for sectors other than 512
+/// bytes.
+#[cfg(not(feature = "sd-only"))]
+pub fn check_root_dir<D: BlockDevice>(device: &D) -> Option<CheckReport> {
+    let mut root = RootDir::open(device)?;
+    let (last_cluster, cluster_bytes) = (root.last_cluster, root.boot.cluster_bytes());
+    let mut report = CheckReport {
+        fat_type: root.fat.fat_type,
+        files: 0,
+        dirs: 0,
+        bad_entries: 0,
+        problems: heapless::Vec::new(),
+    };
+    let mut fat_unreadable = false;
+
+    root.each_entry(|fat, entry| {
+        if is_dir_entry(entry) {
+            report.dirs += 1;
+        } else {
+            report.files += 1;
+        }
+        match fat.check_entry(entry, last_cluster, cluster_bytes) {
+            Ok(()) => false,
+            // The FAT couldn't be read at all, so nothing below it can be trusted
+            Err(None) => {
+                fat_unreadable = true;
+                true
+            }
+            Err(Some(problem)) => {
+                report.bad_entries += 1;
+                let mut name = [0u8; 11];
+                name.copy_from_slice(&entry[..11]);
+                let _ = report.problems.push((name, problem));
+                false
+            }
+        }
+    })?;
+    (!fat_unreadable).then_some(report)
+}
+
+/// Checks the cluster chain of the root-directory file `name` (an 8.3 name, matched
+/// case-insensitively) against its size, so a damaged entry is caught before its
+/// declared length is served. Only reads from the card.
+///
+/// `None` means nothing wrong was found, including when the file or the volume couldn't
+/// be checked (the same limits as `check_root_dir`).
+#[cfg(not(feature = "sd-only"))]
+pub fn check_file<D: BlockDevice>(device: &D, name: &str) -> Option<Problem> {
+    let mut root = RootDir::open(device)?;
+    let (last_cluster, cluster_bytes) = (root.last_cluster, root.boot.cluster_bytes());
+    let mut problem = None;
+    root.each_entry(|fat, entry| {
+        let mut raw = [0u8; 11];
+        raw.copy_from_slice(&entry[..11]);
+        if is_dir_entry(entry) || !short_name(&raw).eq_ignore_ascii_case(name) {
+            return false;
+        }
+        problem = fat.check_entry(entry, last_cluster, cluster_bytes).err().flatten();
+        true
+    });
+    problem
+}
+
+/// Formats a raw 8.3 directory name as `NAME.EXT`.
+#[cfg(not(feature = "sd-only"))]
+pub fn short_name(raw: &[u8; 11]) -> heapless::String<12> {
+    let mut name = heapless::String::new();
+    let base = raw[..8].iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    let ext = raw[8..].iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+    for &b in &raw[..base] {
+        let _ = name.push(if b.is_ascii_graphic() { b as char } else { '?' });
+    }
+    if ext > 0 {
+        let _ = name.push('.');
+        for &b in &raw[8..8 + ext] {
+            let _ = name.push(if b.is_ascii_graphic() { b as char } else { '?' });
+        }
+    }
+    name
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn write_blocks<D: BlockDevice>(device: &D, lba: u32, blocks: &[Block]) -> Result<(), &'static str> {
+    device.write(blocks, BlockIdx(lba)).map_err(|_| "Block write failed")
+}
+
+/// Where the formatted volume starts: 4 MiB in, the alignment SD cards are erased in.
+#[cfg(not(feature = "sd-only"))]
+const FORMAT_START_LBA: u32 = 8192;
+#[cfg(not(feature = "sd-only"))]
+const FORMAT_RESERVED_SECTORS: u16 = 32;
+#[cfg(not(feature = "sd-only"))]
+const FORMAT_NUM_FATS: u8 = 2;
+// Offsets of the FSInfo sector and the boot sector's backup within the reserved area
+#[cfg(not(feature = "sd-only"))]
+const FORMAT_FS_INFO_SECTOR: u16 = 1;
+#[cfg(not(feature = "sd-only"))]
+const FORMAT_BACKUP_BOOT_SECTOR: u16 = 6;
+/// Blocks zeroed per write while clearing the FATs.
+#[cfg(not(feature = "sd-only"))]
+const FORMAT_ZERO_BLOCKS: usize = 8;
+
+/// The FAT32 volume a format will write, worked out from the card's size.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy)]
+pub struct Fat32Layout {
+    pub boot: BootSector,
+    pub start_lba: u32,
+    volume_id: u32,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl Fat32Layout {
+    /// One partition from 4 MiB to the end of a card of `card_blocks` blocks, with the
+    /// cluster size Microsoft's formatter would pick. Fails if that leaves too few
+    /// clusters for FAT32.
+    pub fn new(card_blocks: u32, volume_id: u32) -> Result<Self, &'static str> {
+        let total_sectors = card_blocks.checked_sub(FORMAT_START_LBA).ok_or("Card too small for FAT32")?;
+        let sectors_per_cluster = match total_sectors {
+            0..=532_480 => 1,
+            532_481..=16_777_216 => 8,
+            16_777_217..=33_554_432 => 16,
+            33_554_433..=67_108_864 => 32,
+            _ => 64,
+        };
+        // The FAT size calculation from the FAT32 spec, which errs slightly large
+        let per_fat_sector = (256 * sectors_per_cluster as u32 + FORMAT_NUM_FATS as u32) / 2;
+        // Saturating, so a card with no room past the reserved area fails the check below
+        let fat_size = total_sectors.saturating_sub(FORMAT_RESERVED_SECTORS as u32).div_ceil(per_fat_sector);
+
+        let boot = BootSector {
+            bytes_per_sector: Block::LEN as u16,
+            sectors_per_cluster,
+            reserved_sectors: FORMAT_RESERVED_SECTORS,
+            num_fats: FORMAT_NUM_FATS,
+            root_entries: 0,
+            total_sectors,
+            fat_size,
+            fs_info_sector: FORMAT_FS_INFO_SECTOR,
+            root_cluster: 2,
+        };
+        if boot.fat_type() != FatType::Fat32 {
+            return Err("Card too small for FAT32");
+        }
+        Ok(Fat32Layout { boot, start_lba: FORMAT_START_LBA, volume_id })
+    }
+
+    /// Blocks cleared before the structures are written: everything from the volume's
+    /// start to the end of the root directory's cluster.
+    fn zeroed_blocks(&self) -> u32 {
+        self.boot.data_start_sector() + self.boot.sectors_per_cluster as u32
+    }
+
+    fn mbr(&self) -> Block {
+        let mut block = Block::new();
+        let entry = &mut block.contents[446..462];
+        // CHS fields all saying "use the LBA fields"
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[4] = 0x0C; // FAT32 with LBA
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        put_u32(entry, 8, self.start_lba);
+        put_u32(entry, 12, self.boot.total_sectors);
+        block.contents[510] = 0x55;
+        block.contents[511] = 0xAA;
+        block
+    }
+
+    fn boot_sector(&self) -> Block {
+        let mut block = Block::new();
+        let bytes = &mut block.contents;
+        bytes[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        bytes[3..11].copy_from_slice(b"MSWIN4.1");
+        put_u16(bytes, 11, self.boot.bytes_per_sector);
+        bytes[13] = self.boot.sectors_per_cluster;
+        put_u16(bytes, 14, self.boot.reserved_sectors);
+        bytes[16] = self.boot.num_fats;
+        bytes[21] = 0xF8; // fixed media
+        put_u16(bytes, 24, 63); // sectors per track and heads, for CHS-minded tools
+        put_u16(bytes, 26, 255);
+        put_u32(bytes, 28, self.start_lba);
+        put_u32(bytes, 32, self.boot.total_sectors);
+        put_u32(bytes, 36, self.boot.fat_size);
+        put_u32(bytes, 44, self.boot.root_cluster);
+        put_u16(bytes, 48, self.boot.fs_info_sector);
+        put_u16(bytes, 50, FORMAT_BACKUP_BOOT_SECTOR);
+        bytes[64] = 0x80;
+        bytes[66] = 0x29; // the volume ID, label and type below are present
+        put_u32(bytes, 67, self.volume_id);
+        bytes[71..82].copy_from_slice(b"NO NAME    ");
+        bytes[82..90].copy_from_slice(b"FAT32   ");
+        bytes[510] = 0x55;
+        bytes[511] = 0xAA;
+        block
+    }
+
+    fn fs_info(&self) -> Block {
+        let mut block = Block::new();
+        let bytes = &mut block.contents;
+        put_u32(bytes, 0, 0x4161_5252);
+        put_u32(bytes, 484, 0x6141_7272);
+        // Every cluster but the root directory's is free, and the next one after it is
+        put_u32(bytes, 488, self.boot.cluster_count() - 1);
+        put_u32(bytes, 492, 3);
+        put_u32(bytes, 508, 0xAA55_0000);
+        block
+    }
+
+    fn fat_head(&self) -> Block {
+        let mut block = Block::new();
+        put_u32(&mut block.contents, 0, 0x0FFF_FFF8); // media byte
+        put_u32(&mut block.contents, 4, 0x0FFF_FFFF);
+        put_u32(&mut block.contents, 8, 0x0FFF_FFFF); // the root directory's one cluster
+        block
+    }
+}
+
+/// Writes a `Fat32Layout` to a card a few blocks at a time, so the caller can report
+/// progress and let other tasks run in between.
+///
+/// The old partition table and boot sector are cleared first and the new boot sector is
+/// written last, so a format that stops partway leaves a card that won't mount rather
+/// than one that mounts with a half-written FAT.
+#[cfg(not(feature = "sd-only"))]
+pub struct Formatter {
+    layout: Fat32Layout,
+    zeroed: u32,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl Formatter {
+    pub fn new(layout: Fat32Layout) -> Self {
+        Formatter { layout, zeroed: 0 }
+    }
+
+    /// Blocks cleared so far and in all.
+    pub fn progress(&self) -> (u32, u32) {
+        (self.zeroed, self.layout.zeroed_blocks())
+    }
+
+    /// Clears the next few blocks. Returns `false` once everything is cleared and
+    /// `finish` should be called.
+    pub fn zero_next<D: BlockDevice>(&mut self, device: &D) -> Result<bool, &'static str> {
+        if self.zeroed == 0 {
+            write_blocks(device, 0, &[Block::new()])?;
+        }
+        let total = self.layout.zeroed_blocks();
+        let count = (total - self.zeroed).min(FORMAT_ZERO_BLOCKS as u32);
+        if count == 0 {
+            return Ok(false);
+        }
+        let zeros = [const { Block::new() }; FORMAT_ZERO_BLOCKS];
+        write_blocks(device, self.layout.start_lba + self.zeroed, &zeros[..count as usize])?;
+        self.zeroed += count;
+        Ok(self.zeroed < total)
+    }
+
+    /// Writes the FATs' first entries, FSInfo, the partition table and, last, the boot
+    /// sector and its backup.
+    pub fn finish<D: BlockDevice>(&self, device: &D) -> Result<(), &'static str> {
+        let layout = &self.layout;
+        let start = layout.start_lba;
+        let fat_head = layout.fat_head();
+        for fat in 0..layout.boot.num_fats as u32 {
+            write_blocks(device, start + layout.boot.reserved_sectors as u32 + fat * layout.boot.fat_size, core::slice::from_ref(&fat_head))?;
+        }
+        let fs_info = layout.fs_info();
+        write_blocks(device, start + FORMAT_FS_INFO_SECTOR as u32, core::slice::from_ref(&fs_info))?;
+        write_blocks(device, start + FORMAT_BACKUP_BOOT_SECTOR as u32 + 1, &[fs_info])?;
+        write_blocks(device, 0, &[layout.mbr()])?;
+        let boot = layout.boot_sector();
+        write_blocks(device, start + FORMAT_BACKUP_BOOT_SECTOR as u32, core::slice::from_ref(&boot))?;
+        write_blocks(device, start, &[boot])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    /// A card of `blocks` blocks that only stores the ones that aren't all zeros.
+    struct MemoryCard {
+        blocks: u32,
+        written: RefCell<BTreeMap<u32, Block>>,
+    }
+
+    impl MemoryCard {
+        fn new(blocks: u32) -> Self {
+            MemoryCard { blocks, written: RefCell::new(BTreeMap::new()) }
+        }
+    }
+
+    impl BlockDevice for MemoryCard {
+        type Error = ();
+
+        fn read(&self, blocks: &mut [Block], start: BlockIdx) -> Result<(), ()> {
+            let written = self.written.borrow();
+            for (lba, block) in (start.0..).zip(blocks) {
+                if lba >= self.blocks {
+                    return Err(());
+                }
+                *block = written.get(&lba).cloned().unwrap_or_default();
+            }
+            Ok(())
+        }
+
+        fn write(&self, blocks: &[Block], start: BlockIdx) -> Result<(), ()> {
+            let mut written = self.written.borrow_mut();
+            for (lba, block) in (start.0..).zip(blocks) {
+                if lba >= self.blocks {
+                    return Err(());
+                }
+                if block.contents.iter().all(|&b| b == 0) {
+                    written.remove(&lba);
+                } else {
+                    written.insert(lba, block.clone());
+                }
+            }
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> Result<embedded_sdmmc::BlockCount, ()> {
+            Ok(embedded_sdmmc::BlockCount(self.blocks))
+        }
+    }
+
+    fn format(card: &MemoryCard) -> Fat32Layout {
+        let layout = Fat32Layout::new(card.blocks, 0x1234_5678).unwrap();
+        let mut formatter = Formatter::new(layout);
+        while formatter.zero_next(card).unwrap() {}
+        formatter.finish(card).unwrap();
+        layout
+    }
+
+    #[test]
+    fn formatted_cards_read_back_as_empty_fat32_volumes() {
+        // 64 MiB, 1 GiB, 16 GiB and 128 GiB, which get 1, 8, 32 and 64 sectors per cluster
+        for blocks in [131_072, 2_097_152, 33_554_432, 268_435_456] {
+            let card = MemoryCard::new(blocks);
+            let layout = format(&card);
+
+            let boot = parse_boot_sector(&read_block(&card, layout.start_lba).unwrap()).unwrap();
+            assert!(boot.fat_type() == FatType::Fat32, "{} blocks", blocks);
+            assert_eq!(boot.sectors_per_cluster, layout.boot.sectors_per_cluster);
+
+            let (start_lba, boot) = first_volume(&card).unwrap();
+            assert_eq!(start_lba, layout.start_lba);
+            assert_eq!(boot.total_sectors, layout.boot.total_sectors);
+            assert_eq!(boot.fat_size, layout.boot.fat_size);
+            assert_eq!(boot.root_cluster, 2);
+            assert!(start_lba + boot.total_sectors <= blocks);
+
+            let free = free_space(&card).unwrap();
+            assert_eq!(free.free_clusters, boot.cluster_count() as u64 - 1);
+            assert_eq!(free.cluster_bytes, boot.cluster_bytes());
+
+            let report = check_root_dir(&card).unwrap();
+            assert_eq!((report.files, report.dirs, report.bad_entries), (0, 0, 0));
+            assert!(!first_volume_is_exfat(&card));
+        }
+    }
+
+    #[test]
+    fn cards_too_small_for_fat32_are_refused() {
+        // Under the 4 MiB alignment gap, and too few clusters for FAT32 after it
+        for blocks in [0, 4096, 8192, 65_536] {
+            assert!(Fat32Layout::new(blocks, 0).is_err(), "{} blocks", blocks);
+        }
+    }
+}
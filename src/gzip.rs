@@ -0,0 +1,323 @@
+//! Minimal streaming gzip encoder for response bodies.
+//!
+//! Uses a single-candidate LZ77 match finder over an 8 KB window and the fixed Huffman
+//! tables, which is plenty for the repetitive HTML this device renders while keeping
+//! memory use flat (about 11 KB per encoder) and needing no dynamic tables.
+
+use embedded_io_async::Write;
+
+const WINDOW: usize = 4096;
+const BUFFER: usize = 2 * WINDOW;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 10;
+const OUT_CAPACITY: usize = 1024;
+// Worst case output of one encode step (match + extra bits) or the stream trailer
+const OUT_RESERVE: usize = 32;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The compression state machine, independent of where the output goes.
+struct Deflater {
+    window: [u8; BUFFER],
+    // Bytes currently held in `window`
+    len: usize,
+    // Next byte of `window` to encode
+    pos: usize,
+    // Most recent position + 1 for each 3-byte hash, 0 when empty
+    head: [u16; 1 << HASH_BITS],
+    bits: u32,
+    nbits: u32,
+    crc: u32,
+    size: u32,
+    out: [u8; OUT_CAPACITY],
+    out_len: usize,
+    started: bool,
+    finished: bool,
+}
+
+impl Deflater {
+    const fn new() -> Self {
+        Deflater {
+            window: [0; BUFFER],
+            len: 0,
+            pos: 0,
+            head: [0; 1 << HASH_BITS],
+            bits: 0,
+            nbits: 0,
+            crc: 0xFFFF_FFFF,
+            size: 0,
+            out: [0; OUT_CAPACITY],
+            out_len: 0,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Copies as much of `input` into the window as fits, returning how much was taken.
+    fn accept(&mut self, input: &[u8]) -> usize {
+        if self.len == BUFFER && self.pos >= WINDOW {
+            self.slide();
+        }
+
+        let n = input.len().min(BUFFER - self.len);
+        self.window[self.len..self.len + n].copy_from_slice(&input[..n]);
+        self.len += n;
+        for &byte in &input[..n] {
+            self.crc = CRC_TABLE[((self.crc ^ byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+        self.size = self.size.wrapping_add(n as u32);
+        n
+    }
+
+    fn slide(&mut self) {
+        self.window.copy_within(WINDOW..self.len, 0);
+        self.len -= WINDOW;
+        self.pos -= WINDOW;
+        for entry in self.head.iter_mut() {
+            *entry = entry.saturating_sub(WINDOW as u16);
+        }
+    }
+
+    fn hash(&self, at: usize) -> usize {
+        let key = (self.window[at] as u32) << 16 | (self.window[at + 1] as u32) << 8 | self.window[at + 2] as u32;
+        (key.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn put_bits(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.nbits;
+        self.nbits += count;
+        while self.nbits >= 8 {
+            self.out[self.out_len] = self.bits as u8;
+            self.out_len += 1;
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    // Huffman codes are defined MSB-first, but the bit stream is packed LSB-first
+    fn put_code(&mut self, code: u32, count: u32) {
+        self.put_bits(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn put_symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xC0 + symbol - 280, 8),
+        }
+    }
+
+    fn put_match(&mut self, length: usize, distance: usize) {
+        let i = LEN_BASE.iter().rposition(|&base| base as usize <= length).unwrap_or(0);
+        self.put_symbol(257 + i as u16);
+        self.put_bits((length - LEN_BASE[i] as usize) as u32, LEN_EXTRA[i] as u32);
+
+        let d = DIST_BASE.iter().rposition(|&base| base as usize <= distance).unwrap_or(0);
+        self.put_code(d as u32, 5);
+        self.put_bits((distance - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d] as u32);
+    }
+
+    fn put_byte(&mut self, byte: u8) {
+        self.out[self.out_len] = byte;
+        self.out_len += 1;
+    }
+
+    /// Encodes buffered input until the output buffer fills. With `finish`, also drains the
+    /// lookahead and writes the stream trailer once everything has been encoded.
+    fn compress(&mut self, finish: bool) {
+        if !self.started {
+            for byte in [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff] {
+                self.put_byte(byte);
+            }
+            // First block: not final, fixed Huffman
+            self.put_bits(0, 1);
+            self.put_bits(1, 2);
+            self.started = true;
+        }
+
+        let limit = if finish { self.len } else { self.len.saturating_sub(MAX_MATCH) };
+        while self.pos < limit && self.out_len + OUT_RESERVE <= OUT_CAPACITY {
+            let pos = self.pos;
+            let mut best = (0, 0);
+            if pos + MIN_MATCH <= self.len {
+                let h = self.hash(pos);
+                let candidate = self.head[h] as usize;
+                self.head[h] = (pos + 1) as u16;
+                if candidate != 0 {
+                    let start = candidate - 1;
+                    let max = MAX_MATCH.min(self.len - pos);
+                    let mut length = 0;
+                    while length < max && self.window[start + length] == self.window[pos + length] {
+                        length += 1;
+                    }
+                    if length >= MIN_MATCH {
+                        best = (length, pos - start);
+                    }
+                }
+            }
+
+            match best {
+                (length, distance) if length >= MIN_MATCH => {
+                    self.put_match(length, distance);
+                    for p in pos + 1..pos + length {
+                        if p + MIN_MATCH <= self.len {
+                            let h = self.hash(p);
+                            self.head[h] = (p + 1) as u16;
+                        }
+                    }
+                    self.pos += length;
+                }
+                _ => {
+                    self.put_symbol(self.window[pos] as u16);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        if finish && !self.finished && self.pos == self.len && self.out_len + OUT_RESERVE <= OUT_CAPACITY {
+            self.put_symbol(256);
+            // Empty final block, since the first block's header is already out
+            self.put_bits(1, 1);
+            self.put_bits(1, 2);
+            self.put_symbol(256);
+            if self.nbits > 0 {
+                self.put_bits(0, 8 - self.nbits);
+            }
+            for byte in (!self.crc).to_le_bytes().into_iter().chain(self.size.to_le_bytes()) {
+                self.put_byte(byte);
+            }
+            self.finished = true;
+        }
+    }
+}
+
+/// A `Write` adapter that gzip-compresses everything written to it into `inner`.
+///
+/// Call [`GzipWriter::finish`] after the last write to emit the gzip trailer.
+pub struct GzipWriter<'a, W: Write> {
+    inner: &'a mut W,
+    deflater: Deflater,
+}
+
+impl<'a, W: Write> GzipWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        GzipWriter {
+            inner,
+            deflater: Deflater::new(),
+        }
+    }
+
+    async fn drain(&mut self) -> Result<(), W::Error> {
+        let n = self.deflater.out_len;
+        self.deflater.out_len = 0;
+        self.inner.write_all(&self.deflater.out[..n]).await
+    }
+
+    pub async fn finish(mut self) -> Result<(), W::Error> {
+        while !self.deflater.finished {
+            self.deflater.compress(true);
+            self.drain().await?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> embedded_io_async::ErrorType for GzipWriter<'_, W> {
+    type Error = W::Error;
+}
+
+impl<W: Write> Write for GzipWriter<'_, W> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = self.deflater.accept(rest);
+            rest = &rest[n..];
+            if n == 0 {
+                self.deflater.compress(false);
+                self.drain().await?;
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Whether a response of this type benefits from compression. Images, audio, video and
+/// archives are already compressed, so gzipping them only burns CPU.
+pub fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("image/svg+xml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Deflater;
+
+    /// Everything the deflater writes for `input`, as `GzipWriter` would send it.
+    fn gzip(input: &[u8]) -> std::vec::Vec<u8> {
+        let mut deflater = Deflater::new();
+        let mut out = std::vec::Vec::new();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let n = deflater.accept(rest);
+            rest = &rest[n..];
+            if n == 0 {
+                deflater.compress(false);
+                out.extend_from_slice(&deflater.out[..deflater.out_len]);
+                deflater.out_len = 0;
+            }
+        }
+        while !deflater.finished {
+            deflater.compress(true);
+            out.extend_from_slice(&deflater.out[..deflater.out_len]);
+            deflater.out_len = 0;
+        }
+        out
+    }
+
+    #[test]
+    fn empty_input_is_a_valid_empty_stream() {
+        let out = gzip(b"");
+        assert_eq!(out[..3], [0x1f, 0x8b, 8]);
+        let (deflate, trailer) = out[10..].split_at(out.len() - 18);
+        assert_eq!(miniz_oxide::inflate::decompress_to_vec(deflate).unwrap(), b"");
+        // CRC-32 and length of no bytes
+        assert_eq!(trailer, [0; 8]);
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let text = b"<li>README.TXT</li><li>DATA.CSV</li><li>NOTES.MD</li>".repeat(40);
+        let out = gzip(&text);
+        let deflate = &out[10..out.len() - 8];
+        assert_eq!(miniz_oxide::inflate::decompress_to_vec(deflate).unwrap(), text);
+    }
+}
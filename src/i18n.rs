@@ -0,0 +1,180 @@
+//! Translations of the listing page's UI strings.
+//!
+//! Each language is a table indexed by `Text`. An empty entry means the string hasn't
+//! been translated yet and the English one is shown instead.
+
+/// Languages the UI can be shown in, selected with `lang=` in `WIFI.CFG` or
+/// `POST /api/config`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Lang {
+    En,
+    De,
+}
+
+impl Lang {
+    pub fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "en" => Some(Lang::En),
+            "de" => Some(Lang::De),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::De => "de",
+        }
+    }
+
+    /// Index for storing the language in an atomic.
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Lang::De,
+            _ => Lang::En,
+        }
+    }
+
+    /// The string for `key` in this language, or in English if it has no translation.
+    pub fn text(self, key: Text) -> &'static str {
+        let table = match self {
+            Lang::En => &EN,
+            Lang::De => &DE,
+        };
+        match table[key as usize] {
+            "" => EN[key as usize],
+            text => text,
+        }
+    }
+}
+
+/// Keys for the translatable strings, in table order.
+#[derive(Clone, Copy)]
+pub enum Text {
+    Heading,
+    WifiActive,
+    IpAddress,
+    WebServer,
+    RunningOnPort,
+    FilesOnCard,
+    Status,
+    NoFilesFound,
+    ProperlyInserted,
+    FormattedFat32,
+    CorrectPins,
+    CardStatus,
+    FilesFound,
+    TotalSize,
+    WriteProtected,
+    Show,
+    All,
+    Files,
+    Folders,
+    HideHidden,
+    ShowHidden,
+    GridView,
+    ListView,
+    SelectFiles,
+    CancelSelection,
+    DownloadSelected,
+    Download,
+    Directory,
+    DownloadCount,
+    DownloadsCount,
+    NoMatches,
+    CurrentStatus,
+    HardwareConfiguration,
+    Instructions,
+    AutoRefresh,
+    RecentlyModified,
+    Seconds,
+    HiddenSsid,
+}
+
+const COUNT: usize = Text::HiddenSsid as usize + 1;
+
+const EN: [&str; COUNT] = [
+    "SD Card File Browser",
+    "WiFi AP Active:",
+    "IP Address:",
+    "Web Server:",
+    "Running on port 80",
+    "Files on SD Card:",
+    "Status:",
+    "No files found. Make sure SD card is:",
+    "Properly inserted",
+    "Formatted as FAT32",
+    "Connected to correct SPI pins",
+    "SD Card Status:",
+    "Files found:",
+    "Total size:",
+    "Card write-protected",
+    "Show:",
+    "All",
+    "Files",
+    "Folders",
+    "Hide hidden/system",
+    "Show hidden/system",
+    "Grid view",
+    "List view",
+    "Select files",
+    "Cancel selection",
+    "Download selected (.tar)",
+    "Download",
+    "directory",
+    "download",
+    "downloads",
+    "No entries match this filter.",
+    "Current Status:",
+    "Hardware Configuration:",
+    "Instructions:",
+    "Page auto-refreshes every",
+    "Recently modified:",
+    "seconds",
+    "hidden",
+];
+
+const DE: [&str; COUNT] = [
+    "SD-Karten-Browser",
+    "WLAN-AP aktiv:",
+    "IP-Adresse:",
+    "Webserver:",
+    "L\u{e4}uft auf Port 80",
+    "Dateien auf der SD-Karte:",
+    "Status:",
+    "Keine Dateien gefunden. Bitte pr\u{fc}fen, ob die SD-Karte:",
+    "richtig eingesteckt ist",
+    "als FAT32 formatiert ist",
+    "an den richtigen SPI-Pins angeschlossen ist",
+    "SD-Kartenstatus:",
+    "Gefundene Dateien:",
+    "Gesamtgr\u{f6}\u{df}e:",
+    "Karte schreibgesch\u{fc}tzt",
+    "Anzeigen:",
+    "Alle",
+    "Dateien",
+    "Ordner",
+    "Versteckte/System ausblenden",
+    "Versteckte/System einblenden",
+    "Rasteransicht",
+    "Listenansicht",
+    "Dateien ausw\u{e4}hlen",
+    "Auswahl abbrechen",
+    "Auswahl herunterladen (.tar)",
+    "Herunterladen",
+    "Ordner",
+    "Download",
+    "Downloads",
+    "Keine Eintr\u{e4}ge passen zu diesem Filter.",
+    "Aktueller Status:",
+    "Hardware-Konfiguration:",
+    "Anleitung:",
+    "Die Seite aktualisiert sich alle",
+    "Zuletzt ge\u{e4}ndert:",
+    "Sekunden",
+    "versteckt",
+];
@@ -0,0 +1,149 @@
+//! State of the requests being handled, which the response helpers look up from the
+//! socket: handlers don't carry a request context. Requests are told apart by their
+//! socket's local and remote endpoints, which no two open connections share.
+//!
+//! Each request records whether it's a `HEAD`, so `ResponseBuilder::send` can drop the
+//! body, and its timings for the `Server-Timing` header, so browser devtools show where
+//! a response's time went.
+//!
+//! `total` runs from the request arriving to its headers going out, and `sd` is the part
+//! of that spent waiting for or holding the card bus. Time spent sending the body isn't
+//! counted, since the header has to go out before it.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use core::ops::Deref;
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::IpEndpoint;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::mutex::MutexGuard;
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+
+use crate::{config, SdCardOwner, SharedRawMutex, MAX_HTTP_INTERFACES, SD_BUS};
+
+/// Local and remote endpoint of a connection.
+type Key = (IpEndpoint, IpEndpoint);
+
+/// One per server task, since each handles a request at a time.
+const MAX_IN_FLIGHT: usize = MAX_HTTP_INTERFACES * config::LISTEN_SOCKETS;
+
+struct InFlight {
+    key: Key,
+    head: bool,
+    started: Instant,
+    /// Card time from locks already released
+    sd: Duration,
+    /// When the current lock was asked for, while one is
+    sd_since: Option<Instant>,
+}
+
+// A blocking mutex, since `SdLock` updates its row in `Drop`
+static TABLE: Mutex<SharedRawMutex, RefCell<Vec<InFlight, MAX_IN_FLIGHT>>> = Mutex::new(RefCell::new(Vec::new()));
+
+fn key(socket: &TcpSocket<'_>) -> Option<Key> {
+    Some((socket.local_endpoint()?, socket.remote_endpoint()?))
+}
+
+fn with<R>(key: Option<Key>, f: impl FnOnce(&mut InFlight) -> R) -> Option<R> {
+    let key = key?;
+    TABLE.lock(|table| table.borrow_mut().iter_mut().find(|request| request.key == key).map(f))
+}
+
+/// A request being handled, forgotten when dropped. The key is kept rather than looked
+/// up again, since a closed socket no longer has a remote endpoint.
+pub struct Request {
+    key: Option<Key>,
+}
+
+/// Records the request that just arrived on `socket` and starts timing it.
+pub fn start(socket: &TcpSocket<'_>, head: bool) -> Request {
+    let key = key(socket);
+    if let Some(key) = key {
+        let request = InFlight {
+            key,
+            head,
+            started: Instant::now(),
+            sd: Duration::from_ticks(0),
+            sd_since: None,
+        };
+        TABLE.lock(|table| {
+            let mut table = table.borrow_mut();
+            table.retain(|request| request.key != key);
+            let _ = table.push(request);
+        });
+    }
+    Request { key }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            TABLE.lock(|table| table.borrow_mut().retain(|request| request.key != key));
+        }
+    }
+}
+
+/// Whether the request on `socket` is a `HEAD`, whose response ends with its headers.
+pub fn is_head(socket: &TcpSocket<'_>) -> bool {
+    with(key(socket), |request| request.head).unwrap_or(false)
+}
+
+/// Milliseconds with one decimal, as `Server-Timing` durations are written.
+fn push_ms(out: &mut String<80>, duration: Duration) {
+    let micros = duration.as_micros();
+    let _ = write!(out, "{}.{}", micros / 1000, micros % 1000 / 100);
+}
+
+/// The `Server-Timing` header line for the request on `socket` so far, or `None` if it
+/// isn't being timed.
+pub fn timing_header(socket: &TcpSocket<'_>) -> Option<String<80>> {
+    let now = Instant::now();
+    with(key(socket), |timing| {
+        let mut line = String::new();
+        let _ = line.push_str("Server-Timing: total;dur=");
+        push_ms(&mut line, now - timing.started);
+        let sd = timing.sd + timing.sd_since.map_or(Duration::from_ticks(0), |since| now - since);
+        if sd.as_ticks() > 0 {
+            let _ = line.push_str(", sd;dur=");
+            push_ms(&mut line, sd);
+        }
+        let _ = line.push_str("\r\n");
+        line
+    })
+}
+
+/// The card bus, held on behalf of the request on a socket. Waiting for it and holding it
+/// both count towards that request's `sd` time.
+pub struct SdLock {
+    guard: MutexGuard<'static, SharedRawMutex, SdCardOwner>,
+    key: Option<Key>,
+}
+
+/// `SD_BUS.lock()` for a handler serving `socket`.
+pub async fn lock_sd(socket: &TcpSocket<'_>) -> SdLock {
+    let key = key(socket);
+    with(key, |timing| timing.sd_since = Some(Instant::now()));
+    let guard = SD_BUS.lock().await;
+    SdLock { guard, key }
+}
+
+impl Deref for SdLock {
+    type Target = SdCardOwner;
+
+    fn deref(&self) -> &SdCardOwner {
+        &self.guard
+    }
+}
+
+impl Drop for SdLock {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        with(self.key, |timing| {
+            if let Some(since) = timing.sd_since.take() {
+                timing.sd += now - since;
+            }
+        });
+    }
+}
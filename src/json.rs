@@ -0,0 +1,206 @@
+//! Just enough JSON to read a flat settings object.
+//!
+//! Only a single `{"key": value, ...}` object is accepted, with string, integer, boolean
+//! and null values. Nested objects, arrays and fractions are rejected rather than skipped,
+//! since no request body needs them.
+
+use heapless::String;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    /// A string's contents as written, escapes included; see `unescape`.
+    Str(&'a str),
+    Int(i64),
+    Bool(bool),
+    Null,
+}
+
+/// Iterator over the members of an object, in the order they appear. Stops after the
+/// first syntax error, which it yields as `Err`.
+pub struct Members<'a> {
+    rest: &'a str,
+    first: bool,
+    done: bool,
+}
+
+/// Starts reading the object in `text`.
+pub fn members(text: &str) -> Result<Members<'_>, &'static str> {
+    let rest = text.trim().strip_prefix('{').ok_or("expected a JSON object")?;
+    Ok(Members { rest, first: true, done: false })
+}
+
+impl<'a> Members<'a> {
+    fn member(&mut self) -> Result<Option<(&'a str, Value<'a>)>, &'static str> {
+        let mut rest = self.rest.trim_start();
+        if let Some(after) = rest.strip_prefix('}') {
+            if !after.trim().is_empty() {
+                return Err("trailing data after object");
+            }
+            return Ok(None);
+        }
+        if !self.first {
+            rest = rest.strip_prefix(',').ok_or("expected ',' or '}'")?.trim_start();
+        }
+        self.first = false;
+
+        let (key, after) = string(rest).ok_or("expected a quoted key")?;
+        let after = after.trim_start().strip_prefix(':').ok_or("expected ':' after key")?;
+        let (value, after) = value(after.trim_start())?;
+        self.rest = after;
+        Ok(Some((key, value)))
+    }
+}
+
+impl<'a> Iterator for Members<'a> {
+    type Item = Result<(&'a str, Value<'a>), &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.member() {
+            Ok(Some(member)) => Some(Ok(member)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Splits a quoted string off the front of `text`, returning its raw contents and the rest.
+fn string(text: &str) -> Option<(&str, &str)> {
+    let body = text.strip_prefix('"')?;
+    let mut escaped = false;
+    for (i, byte) in body.bytes().enumerate() {
+        match byte {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => return Some((&body[..i], &body[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn value(text: &str) -> Result<(Value<'_>, &str), &'static str> {
+    if text.starts_with('"') {
+        let (raw, rest) = string(text).ok_or("unterminated string")?;
+        return Ok((Value::Str(raw), rest));
+    }
+    for (word, value) in [("true", Value::Bool(true)), ("false", Value::Bool(false)), ("null", Value::Null)] {
+        if let Some(rest) = text.strip_prefix(word) {
+            return Ok((value, rest));
+        }
+    }
+    let end = text
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+        .map_or(text.len(), |(i, _)| i);
+    let number = text[..end].parse().map_err(|_| "expected a string, integer, boolean or null")?;
+    // A fraction or exponent would otherwise read as the integer before it
+    if text[end..].starts_with(|c: char| c == '.' || c.is_ascii_alphanumeric()) {
+        return Err("numbers must be integers");
+    }
+    Ok((Value::Int(number), &text[end..]))
+}
+
+/// Decodes the escapes in a string's raw contents. `None` if an escape is malformed, is a
+/// surrogate pair, or the result doesn't fit in `N` bytes.
+pub fn unescape<const N: usize>(raw: &str) -> Option<String<N>> {
+    let mut out = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                '/' => '/',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let hex = chars.as_str().get(..4)?;
+                    // `from_str_radix` would also take a leading `+`
+                    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return None;
+                    }
+                    let code = u32::from_str_radix(hex, 16).ok()?;
+                    chars = chars.as_str()[4..].chars();
+                    char::from_u32(code)?
+                }
+                _ => return None,
+            }
+        } else {
+            c
+        };
+        out.push(c).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{members, unescape, Value};
+
+    #[test]
+    fn an_empty_object_has_no_members() {
+        assert!(members("{}").unwrap().next().is_none());
+        assert!(members(" { } \n").unwrap().next().is_none());
+    }
+
+    #[test]
+    fn escaped_quotes_stay_inside_keys_and_values() {
+        let mut object = members(r#"{"a\"b": "c\"d", "n": -12}"#).unwrap();
+        assert!(object.next() == Some(Ok((r#"a\"b"#, Value::Str(r#"c\"d"#)))));
+        assert!(object.next() == Some(Ok(("n", Value::Int(-12)))));
+        assert!(object.next().is_none());
+    }
+
+    #[test]
+    fn trailing_data_after_the_object_is_an_error() {
+        let mut object = members(r#"{"a": true} x"#).unwrap();
+        assert!(object.next() == Some(Ok(("a", Value::Bool(true)))));
+        assert!(object.next() == Some(Err("trailing data after object")));
+        assert!(object.next().is_none());
+    }
+
+    #[test]
+    fn numbers_must_be_plain_integers_that_fit() {
+        for text in [r#"{"n": -}"#, r#"{"n": 1e5}"#, r#"{"n": 1.5}"#, r#"{"n": 9223372036854775808}"#] {
+            let mut object = members(text).unwrap();
+            assert!(matches!(object.next(), Some(Err(_))), "{}", text);
+            assert!(object.next().is_none());
+        }
+        let mut object = members(r#"{"n": -9223372036854775808}"#).unwrap();
+        assert!(object.next() == Some(Ok(("n", Value::Int(i64::MIN)))));
+    }
+
+    #[test]
+    fn unescape_decodes_u_escapes() {
+        assert_eq!(unescape::<16>(r"a\u00e9\n\/").unwrap().as_str(), "a\u{e9}\n/");
+        assert_eq!(unescape::<16>(r"\u20AC").unwrap().as_str(), "\u{20ac}");
+    }
+
+    #[test]
+    fn unescape_rejects_surrogates_and_malformed_escapes() {
+        assert!(unescape::<16>(r"\ud83d\ude00").is_none());
+        assert!(unescape::<16>(r"\udc00").is_none());
+        assert!(unescape::<16>(r"\u00e").is_none());
+        assert!(unescape::<16>(r"\u+041").is_none());
+        assert!(unescape::<16>(r"\x41").is_none());
+        assert!(unescape::<16>("\\").is_none());
+    }
+
+    #[test]
+    fn unescape_fails_when_the_result_does_not_fit() {
+        assert!(unescape::<4>("abcde").is_none());
+        assert_eq!(unescape::<4>("abcd").unwrap().as_str(), "abcd");
+    }
+}
@@ -0,0 +1,62 @@
+//! The most recent `log_info!` / `log_warn!` lines, kept as text for `GET /debug/log` so
+//! they can be read over WiFi without a probe attached.
+//!
+//! defmt only ever sends an encoded stream to RTT, so the macros format each line a second
+//! time with `core::fmt` and hand it to `record`. When the ring is full, the oldest whole
+//! lines are dropped to make room.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::Deque;
+
+use crate::SharedRawMutex;
+
+/// Bytes of log text kept.
+pub const CAPACITY: usize = 4096;
+
+/// Longest single line; longer messages are cut with "…".
+const MAX_LINE: usize = 160;
+
+// A blocking mutex, since lines are recorded from plain functions as well as tasks. It's
+// only held to copy bytes in or out, never across an await.
+static RING: Mutex<SharedRawMutex, RefCell<Deque<u8, CAPACITY>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// Appends one line, stamped with the uptime and `level`.
+pub fn record(level: &str, args: core::fmt::Arguments<'_>) {
+    let now = Instant::now().as_millis();
+    let message = crate::text::format_truncated::<MAX_LINE>(args);
+    let mut line = heapless::String::<{ MAX_LINE + 24 }>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!("[{}.{:03}] {:<4} {}\n", now / 1000, now % 1000, level, message),
+    );
+
+    RING.lock(|ring| {
+        let mut ring = ring.borrow_mut();
+        while CAPACITY - ring.len() < line.len() {
+            // Drop up to and including the oldest newline, so no partial line is left
+            while let Some(byte) = ring.pop_front() {
+                if byte == b'\n' {
+                    break;
+                }
+            }
+        }
+        for &byte in line.as_bytes() {
+            let _ = ring.push_back(byte);
+        }
+    });
+}
+
+/// Copies the ring into `out`, oldest line first, and returns how many bytes it holds.
+#[cfg(not(feature = "sd-only"))]
+pub fn snapshot(out: &mut [u8; CAPACITY]) -> usize {
+    RING.lock(|ring| {
+        let ring = ring.borrow();
+        let (front, back) = ring.as_slices();
+        out[..front.len()].copy_from_slice(front);
+        out[front.len()..front.len() + back.len()].copy_from_slice(back);
+        front.len() + back.len()
+    })
+}
@@ -1,23 +1,124 @@
 #![no_std]
 #![no_main]
 
+#[cfg(not(feature = "sd-only"))]
 use cyw43_pio::{PioSpi, RM2_CLOCK_DIVIDER};
 use defmt::*;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 use embassy_executor::Spawner;
+use embassy_futures::select::select;
+#[cfg(not(feature = "sd-only"))]
+use embassy_futures::select::{select4, Either, Either4};
+use embassy_futures::yield_now;
+#[cfg(not(feature = "sd-only"))]
 use embassy_net::tcp::TcpSocket;
+#[cfg(not(feature = "sd-only"))]
 use embassy_net::{Config, Stack, StackResources};
+#[cfg(not(feature = "sd-only"))]
 use embassy_rp::bind_interrupts;
 use embassy_rp::gpio::{Level, Output};
+use embassy_rp::peripherals::SPI0;
+#[cfg(not(feature = "sd-only"))]
 use embassy_rp::peripherals::{DMA_CH0, PIO0};
+#[cfg(not(feature = "sd-only"))]
 use embassy_rp::pio::{InterruptHandler as PioInterruptHandler, Pio};
-use embassy_rp::spi::{Config as SpiConfig, Spi};
-use embassy_time::{Duration, Timer};
+use embassy_rp::spi::{Blocking, Config as SpiConfig, Spi};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
+#[cfg(not(feature = "sd-only"))]
 use embedded_io_async::Write;
 use embedded_sdmmc::{SdCard, TimeSource, Timestamp, VolumeManager};
+#[cfg(not(feature = "sd-only"))]
 use static_cell::StaticCell;
+use store::FileStore;
+#[cfg(not(feature = "sd-only"))]
+use store::{FileWindow, HttpError};
+#[cfg(any(not(feature = "sd-only"), feature = "mock-sd", feature = "bundled-files"))]
+use store::MemoryStore;
 use {defmt_rtt as _, panic_probe as _};
 
+/// UI language for the listing (a `Lang` index); set by `lang=` in WIFI.CFG or POST /api/config.
+#[cfg(not(feature = "sd-only"))]
+static LANG: AtomicU8 = AtomicU8::new(0);
+
+/// Runtime log verbosity: 0 = info, 1 = warn, 2 = error. Boot messages ignore it.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// Seconds between SD rescans; set by `scan_interval=` in WIFI.CFG or POST /api/config.
+static SCAN_INTERVAL_SECS: AtomicU32 = AtomicU32::new(30);
+
+/// Seconds between listing page reloads, 0 for none; set by `refresh=` in WIFI.CFG or
+/// POST /api/config.
+#[cfg(not(feature = "sd-only"))]
+static REFRESH_SECS: AtomicU32 = AtomicU32::new(5);
+
+/// The radio's power saving (a `PowerMode` index). Main applies changes signalled on
+/// `POWER_MODE_CHANGE`, since it owns the cyw43 control handle.
+#[cfg(not(feature = "sd-only"))]
+static POWER_MODE: AtomicU8 = AtomicU8::new(0);
+#[cfg(not(feature = "sd-only"))]
+static POWER_MODE_CHANGE: embassy_sync::signal::Signal<SharedRawMutex, PowerMode> =
+    embassy_sync::signal::Signal::new();
+
+// Per-request and per-scan logging that can be quieted at runtime via POST /api/config.
+// With debug-endpoints, each line is also kept as text for GET /debug/log.
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if LOG_LEVEL.load(Ordering::Relaxed) == 0 {
+            info!($($arg)*);
+            #[cfg(feature = "debug-endpoints")]
+            crate::log_ring::record("INFO", format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        if LOG_LEVEL.load(Ordering::Relaxed) <= 1 {
+            warn!($($arg)*);
+            #[cfg(feature = "debug-endpoints")]
+            crate::log_ring::record("WARN", format_args!($($arg)*));
+        }
+    };
+}
+
+#[cfg(not(feature = "sd-only"))]
+mod bmp;
+mod config;
+mod fat;
+#[cfg(not(feature = "sd-only"))]
+mod gzip;
+#[cfg(not(feature = "sd-only"))]
+mod i18n;
+#[cfg(not(feature = "sd-only"))]
+mod inflight;
+#[cfg(not(feature = "sd-only"))]
+mod json;
+#[cfg(feature = "debug-endpoints")]
+mod log_ring;
+#[cfg(not(feature = "sd-only"))]
+mod ops;
+#[cfg(not(feature = "sd-only"))]
+mod stack;
+mod store;
+#[cfg(feature = "bundled-files")]
+mod bundle;
+#[cfg(feature = "mock-sd")]
+mod mock_sd;
+#[cfg(not(feature = "sd-only"))]
+mod tar;
+mod text;
+#[cfg(not(feature = "sd-only"))]
+mod wifi_config;
+
+#[cfg(not(feature = "sd-only"))]
+use gzip::GzipWriter;
+#[cfg(not(feature = "sd-only"))]
+use i18n::{Lang, Text};
+use text::format_truncated;
+#[cfg(not(feature = "sd-only"))]
+use wifi_config::{PowerMode, WifiConfig};
+
 // Program metadata
 #[unsafe(link_section = ".bi_entries")]
 #[used]
@@ -30,13 +131,96 @@ pub static PICOTOOL_ENTRIES: [embassy_rp::binary_info::EntryAddr; 4] = [
     embassy_rp::binary_info::rp_program_build_attribute!(),
 ];
 
+#[cfg(not(feature = "sd-only"))]
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => PioInterruptHandler<PIO0>;
 });
 
+/// Clock presets for the RP2350.
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+enum PowerPreset {
+    /// 100 MHz system clock, which lowers peak current draw. Use this if the board resets
+    /// when WiFi and the SD card are busy on a marginal USB supply.
+    Stable,
+    /// The default 150 MHz system clock.
+    Performance,
+}
+
+const POWER_PRESET: PowerPreset = PowerPreset::Performance;
+
+impl PowerPreset {
+    fn sys_clock_mhz(self) -> u32 {
+        match self {
+            PowerPreset::Stable => 100,
+            PowerPreset::Performance => 150,
+        }
+    }
+
+    fn rp_config(self) -> embassy_rp::config::Config {
+        let mut config = embassy_rp::config::Config::default();
+        if let PowerPreset::Stable = self {
+            if let Some(pll) = config.clocks.xosc.as_mut().and_then(|xosc| xosc.sys_pll.as_mut()) {
+                // 12 MHz crystal * 100 = 1200 MHz VCO, / (6 * 2) = 100 MHz
+                pll.refdiv = 1;
+                pll.fbdiv = 100;
+                pll.post_div1 = 6;
+                pll.post_div2 = 2;
+            }
+        }
+        config
+    }
+}
+
+// Must match the rp_program_name! entry above
+#[cfg(not(feature = "sd-only"))]
+const PROGRAM_NAME: &str = "LT7689 SD Browser";
+
+#[cfg(not(feature = "sd-only"))]
 const WIFI_SSID: &str = "PicoW_SD_Browser";
+// Default channel; WIFI.CFG on the card can pick another
+#[cfg(not(feature = "sd-only"))]
+const AP_CHANNEL: u8 = 5;
+
+#[cfg(not(feature = "sd-only"))]
+const _: () = assert!(
+    !WIFI_SSID.is_empty() && WIFI_SSID.len() <= 32,
+    "WIFI_SSID must be 1-32 bytes"
+);
+// Also used instead of a WIFI.CFG password that isn't a valid WPA2 passphrase
+#[cfg(not(feature = "sd-only"))]
 const WIFI_PASSWORD: &str = "12345678";
 
+#[cfg(not(feature = "sd-only"))]
+const _: () = assert!(
+    WIFI_PASSWORD.len() >= 8 && WIFI_PASSWORD.len() <= 63,
+    "WIFI_PASSWORD must be 8-63 characters"
+);
+
+/// WPA2 accepts an 8-63 character printable ASCII passphrase or a 64-digit hex key.
+#[cfg(not(feature = "sd-only"))]
+fn is_valid_wpa2_password(password: &str) -> bool {
+    match password.len() {
+        8..=63 => password.bytes().all(|b| (0x20..=0x7e).contains(&b)),
+        64 => password.bytes().all(|b| b.is_ascii_hexdigit()),
+        _ => false,
+    }
+}
+
+// Access point addressing - change these if 192.168.4.0/24 clashes with another network
+#[cfg(not(feature = "sd-only"))]
+const AP_ADDRESS: embassy_net::Ipv4Address = embassy_net::Ipv4Address::new(192, 168, 4, 1);
+#[cfg(not(feature = "sd-only"))]
+const AP_GATEWAY: embassy_net::Ipv4Address = AP_ADDRESS;
+#[cfg(not(feature = "sd-only"))]
+const AP_PREFIX_LEN: u8 = 24;
+
+#[cfg(not(feature = "sd-only"))]
+const _: () = assert!(
+    AP_PREFIX_LEN >= 8 && AP_PREFIX_LEN <= 30,
+    "AP_PREFIX_LEN must leave room for the AP and at least one client"
+);
+
 // Dummy TimeSource for SD card
 struct DummyTimesource;
 impl TimeSource for DummyTimesource {
@@ -45,378 +229,5541 @@ impl TimeSource for DummyTimesource {
     }
 }
 
-// Shared SD card file list
-static SD_FILES: embassy_sync::mutex::Mutex<
-    embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
-    heapless::Vec<FileInfo, 32>,
-> = embassy_sync::mutex::Mutex::new(heapless::Vec::new());
+// Raw mutex behind every shared static below. All tasks run on the one thread-mode executor
+// and no interrupt handler touches this state, so the `thread-mode-mutex` feature can swap
+// the critical section (interrupts off while the lock state changes) for a plain check that
+// the caller isn't in an interrupt. Anything that later shares state with an interrupt
+// handler or a second core needs the critical-section version back.
+#[cfg(not(feature = "thread-mode-mutex"))]
+type SharedRawMutex = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "thread-mode-mutex")]
+type SharedRawMutex = embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+
+// Most root-directory entries the listing holds; the rest of a larger directory is left out
+const MAX_FILES: usize = 32;
+
+// Shared SD card file list
+static SD_FILES: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    heapless::Vec<FileInfo, MAX_FILES>,
+> = embassy_sync::mutex::Mutex::new(heapless::Vec::new());
+
+// Set when the last scan found more than MAX_FILES entries, so the listing is incomplete
+static LISTING_TRUNCATED: AtomicBool = AtomicBool::new(false);
+
+static SD_STATUS: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    &str,
+> = embassy_sync::mutex::Mutex::new("Initializing...");
+
+// Stop rescanning the card after this long without an HTTP request
+const SCANNER_IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+// Uptime (in seconds) of the most recent accepted connection
+static LAST_REQUEST_SECS: AtomicU32 = AtomicU32::new(0);
+
+// Wakes a paused SD scanner when a client connects
+static SCANNER_WAKE: embassy_sync::signal::Signal<
+    SharedRawMutex,
+    (),
+> = embassy_sync::signal::Signal::new();
+
+// Serializes SD card access between the scanner and request handlers. Each user builds
+// its own SPI driver from stolen pins, so overlapping users would tear down each other's bus.
+//
+// Lock order: take SD_BUS before SD_FILES, never the other way round. The scanner
+// publishes partial listings to SD_FILES while it holds the bus, so a handler that waited
+// for the bus while holding SD_FILES would deadlock with it.
+static SD_BUS: embassy_sync::mutex::Mutex<SharedRawMutex, SdCardOwner> =
+    embassy_sync::mutex::Mutex::new(SdCardOwner(()));
+
+/// Proof that `SD_BUS` is held. Only the lock hands one out, and everything that drives
+/// the card takes one by reference, so SD access without the lock doesn't compile.
+struct SdCardOwner(());
+
+// Served in place of the listing at / when present in the root directory
+const INDEX_FILE: &str = "INDEX.HTM";
+
+// Set by the scanner when the root directory contains INDEX_FILE
+static ROOT_HAS_INDEX: AtomicBool = AtomicBool::new(false);
+
+// Requests an immediate rescan after a handler modified the card
+static RESCAN: embassy_sync::signal::Signal<
+    SharedRawMutex,
+    (),
+> = embassy_sync::signal::Signal::new();
+
+// Result of the most recent scan (file count or error), signalled after SD_FILES is published
+static SCAN_DONE: embassy_sync::signal::Signal<
+    SharedRawMutex,
+    Result<usize, &'static str>,
+> = embassy_sync::signal::Signal::new();
+
+// Longest time (in microseconds) a listing render has held SD_FILES, for /metrics. The
+// scanner can't publish a new listing in the meantime.
+#[cfg(not(feature = "sd-only"))]
+static LISTING_LOCK_MAX_US: AtomicU32 = AtomicU32::new(0);
+
+// Error counters since boot (or the last POST /api/errors/clear), for GET /api/errors:
+// scans that failed, requests that ended in a socket error, SD operations retried, and
+// downloads whose size disagreed with their cluster chain
+static SCAN_ERRORS: AtomicU32 = AtomicU32::new(0);
+#[cfg(not(feature = "sd-only"))]
+static FAILED_REQUESTS: AtomicU32 = AtomicU32::new(0);
+static SD_RETRIES: AtomicU32 = AtomicU32::new(0);
+#[cfg(not(feature = "sd-only"))]
+static SIZE_MISMATCHES: AtomicU32 = AtomicU32::new(0);
+
+// How long GET /rescan?wait=1 waits for the scanner before giving up
+#[cfg(not(feature = "sd-only"))]
+const RESCAN_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Per-route request counters for /metrics. Keyed by route rather than raw path so arbitrary
+// URLs can't grow the table; anything unmatched lands in the final "other" bucket.
+#[cfg(not(feature = "sd-only"))]
+static ROUTE_HITS: [(&str, AtomicU32); 33] = [
+    ("/", AtomicU32::new(0)),
+    ("/view", AtomicU32::new(0)),
+    ("/download", AtomicU32::new(0)),
+    ("/download-dir", AtomicU32::new(0)),
+    ("/download-selected", AtomicU32::new(0)),
+    ("/thumb", AtomicU32::new(0)),
+    ("/rescan", AtomicU32::new(0)),
+    ("/copy", AtomicU32::new(0)),
+    ("/append", AtomicU32::new(0)),
+    ("/metrics", AtomicU32::new(0)),
+    ("/api/sysinfo", AtomicU32::new(0)),
+    ("/api/scan", AtomicU32::new(0)),
+    ("/api/files", AtomicU32::new(0)),
+    ("/diag", AtomicU32::new(0)),
+    ("/api/errors", AtomicU32::new(0)),
+    ("/api/errors/clear", AtomicU32::new(0)),
+    ("/api/config", AtomicU32::new(0)),
+    ("/api/sd-health", AtomicU32::new(0)),
+    ("/api/ops", AtomicU32::new(0)),
+    ("/api/ops/cancel", AtomicU32::new(0)),
+    ("/api/stream", AtomicU32::new(0)),
+    ("/wifi/ssid", AtomicU32::new(0)),
+    ("/setup", AtomicU32::new(0)),
+    ("/favicon.ico", AtomicU32::new(0)),
+    ("/style.css", AtomicU32::new(0)),
+    ("/config.cfg", AtomicU32::new(0)),
+    ("/debug/benchmark", AtomicU32::new(0)),
+    ("/debug/handles", AtomicU32::new(0)),
+    ("/debug/mbr", AtomicU32::new(0)),
+    ("/debug/fsck", AtomicU32::new(0)),
+    ("/debug/log", AtomicU32::new(0)),
+    ("/debug/format", AtomicU32::new(0)),
+    ("other", AtomicU32::new(0)),
+];
+
+// Downloads per file since boot, least recently downloaded first. When full, the oldest
+// entry is evicted to make room.
+static DOWNLOAD_COUNTS: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    heapless::Vec<DownloadCount, 16>,
+> = embassy_sync::mutex::Mutex::new(heapless::Vec::new());
+
+#[derive(Clone)]
+struct DownloadCount {
+    name: heapless::String<64>,
+    count: u32,
+}
+
+// Rejects requests that would write to the card; toggled with `POST /api/config`
+#[cfg(not(feature = "sd-only"))]
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+// Whether main blinks the onboard LED; toggled with `POST /api/config`
+#[cfg(not(feature = "sd-only"))]
+static HEARTBEAT: AtomicBool = AtomicBool::new(true);
+
+// Set while the card's write-protect switch is on (only with the `sd-write-protect` feature)
+#[cfg(any(not(feature = "sd-only"), feature = "sd-write-protect"))]
+static WRITE_PROTECTED: AtomicBool = AtomicBool::new(false);
+
+/// Refuses a write to `name` with `415` if its extension isn't in WIFI.CFG's `uploads=`
+/// list. Returns whether the response was sent.
+#[cfg(not(feature = "sd-only"))]
+async fn refuse_upload_type(socket: &mut TcpSocket<'_>, name: &str) -> bool {
+    if wifi_config::upload_allowed(&UPLOAD_TYPES.lock().await, name) {
+        return false;
+    }
+    log_warn!("Refusing to write {}: file type not allowed", name);
+    send_plain(socket, b"415 Unsupported Media Type", b"File type not allowed for uploads\n").await;
+    true
+}
+
+/// Why writes are currently refused, if they are.
+#[cfg(not(feature = "sd-only"))]
+fn write_block_reason() -> Option<&'static [u8]> {
+    if cfg!(feature = "mock-sd") {
+        Some(b"Writes aren't supported on the mock card\n")
+    } else if WRITE_PROTECTED.load(Ordering::Relaxed) {
+        Some(b"Card is write-protected\n")
+    } else if READ_ONLY.load(Ordering::Relaxed) {
+        Some(b"Card is read-only\n")
+    } else {
+        None
+    }
+}
+
+// Directory entries read per batch; the scanner yields between batches so pages can
+// render its progress
+const SCAN_BATCH: usize = 8;
+
+// Set while a scan is running, with the number of entries it has read so far
+static SCAN_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static SCAN_PROGRESS: AtomicU32 = AtomicU32::new(0);
+
+// WiFi scans are run by main, which owns the cyw43 control handle. A handler signals
+// WIFI_SCAN_REQUEST and waits for the networks on WIFI_SCAN_DONE.
+#[cfg(not(feature = "sd-only"))]
+static WIFI_SCAN_REQUEST: embassy_sync::signal::Signal<SharedRawMutex, ()> =
+    embassy_sync::signal::Signal::new();
+#[cfg(not(feature = "sd-only"))]
+static WIFI_SCAN_DONE: embassy_sync::signal::Signal<
+    SharedRawMutex,
+    heapless::Vec<WifiNetwork, MAX_SCAN_RESULTS>,
+> = embassy_sync::signal::Signal::new();
+
+// When the last scan started. Held for the whole of /api/scan, so it also keeps two
+// clients from scanning at once.
+#[cfg(not(feature = "sd-only"))]
+static LAST_WIFI_SCAN: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    Option<Instant>,
+> = embassy_sync::mutex::Mutex::new(None);
+
+// Scanning takes the radio off the AP channel for a few seconds, so space scans out
+#[cfg(not(feature = "sd-only"))]
+const WIFI_SCAN_INTERVAL: Duration = Duration::from_secs(30);
+#[cfg(not(feature = "sd-only"))]
+const WIFI_SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(not(feature = "sd-only"))]
+const MAX_SCAN_RESULTS: usize = 16;
+
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone)]
+struct WifiNetwork {
+    ssid: heapless::String<32>,
+    bssid: [u8; 6],
+    rssi: i16,
+    channel: u8,
+}
+
+// Set at boot when the card has no WIFI.CFG, so / offers the first-run setup form
+#[cfg(not(feature = "sd-only"))]
+static NEEDS_SETUP: AtomicBool = AtomicBool::new(false);
+
+// The SSID the AP is currently using; starts as WIFI_SSID (or WIFI.CFG's) and changes with POST /wifi/ssid
+#[cfg(not(feature = "sd-only"))]
+static AP_SSID: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    heapless::String<32>,
+> = embassy_sync::mutex::Mutex::new(heapless::String::new());
+
+// Set while the card can't be read and the listing shows the files built into the firmware
+#[cfg(feature = "bundled-files")]
+static SERVING_BUNDLE: AtomicBool = AtomicBool::new(false);
+
+// Set when WIFI.CFG asks for the SSID to be left out of beacons
+#[cfg(not(feature = "sd-only"))]
+static SSID_HIDDEN: AtomicBool = AtomicBool::new(false);
+
+// WIFI.CFG's name=, shown in place of the listing's built-in title; empty when unset
+#[cfg(not(feature = "sd-only"))]
+static DEVICE_NAME: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    heapless::String<32>,
+> = embassy_sync::mutex::Mutex::new(heapless::String::new());
+
+// WIFI.CFG's uploads=, the extensions /copy and /append may write; empty allows any
+#[cfg(not(feature = "sd-only"))]
+static UPLOAD_TYPES: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    heapless::String<48>,
+> = embassy_sync::mutex::Mutex::new(heapless::String::new());
+
+// Set when WIFI.CFG asks for `/` to answer 503 while the card isn't readable
+#[cfg(not(feature = "sd-only"))]
+static SD_REQUIRED: AtomicBool = AtomicBool::new(false);
+
+// A new SSID for main to restart the AP with
+#[cfg(not(feature = "sd-only"))]
+static AP_SSID_CHANGE: embassy_sync::signal::Signal<
+    SharedRawMutex,
+    heapless::String<32>,
+> = embassy_sync::signal::Signal::new();
+
+// Boot sector of the mounted volume, for reporting FAT type and cluster size
+static SD_VOLUME: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    Option<fat::BootSector>,
+> = embassy_sync::mutex::Mutex::new(None);
+
+// Number of scans whose results matched the previous scan and were not republished
+static SCANS_SKIPPED: AtomicU32 = AtomicU32::new(0);
+
+// How long the most recent scan held the SD card, in milliseconds, for /diag
+static LAST_SCAN_MS: AtomicU32 = AtomicU32::new(0);
+
+// Times the AP was restarted after going WIFI.CFG's ap_restart= hours without a connection
+#[cfg(not(feature = "sd-only"))]
+static AP_RESTARTS: AtomicU32 = AtomicU32::new(0);
+
+// Entry counts of the most recent successful scans, oldest first, for the listing's sparkline
+const FILE_COUNT_SAMPLES: usize = 30;
+static FILE_COUNT_HISTORY: embassy_sync::mutex::Mutex<
+    SharedRawMutex,
+    heapless::Deque<u8, FILE_COUNT_SAMPLES>,
+> = embassy_sync::mutex::Mutex::new(heapless::Deque::new());
+
+#[derive(Clone)]
+struct FileInfo {
+    name: heapless::String<64>,
+    size: u64,
+    is_dir: bool,
+    // FAT attribute bits, as in a directory entry
+    attributes: u8,
+    modified: Timestamp,
+}
+
+impl FileInfo {
+    const READ_ONLY: u8 = 0x01;
+    const HIDDEN: u8 = 0x02;
+    const SYSTEM: u8 = 0x04;
+    const ARCHIVE: u8 = 0x20;
+
+    /// The bits of `attributes` the listing shows. The driver's type can only be read, so
+    /// entries that don't come from the card couldn't have one.
+    fn attribute_bits(attributes: &embedded_sdmmc::Attributes) -> u8 {
+        [
+            (attributes.is_read_only(), Self::READ_ONLY),
+            (attributes.is_hidden(), Self::HIDDEN),
+            (attributes.is_system(), Self::SYSTEM),
+            (attributes.is_archive(), Self::ARCHIVE),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    }
+
+    /// An entry as a `FileStore` lists it.
+    fn from_entry(entry: &store::Entry<'_>) -> Self {
+        FileInfo {
+            name: format_truncated(format_args!("{}", entry.name)),
+            size: entry.size,
+            is_dir: entry.is_dir,
+            attributes: entry.attributes,
+            modified: Timestamp::from_fat(entry.fat_date, entry.fat_time),
+        }
+    }
+
+    /// FAT attribute flags as `RHSA`, with `-` for each flag that isn't set.
+    fn attribute_flags(&self) -> [u8; 4] {
+        let flag = |bit: u8, letter: u8| if self.attributes & bit != 0 { letter } else { b'-' };
+        [
+            flag(Self::READ_ONLY, b'R'),
+            flag(Self::HIDDEN, b'H'),
+            flag(Self::SYSTEM, b'S'),
+            flag(Self::ARCHIVE, b'A'),
+        ]
+    }
+
+    /// Hidden and system entries are left out of the listing unless `?hidden=1` is given.
+    #[cfg(not(feature = "sd-only"))]
+    fn is_hidden(&self) -> bool {
+        self.attributes & (Self::HIDDEN | Self::SYSTEM) != 0
+    }
+
+    /// Entries dated in 1980, FAT's epoch, were written by something without a clock
+    /// (including this firmware), so their dates mean nothing.
+    #[cfg(not(feature = "sd-only"))]
+    fn has_timestamp(&self) -> bool {
+        self.modified.year_since_1970 > 10
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+#[embassy_executor::task]
+async fn cyw43_task(
+    runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
+) -> ! {
+    runner.run().await
+}
+
+#[cfg(not(feature = "sd-only"))]
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
+    runner.run().await
+}
+
+// Consecutive identical samples, 10 ms apart, before a write-protect change is accepted
+#[cfg(feature = "sd-write-protect")]
+const WP_DEBOUNCE_SAMPLES: u8 = 5;
+
+/// Tracks the SD socket's write-protect switch. The pin is pulled up and the switch is
+/// read as protected while the pin is high, which matches the common sockets where the
+/// contact to ground opens when the tab is slid to "lock".
+#[cfg(feature = "sd-write-protect")]
+#[embassy_executor::task]
+async fn write_protect_task(pin: embassy_rp::gpio::Input<'static>) {
+    let mut stable = pin.is_high();
+    WRITE_PROTECTED.store(stable, Ordering::Relaxed);
+    let mut streak = 0;
+    loop {
+        Timer::after(Duration::from_millis(10)).await;
+        if pin.is_high() == stable {
+            streak = 0;
+            continue;
+        }
+        streak += 1;
+        if streak >= WP_DEBOUNCE_SAMPLES {
+            stable = !stable;
+            streak = 0;
+            WRITE_PROTECTED.store(stable, Ordering::Relaxed);
+            info!("SD card write protection {}", if stable { "on" } else { "off" });
+        }
+    }
+}
+
+/// What to do with a warning passed through a `WarnThrottle`.
+enum Throttled {
+    /// New since the last one: log it.
+    Log,
+    /// The same again, and a summary is due: log it with the count so far.
+    Summary(u32),
+    /// The same again: stay quiet.
+    Quiet,
+}
+
+/// Collapses a run of identical warnings so a lasting fault doesn't flood RTT: the first
+/// is logged, the repeats are counted and summarised every `config::WARN_REPEAT_SUMMARY`.
+struct WarnThrottle<T> {
+    last: Option<T>,
+    count: u32,
+    summarised_at: Instant,
+}
+
+impl<T: PartialEq> WarnThrottle<T> {
+    fn new() -> Self {
+        WarnThrottle { last: None, count: 0, summarised_at: Instant::now() }
+    }
+
+    fn check(&mut self, warning: T) -> Throttled {
+        if self.last.as_ref() != Some(&warning) {
+            self.last = Some(warning);
+            self.count = 1;
+            self.summarised_at = Instant::now();
+            return Throttled::Log;
+        }
+        self.count += 1;
+        if self.summarised_at.elapsed() < config::WARN_REPEAT_SUMMARY {
+            return Throttled::Quiet;
+        }
+        self.summarised_at = Instant::now();
+        Throttled::Summary(self.count)
+    }
+
+    /// Ends the run once things work again, returning how many times it occurred.
+    fn clear(&mut self) -> u32 {
+        self.last = None;
+        core::mem::take(&mut self.count)
+    }
+}
+
+#[embassy_executor::task]
+async fn sd_card_task() {
+    info!("SD card task started, waiting for system to stabilize...");
+    Timer::after(Duration::from_secs(3)).await;
+
+    let mut last_signature: Option<ScanSignature> = None;
+    let mut errors = WarnThrottle::new();
+
+    loop {
+        log_info!("Attempting to read SD card...");
+        // This scan covers any rescan requested while we were waiting
+        RESCAN.reset();
+
+        let scan = {
+            let card = SD_BUS.lock().await;
+            let started = Instant::now();
+            // Keep showing the previous listing until the new one is complete, unless
+            // there's nothing valid to show
+            let scan = read_sd_card(&card, last_signature.is_none()).await;
+            LAST_SCAN_MS.store(started.elapsed().as_millis() as u32, Ordering::Relaxed);
+            scan
+        };
+
+        match scan {
+            Ok((file_list, boot_sector, truncated)) => {
+                *SD_VOLUME.lock().await = boot_sector;
+                #[cfg(feature = "bundled-files")]
+                SERVING_BUNDLE.store(false, Ordering::Relaxed);
+                let failures = errors.clear();
+                if failures > 1 {
+                    log_info!("SD card readable again after {} failed scans", failures);
+                }
+
+                {
+                    let mut history = FILE_COUNT_HISTORY.lock().await;
+                    if history.is_full() {
+                        history.pop_front();
+                    }
+                    let _ = history.push_back(file_list.len() as u8);
+                }
+
+                let signature = ScanSignature::of(&file_list);
+                if last_signature == Some(signature) {
+                    SCANS_SKIPPED.fetch_add(1, Ordering::Relaxed);
+                    log_info!("SD card unchanged ({} files), skipping update", file_list.len());
+                } else {
+                    // The first listing after boot or a card error is all new, not a change
+                    let had_listing = last_signature.replace(signature).is_some();
+
+                    // Update shared state
+                    {
+                        let mut files = SD_FILES.lock().await;
+                        if had_listing && STREAM_ACTIVE.load(Ordering::Relaxed) {
+                            queue_file_events(&files, &file_list);
+                        }
+                        files.clear();
+                        for file in &file_list {
+                            let _ = files.push(file.clone());
+                        }
+                    }
+
+                    LISTING_TRUNCATED.store(truncated, Ordering::Relaxed);
+                    {
+                        let mut status = SD_STATUS.lock().await;
+                        *status = if truncated { "Ready (too many entries, listing truncated)" } else { "Ready" };
+                    }
+
+                    // Forget counts for files that have been deleted
+                    DOWNLOAD_COUNTS.lock().await.retain(|entry| {
+                        file_list.iter().any(|f| !f.is_dir && f.name.eq_ignore_ascii_case(&entry.name))
+                    });
+
+                    let has_index = file_list.iter().any(|f| !f.is_dir && f.name == INDEX_FILE);
+                    ROOT_HAS_INDEX.store(has_index, Ordering::Relaxed);
+
+                    log_info!("SD card read successfully, found {} files", file_list.len());
+                }
+                SCAN_DONE.signal(Ok(file_list.len()));
+            }
+            Err(e) => {
+                // Force a republish once the card comes back
+                last_signature = None;
+                ROOT_HAS_INDEX.store(false, Ordering::Relaxed);
+                LISTING_TRUNCATED.store(false, Ordering::Relaxed);
+                SCAN_ERRORS.fetch_add(1, Ordering::Relaxed);
+                {
+                    let mut status = SD_STATUS.lock().await;
+                    *status = e;
+                }
+                match errors.check(e) {
+                    Throttled::Log => log_warn!("SD card error: {}", e),
+                    Throttled::Summary(count) => log_warn!("SD card error: {} (still failing, {} occurrences)", e, count),
+                    Throttled::Quiet => {}
+                }
+                #[cfg(feature = "bundled-files")]
+                publish_bundle().await;
+                SCAN_DONE.signal(Err(e));
+            }
+        }
+
+        let idle_secs = (Instant::now().as_secs() as u32).saturating_sub(LAST_REQUEST_SECS.load(Ordering::Relaxed));
+        if idle_secs >= SCANNER_IDLE_AFTER.as_secs() as u32 {
+            // Nobody is browsing - stop touching the card until the next connection
+            log_info!("No requests for {} s, pausing SD scanner", idle_secs);
+            SCANNER_WAKE.reset();
+            SCANNER_WAKE.wait().await;
+            log_info!("Client connected, resuming SD scanner");
+        } else {
+            // Scan every SCAN_INTERVAL_SECS, or sooner when a handler changed the card
+            let interval = Duration::from_secs(SCAN_INTERVAL_SECS.load(Ordering::Relaxed) as u64);
+            select(Timer::after(interval), RESCAN.wait()).await;
+        }
+    }
+}
+
+/// Lists the built-in files in place of the card's, so the usual routes serve them.
+#[cfg(feature = "bundled-files")]
+async fn publish_bundle() {
+    if SERVING_BUNDLE.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut files = SD_FILES.lock().await;
+    files.clear();
+    let _ = BUNDLE_STORE.list("", &mut |entry| {
+        let _ = files.push(FileInfo::from_entry(entry));
+    });
+    ROOT_HAS_INDEX.store(BUNDLE_STORE.file(INDEX_FILE).is_ok(), Ordering::Relaxed);
+    LISTING_TRUNCATED.store(bundle::FILES.len() > MAX_FILES, Ordering::Relaxed);
+    *SD_STATUS.lock().await = "No SD card, serving built-in files";
+    info!("Serving {} built-in files until a card is readable", bundle::FILES.len());
+}
+
+#[cfg(feature = "mock-sd")]
+static MOCK_STORE: MemoryStore = MemoryStore { files: mock_sd::FILES };
+#[cfg(feature = "bundled-files")]
+static BUNDLE_STORE: MemoryStore = MemoryStore { files: bundle::FILES };
+
+/// The files served from the firmware instead of the card: always with `mock-sd`, and
+/// with `bundled-files` while no card is readable. `None` means the card is the source.
+#[cfg(not(feature = "sd-only"))]
+fn memory_store() -> Option<&'static MemoryStore> {
+    #[cfg(feature = "mock-sd")]
+    let store = Some(&MOCK_STORE);
+    #[cfg(not(feature = "mock-sd"))]
+    let store: Option<&'static MemoryStore> = None;
+    #[cfg(feature = "bundled-files")]
+    let store = store.or_else(|| SERVING_BUNDLE.load(Ordering::Relaxed).then_some(&BUNDLE_STORE));
+    store
+}
+
+/// Where reads and writes go while `card` holds the bus.
+#[cfg(not(feature = "sd-only"))]
+fn file_store(card: &SdCardOwner) -> &dyn FileStore {
+    match memory_store() {
+        Some(store) => store,
+        None => card,
+    }
+}
+
+/// A change between two successive listings, for the `/api/stream` feed.
+#[derive(Clone)]
+struct FileEvent {
+    kind: FileEventKind,
+    name: heapless::String<64>,
+    size: u64,
+}
+
+#[derive(Clone, Copy)]
+enum FileEventKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl FileEventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileEventKind::Added => "added",
+            FileEventKind::Removed => "removed",
+            FileEventKind::Changed => "changed",
+        }
+    }
+}
+
+// Events waiting for the feed client. Only filled while a client is connected, and events
+// that don't fit are counted in FILE_EVENTS_DROPPED rather than waited for.
+static FILE_EVENTS: embassy_sync::channel::Channel<SharedRawMutex, FileEvent, 16> =
+    embassy_sync::channel::Channel::new();
+static STREAM_ACTIVE: AtomicBool = AtomicBool::new(false);
+static FILE_EVENTS_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Queues a `FileEvent` for every entry added, removed or changed (size or date) between
+/// `old` and `new`, matching names case-insensitively like FAT does.
+fn queue_file_events(old: &[FileInfo], new: &[FileInfo]) {
+    let same_name = |a: &FileInfo, b: &FileInfo| a.name.eq_ignore_ascii_case(&b.name);
+    let removed = old
+        .iter()
+        .filter(|o| !new.iter().any(|n| same_name(o, n)))
+        .map(|o| (FileEventKind::Removed, o));
+    let added_or_changed = new.iter().filter_map(|n| match old.iter().find(|o| same_name(o, n)) {
+        None => Some((FileEventKind::Added, n)),
+        Some(o) if o.size != n.size || o.modified != n.modified => Some((FileEventKind::Changed, n)),
+        Some(_) => None,
+    });
+    for (kind, file) in removed.chain(added_or_changed) {
+        let event = FileEvent { kind, name: file.name.clone(), size: file.size };
+        if FILE_EVENTS.try_send(event).is_err() {
+            FILE_EVENTS_DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Cheap fingerprint of a directory listing, used to detect whether a rescan changed anything.
+#[derive(Clone, Copy, PartialEq)]
+struct ScanSignature {
+    count: usize,
+    total_size: u64,
+    name_hash: u32,
+}
+
+impl ScanSignature {
+    fn of(files: &[FileInfo]) -> Self {
+        // FNV-1a over names, attribute flags and modification dates
+        let mut name_hash: u32 = 0x811c_9dc5;
+        let mut total_size = 0u64;
+        for file in files {
+            let m = &file.modified;
+            let date = [m.year_since_1970, m.zero_indexed_month, m.zero_indexed_day, m.hours, m.minutes, m.seconds];
+            for &byte in file.name.as_bytes().iter().chain(&file.attribute_flags()).chain(&date) {
+                name_hash ^= byte as u32;
+                name_hash = name_hash.wrapping_mul(0x0100_0193);
+            }
+            total_size += file.size;
+        }
+
+        ScanSignature {
+            count: files.len(),
+            total_size,
+            name_hash,
+        }
+    }
+}
+
+type SdSpiDevice = ExclusiveDevice<Spi<'static, SPI0, Blocking>, Output<'static>, embassy_time::Delay>;
+type SdCardDevice = SdCard<SdSpiDevice, embassy_time::Delay>;
+
+fn open_sd_card(_owner: &SdCardOwner) -> Result<SdCardDevice, &'static str> {
+    // Create SPI for SD card
+    let mut sd_spi_config = SpiConfig::default();
+    sd_spi_config.frequency = 400_000;
+
+    let spi = Spi::new_blocking(
+        unsafe { embassy_rp::peripherals::SPI0::steal() },
+        unsafe { embassy_rp::peripherals::PIN_18::steal() },
+        unsafe { embassy_rp::peripherals::PIN_19::steal() },
+        unsafe { embassy_rp::peripherals::PIN_16::steal() },
+        sd_spi_config,
+    );
+
+    let cs = Output::new(
+        unsafe { embassy_rp::peripherals::PIN_17::steal() },
+        Level::High,
+    );
+
+    // Create SD card instance
+    let spi_device = match ExclusiveDevice::new(spi, cs, embassy_time::Delay) {
+        Ok(dev) => dev,
+        Err(_) => return Err("Failed to create SPI device"),
+    };
+
+    Ok(SdCard::new(spi_device, embassy_time::Delay))
+}
+
+type SdVolumeManager = VolumeManager<SdCardDevice, DummyTimesource, 4, 4, 1>;
+
+fn open_volume_manager(owner: &SdCardOwner) -> Result<SdVolumeManager, &'static str> {
+    Ok(VolumeManager::new(init_sd_card(owner)?, DummyTimesource))
+}
+
+/// Opens the card and checks that it responds.
+fn init_sd_card(owner: &SdCardOwner) -> Result<SdCardDevice, &'static str> {
+    let sd_card = open_sd_card(owner)?;
+
+    // Initialize SD card
+    match sd_card.num_bytes() {
+        Ok(size) => {
+            log_info!("SD card detected: {} bytes", size);
+        }
+        Err(_) => {
+            return Err("No SD card detected");
+        }
+    };
+
+    Ok(sd_card)
+}
+
+// SD status for a card formatted as exFAT, which large cards often come as
+#[cfg(not(feature = "mock-sd"))]
+const EXFAT_UNSUPPORTED: &str = "exFAT not supported - reformat as FAT32";
+
+/// Reads the root directory listing, the volume's boot sector, and whether the directory
+/// had more entries than the listing can hold. With `mock-sd` the made-up files are
+/// listed instead and the card is never touched.
+///
+/// With `publish_partial`, each batch of entries is copied to `SD_FILES` as it's read, for
+/// when there's no earlier listing to show in the meantime.
+#[cfg_attr(feature = "mock-sd", allow(unused_variables))]
+async fn read_sd_card(
+    owner: &SdCardOwner,
+    publish_partial: bool,
+) -> Result<(heapless::Vec<FileInfo, MAX_FILES>, Option<fat::BootSector>, bool), &'static str> {
+    let mut file_list: heapless::Vec<FileInfo, MAX_FILES> = heapless::Vec::new();
+
+    // Not `file_store`: the built-in files only stand in once a scan of the card has
+    // failed, so it's still the card that's scanned
+    #[cfg(feature = "mock-sd")]
+    let (store, boot_sector): (&dyn FileStore, _) = (&MOCK_STORE, None);
+    #[cfg(not(feature = "mock-sd"))]
+    let (store, boot_sector): (&dyn FileStore, _) = (owner, check_volume(owner)?);
+
+    // List the directory in batches. A listing can't be resumed, so each batch re-walks the
+    // directory from the start and skips what earlier batches took; that costs a few extra
+    // block reads but lets pages render between batches.
+    SCAN_IN_PROGRESS.store(true, Ordering::Relaxed);
+    let mut taken = 0;
+    let truncated = loop {
+        let mut seen = 0;
+        let listed = store.list("", &mut |entry| {
+            if (taken..taken + SCAN_BATCH).contains(&seen) {
+                let _ = file_list.push(FileInfo::from_entry(entry));
+            }
+            seen += 1;
+        });
+        if let Err(e) = listed {
+            SCAN_IN_PROGRESS.store(false, Ordering::Relaxed);
+            #[cfg(feature = "debug-endpoints")]
+            if e == OPEN_VOLUME_FAILED && FORMAT_INTERRUPTED.load(Ordering::Relaxed) {
+                return Err(FORMAT_INTERRUPTED_STATUS);
+            }
+            return Err(e);
+        }
+        taken = seen.min(taken + SCAN_BATCH);
+        SCAN_PROGRESS.store(taken as u32, Ordering::Relaxed);
+
+        if publish_partial {
+            let mut files = SD_FILES.lock().await;
+            files.clear();
+            for file in &file_list {
+                let _ = files.push(file.clone());
+            }
+        }
+
+        if taken == seen || file_list.is_full() {
+            break seen > file_list.len();
+        }
+        yield_now().await;
+    };
+    SCAN_IN_PROGRESS.store(false, Ordering::Relaxed);
+
+    Ok((file_list, boot_sector, truncated))
+}
+
+/// Checks that the card responds and isn't exFAT, returning its first volume's boot
+/// sector if it has one.
+#[cfg(not(feature = "mock-sd"))]
+fn check_volume(owner: &SdCardOwner) -> Result<Option<fat::BootSector>, &'static str> {
+    let sd_card = init_sd_card(owner)?;
+    // The volume manager doesn't expose the BPB, so read it ourselves
+    let boot_sector = fat::first_volume(&sd_card).map(|(_, boot)| boot);
+    // Checked before opening the volume, whose error can't tell exFAT from a damaged card
+    if fat::first_volume_is_exfat(&sd_card) {
+        return Err(EXFAT_UNSUPPORTED);
+    }
+    Ok(boot_sector)
+}
+
+const OPEN_VOLUME_FAILED: &str = "Failed to open volume (format as FAT32)";
+const CLOSE_FAILED: &str = "Failed to close volume (card may need a check)";
+
+/// Closes `volume`, retrying once if the final flush fails. A close that keeps failing
+/// can leave the FAT out of step with the directory, so it's reported rather than
+/// dropped.
+fn close_volume_checked(
+    volume_mgr: &SdVolumeManager,
+    volume: embedded_sdmmc::RawVolume,
+) -> Result<(), &'static str> {
+    for attempt in 1..=2 {
+        match volume_mgr.close_volume(volume) {
+            Ok(()) => return Ok(()),
+            Err(e) => error!("Closing volume failed (attempt {}): {:?}", attempt, defmt::Debug2Format(&e)),
+        }
+        SD_RETRIES.fetch_add(1, Ordering::Relaxed);
+    }
+    Err(CLOSE_FAILED)
+}
+
+#[cfg(not(feature = "sd-only"))]
+type SdDirectory<'a> = embedded_sdmmc::Directory<'a, SdCardDevice, DummyTimesource, 4, 4, 1>;
+#[cfg(not(feature = "sd-only"))]
+type SdError = embedded_sdmmc::Error<embedded_sdmmc::SdCardError>;
+
+// Returned when all of the volume manager's file/directory slots are in use
+#[cfg(not(feature = "sd-only"))]
+const SD_BUSY: &str = "SD card busy (too many open files)";
+
+/// Maps a failed open to a message, singling out exhausted handle slots.
+#[cfg(not(feature = "sd-only"))]
+fn open_error(e: SdError) -> &'static str {
+    match e {
+        embedded_sdmmc::Error::TooManyOpenFiles | embedded_sdmmc::Error::TooManyOpenDirs => SD_BUSY,
+        embedded_sdmmc::Error::NotFound => "File not found",
+        _ => "Failed to open file",
+    }
+}
+
+/// Opens the root directory and runs `f` on it.
+///
+/// Volumes, directories and files are RAII handles that close themselves when dropped, so
+/// scoping them to this call releases every slot `f` opened on all paths, including early
+/// returns and errors. Prefer this over opening handles by hand.
+#[cfg(not(feature = "sd-only"))]
+fn with_root_dir<R>(
+    owner: &SdCardOwner,
+    f: impl FnOnce(&mut SdDirectory<'_>) -> Result<R, &'static str>,
+) -> Result<R, &'static str> {
+    let mut volume_mgr = open_volume_manager(owner)?;
+    let mut volume = volume_mgr
+        .open_volume(embedded_sdmmc::VolumeIdx(0))
+        .map_err(|_| OPEN_VOLUME_FAILED)?;
+    let mut root_dir = volume.open_root_dir().map_err(|_| "Failed to open root directory")?;
+    f(&mut root_dir)
+}
+
+/// Lists the root-level folder `dir`, or the root directory if `dir` is empty, for
+/// `FileStore::list`. Volume labels are left out, since they aren't files.
+fn list_dir(owner: &SdCardOwner, dir: &str, each: &mut dyn FnMut(&store::Entry<'_>)) -> Result<(), &'static str> {
+    let mut volume_mgr = open_volume_manager(owner)?;
+    let mut volume = volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)).map_err(|_| OPEN_VOLUME_FAILED)?;
+    let mut root_dir = volume.open_root_dir().map_err(|_| "Failed to open root directory")?;
+
+    let mut visit = |entry: &embedded_sdmmc::DirEntry| {
+        if entry.attributes.is_volume() {
+            return;
+        }
+        let name = format_truncated::<64>(format_args!("{}", entry.name));
+        let (fat_date, fat_time) = fat_date_time(&entry.mtime);
+        each(&store::Entry {
+            name: &name,
+            size: entry.size as u64,
+            is_dir: entry.attributes.is_directory(),
+            attributes: FileInfo::attribute_bits(&entry.attributes),
+            fat_date,
+            fat_time,
+        });
+    };
+    if dir.is_empty() {
+        root_dir.iterate_dir(&mut visit).map_err(|_| "Failed to read root directory")?;
+    } else {
+        let mut folder = match root_dir.open_dir(dir) {
+            Ok(folder) => folder,
+            Err(embedded_sdmmc::Error::NotFound | embedded_sdmmc::Error::OpenedFileAsDir) => {
+                return Err(store::NO_SUCH_FOLDER);
+            }
+            Err(_) => return Err("Failed to open folder"),
+        };
+        folder.iterate_dir(&mut visit).map_err(|_| "Failed to read folder")?;
+    }
+
+    if root_dir.close().is_err() {
+        error!("Failed to close root directory after listing");
+        return Err(CLOSE_FAILED);
+    }
+    close_volume_checked(&volume_mgr, volume.to_raw_volume())
+}
+
+/// `timestamp` as FAT packs it into a directory entry, the reverse of `Timestamp::from_fat`.
+fn fat_date_time(timestamp: &Timestamp) -> (u16, u16) {
+    let date = ((timestamp.year_since_1970.saturating_sub(10) as u16) << 9)
+        | ((timestamp.zero_indexed_month as u16 + 1) << 5)
+        | (timestamp.zero_indexed_day as u16 + 1);
+    let time = ((timestamp.hours as u16) << 11) | ((timestamp.minutes as u16) << 5) | (timestamp.seconds as u16 / 2);
+    (date, time)
+}
+
+/// Reads up to `buf.len()` bytes from the start of a file, returning the number of bytes
+/// read and the file's full length.
+#[cfg(not(feature = "sd-only"))]
+fn read_file_head(store: &dyn FileStore, name: &str, buf: &mut [u8]) -> Result<(usize, u64), &'static str> {
+    store.read(name, FileWindow::From(0), buf).map(|(len, _, length)| (len, length))
+}
+
+/// Reads up to `buf.len()` bytes of a root-directory file from `window`, returning the
+/// number of bytes read, the offset they start at, and the file's full length. A start
+/// past the end of the file reads the tail instead.
+#[cfg(not(feature = "sd-only"))]
+fn read_file_window(
+    owner: &SdCardOwner,
+    name: &str,
+    window: FileWindow,
+    buf: &mut [u8],
+) -> Result<(usize, u64, u64), &'static str> {
+    with_root_dir(owner, |root_dir| {
+        let mut file = root_dir
+            .open_file_in_dir(name, embedded_sdmmc::Mode::ReadOnly)
+            .map_err(open_error)?;
+
+        let length = file.length() as u64;
+        let start = window.start(length, buf.len());
+        if start > 0 {
+            file.seek_from_start(start as u32).map_err(|_| "Failed to seek in file")?;
+        }
+
+        let mut filled = 0;
+        while filled < buf.len() && !file.is_eof() {
+            match file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(_) => return Err("Failed to read file"),
+            }
+        }
+
+        Ok((filled, start, length))
+    })
+}
+
+/// Replaces the contents of a root-directory file with `data`, creating it if needed.
+#[cfg(not(feature = "sd-only"))]
+fn write_file(owner: &SdCardOwner, name: &str, data: &[u8]) -> Result<(), &'static str> {
+    with_root_dir(owner, |root_dir| {
+        let mut file = root_dir
+            .open_file_in_dir(name, embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)
+            .map_err(open_error)?;
+        file.write(data).map_err(|_| "Failed to write file")?;
+        file.close().map_err(|_| "Failed to flush file")
+    })
+}
+
+/// The card itself, through the owner token so callers must hold `SD_BUS`.
+impl FileStore for SdCardOwner {
+    fn list(&self, dir: &str, each: &mut dyn FnMut(&store::Entry<'_>)) -> Result<(), &'static str> {
+        list_dir(self, dir, each)
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn read(&self, name: &str, window: FileWindow, buf: &mut [u8]) -> Result<(usize, u64, u64), &'static str> {
+        read_file_window(self, name, window, buf)
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), &'static str> {
+        write_file(self, name, data)
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn append(&self, name: &str, data: &[u8]) -> Result<u64, HttpError> {
+        append_file(self, name, data)
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn copy(&self, from: &str, to: &str, force: bool) -> Result<u64, HttpError> {
+        copy_file(self, from, to, force)
+    }
+}
+
+/// The built-in settings, used where WIFI.CFG doesn't set something.
+#[cfg(not(feature = "sd-only"))]
+fn default_wifi_config() -> WifiConfig {
+    let mut defaults = WifiConfig {
+        ssid: heapless::String::new(),
+        password: heapless::String::new(),
+        channel: AP_CHANNEL,
+        lang: Lang::En,
+        log_level: 0,
+        read_only: false,
+        heartbeat: true,
+        scan_interval: 30,
+        refresh: 5,
+        power_mode: PowerMode::Performance,
+        hidden: false,
+        ap_restart_hours: 0,
+        name: heapless::String::new(),
+        upload_types: heapless::String::new(),
+        sd_required: false,
+    };
+    let _ = defaults.ssid.push_str(WIFI_SSID);
+    let _ = defaults.password.push_str(WIFI_PASSWORD);
+    defaults
+}
+
+/// `base` with its runtime settings replaced by the ones currently in effect.
+#[cfg(not(feature = "sd-only"))]
+fn runtime_settings(base: WifiConfig) -> WifiConfig {
+    WifiConfig {
+        lang: Lang::from_u8(LANG.load(Ordering::Relaxed)),
+        log_level: LOG_LEVEL.load(Ordering::Relaxed),
+        read_only: READ_ONLY.load(Ordering::Relaxed),
+        heartbeat: HEARTBEAT.load(Ordering::Relaxed),
+        scan_interval: SCAN_INTERVAL_SECS.load(Ordering::Relaxed) as u16,
+        refresh: REFRESH_SECS.load(Ordering::Relaxed) as u16,
+        power_mode: PowerMode::from_u8(POWER_MODE.load(Ordering::Relaxed)),
+        ..base
+    }
+}
+
+/// Puts the runtime settings in `settings` into effect. The AP settings only take effect
+/// at boot.
+#[cfg(not(feature = "sd-only"))]
+fn apply_runtime_settings(settings: &WifiConfig) {
+    LANG.store(settings.lang.to_u8(), Ordering::Relaxed);
+    LOG_LEVEL.store(settings.log_level, Ordering::Relaxed);
+    READ_ONLY.store(settings.read_only, Ordering::Relaxed);
+    HEARTBEAT.store(settings.heartbeat, Ordering::Relaxed);
+    SCAN_INTERVAL_SECS.store(settings.scan_interval as u32, Ordering::Relaxed);
+    REFRESH_SECS.store(settings.refresh as u32, Ordering::Relaxed);
+    if POWER_MODE.swap(settings.power_mode.to_u8(), Ordering::Relaxed) != settings.power_mode.to_u8() {
+        POWER_MODE_CHANGE.signal(settings.power_mode);
+    }
+}
+
+/// Stops the AP's beacons from carrying the SSID, through the firmware's `closednet`
+/// setting. Clients then have to type the name in. Has to be reapplied after every AP start.
+#[cfg(not(feature = "sd-only"))]
+async fn hide_ssid(control: &mut cyw43::Control<'_>) {
+    control.set_iovar_u32("closednet", 1).await;
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn cyw43_power_mode(mode: PowerMode) -> cyw43::PowerManagementMode {
+    match mode {
+        PowerMode::Performance => cyw43::PowerManagementMode::Performance,
+        PowerMode::PowerSave => cyw43::PowerManagementMode::PowerSave,
+        PowerMode::Aggressive => cyw43::PowerManagementMode::Aggressive,
+    }
+}
+
+/// Reads `WIFI.CFG` over the built-in settings. `Ok(None)` means the card has no config
+/// file yet; an unreadable card is an error so it isn't mistaken for a first run.
+#[cfg(not(feature = "sd-only"))]
+async fn load_wifi_config(defaults: WifiConfig) -> Result<Option<WifiConfig>, &'static str> {
+    let mut buf = [0u8; wifi_config::MAX_LEN];
+    let result = {
+        let card = SD_BUS.lock().await;
+        read_file_head(file_store(&card), wifi_config::FILE_NAME, &mut buf)
+    };
+    match result {
+        Ok((len, _)) => {
+            let text = core::str::from_utf8(&buf[..len]).map_err(|_| "WIFI.CFG is not valid UTF-8")?;
+            defaults.parse(text).map(Some)
+        }
+        Err("File not found") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens and drops `name` more times than there are file slots; a leaked handle makes
+/// this fail with `SD_BUSY` partway through.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+fn check_handle_release(owner: &SdCardOwner, name: &str) -> Result<u32, &'static str> {
+    const ROUNDS: u32 = 32;
+    with_root_dir(owner, |root_dir| {
+        for _ in 0..ROUNDS {
+            let file = root_dir
+                .open_file_in_dir(name, embedded_sdmmc::Mode::ReadOnly)
+                .map_err(open_error)?;
+            drop(file);
+        }
+        Ok(ROUNDS)
+    })
+}
+
+/// `YYYY-MM-DD HH:MM`, in whatever time zone the writing computer used.
+#[cfg(not(feature = "sd-only"))]
+fn format_timestamp(timestamp: &Timestamp) -> heapless::String<16> {
+    let mut text = heapless::String::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut text,
+        format_args!(
+            "{:04}-{:02}-{:02} {:02}:{:02}",
+            1970 + timestamp.year_since_1970 as u16,
+            timestamp.zero_indexed_month + 1,
+            timestamp.zero_indexed_day + 1,
+            timestamp.hours,
+            timestamp.minutes
+        ),
+    );
+    text
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn format_size(bytes: u64) -> heapless::String<16> {
+    let mut result = heapless::String::new();
+
+    if bytes < 1024 {
+        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} B", bytes));
+    } else if bytes < 1024 * 1024 {
+        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} KB", bytes / 1024));
+    } else if bytes < 1024 * 1024 * 1024 {
+        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} MB", bytes / (1024 * 1024)));
+    } else {
+        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} GB", bytes / (1024 * 1024 * 1024)));
+    }
+
+    result
+}
+
+// Upper bound on network interfaces served at once (e.g. AP + station)
+#[cfg(not(feature = "sd-only"))]
+const MAX_HTTP_INTERFACES: usize = 2;
+
+// Connections currently being handled, across all server tasks
+#[cfg(not(feature = "sd-only"))]
+static ACTIVE_CONNECTIONS: AtomicU32 = AtomicU32::new(0);
+
+// Accepts that found a client already waiting, and the longest run of them back to back
+// on one socket, for /metrics. A long run means the listening sockets aren't keeping up.
+#[cfg(not(feature = "sd-only"))]
+static BACKLOGGED_ACCEPTS: AtomicU32 = AtomicU32::new(0);
+#[cfg(not(feature = "sd-only"))]
+static PEAK_CONCURRENT_WAITERS: AtomicU32 = AtomicU32::new(0);
+
+/// Counts a connection in `ACTIVE_CONNECTIONS` until dropped, so the count stays right
+/// however the connection ends. There's no cap: each listening socket serves one client
+/// at a time, and the TCP backlog holds the rest.
+#[cfg(not(feature = "sd-only"))]
+struct ConnectionSlot;
+
+#[cfg(not(feature = "sd-only"))]
+impl ConnectionSlot {
+    fn acquire() -> Self {
+        ACTIVE_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ConnectionSlot
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        ACTIVE_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the listing has something to show: the last scan read the card, or the
+/// built-in files stand in for it.
+#[cfg(not(feature = "sd-only"))]
+fn sd_ready(status: &str) -> bool {
+    #[cfg(feature = "bundled-files")]
+    if SERVING_BUNDLE.load(Ordering::Relaxed) {
+        return true;
+    }
+    status.starts_with("Ready")
+}
+
+/// The `sd_required=1` answer for `/` while the card can't be read, so monitors see the
+/// board as down rather than a `200` page with a warning on it.
+#[cfg(not(feature = "sd-only"))]
+async fn send_not_ready(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let body: &[u8] = b"<!DOCTYPE html>\n<html><body><h1>SD not ready</h1><p>The SD card can't be read yet.</p></body></html>\n";
+    ResponseBuilder::new(b"503 Service Unavailable")
+        .content_type("text/html; charset=utf-8")
+        .content_length(body.len() as u64)
+        .header("Retry-After", b"5")
+        .header("Cache-Control", b"no-store")
+        .send(socket)
+        .await;
+    if !request.is_head() {
+        let _ = socket.write_all(body).await;
+    }
+}
+
+/// Starts `config::LISTEN_SOCKETS` accept loops per network stack so the browser is
+/// reachable on every interface.
+#[cfg(not(feature = "sd-only"))]
+fn spawn_http_servers(spawner: &Spawner, stacks: &[&'static Stack<'static>]) {
+    for (interface, stack) in stacks.iter().enumerate() {
+        for listener in 0..config::LISTEN_SOCKETS {
+            match http_server_task(interface, listener, stack) {
+                Ok(token) => spawner.spawn(token),
+                Err(_) => warn!(
+                    "Not serving interface {}: at most {} interfaces are supported",
+                    interface, MAX_HTTP_INTERFACES
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+#[embassy_executor::task(pool_size = MAX_HTTP_INTERFACES * config::LISTEN_SOCKETS)]
+async fn http_server_task(interface: usize, listener: usize, stack: &'static Stack<'static>) {
+    info!("HTTP server task {} started on interface {}", listener, interface);
+    Timer::after(Duration::from_millis(500)).await;
+    match stack.config_v4() {
+        Some(config) => info!("Starting HTTP server on {}:80", config.address.address()),
+        None => info!("Starting HTTP server on interface {} port 80", interface),
+    }
+
+    let mut rx_buffer = [0; config::SOCKET_RX_BUFFER];
+    let mut tx_buffer = [0; config::SOCKET_TX_BUFFER];
+    let mut request_count = 0u32;
+    // Clients found waiting in a row, and how long the last request took to handle
+    let mut waiters = 0u32;
+    let mut last_handled = Duration::from_ticks(0);
+    let mut accept_errors = WarnThrottle::new();
+
+    loop {
+        // Accepting on a stack without link fails immediately, so park until it's back
+        // instead of spinning through accept errors
+        if !stack.is_link_up() {
+            warn!("Interface {} link down, waiting for it to return", interface);
+            stack.wait_link_up().await;
+            info!("Interface {} link up, accepting again", interface);
+        }
+
+        let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(config::SOCKET_TIMEOUT));
+
+        log_info!(
+            "Listening on TCP:80... (requests served: {})",
+            request_count
+        );
+        let listening_at = Instant::now();
+        if let Err(e) = socket.accept(80).await {
+            match accept_errors.check(e) {
+                Throttled::Log => log_warn!("Accept error: {:?}", e),
+                Throttled::Summary(count) => log_warn!("Accept error: {:?} (still failing, {} occurrences)", e, count),
+                Throttled::Quiet => {}
+            }
+            Timer::after(Duration::from_millis(100)).await;
+            continue;
+        }
+        accept_errors.clear();
+        let accepted_at = Instant::now();
+        note_backlog(&mut waiters, accepted_at - listening_at, last_handled);
+
+        log_info!("Received connection from {:?}", socket.remote_endpoint());
+        let _slot = ConnectionSlot::acquire();
+        request_count += 1;
+        LAST_REQUEST_SECS.store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+        SCANNER_WAKE.signal(());
+
+        match handle_client(&mut socket).await {
+            Ok(_) => log_info!("Request #{} completed successfully", request_count),
+            Err(e) => {
+                FAILED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+                log_warn!("Request #{} failed: {:?}", request_count, e)
+            }
+        }
+
+        socket.abort();
+        last_handled = Instant::now() - accepted_at;
+        Timer::after(Duration::from_millis(50)).await;
+    }
+}
+
+/// Counts an accept that took no longer than `config::BACKLOG_ACCEPT_WINDOW` as a client
+/// that was queued behind the previous request, and warns when that request was slow.
+#[cfg(not(feature = "sd-only"))]
+fn note_backlog(waiters: &mut u32, accept_wait: Duration, last_handled: Duration) {
+    if accept_wait > config::BACKLOG_ACCEPT_WINDOW {
+        *waiters = 0;
+        return;
+    }
+    *waiters += 1;
+    BACKLOGGED_ACCEPTS.fetch_add(1, Ordering::Relaxed);
+    PEAK_CONCURRENT_WAITERS.fetch_max(*waiters, Ordering::Relaxed);
+    if last_handled >= config::BACKLOG_WARN_AFTER {
+        log_warn!(
+            "Client was waiting behind a {} ms request ({} in a row); raise LISTEN_SOCKETS to serve more at once",
+            last_handled.as_millis(),
+            *waiters
+        );
+    }
+}
+
+/// Serves `GET /api/stream` on `config::STREAM_PORT`, one client at a time, so a feed
+/// left open doesn't hold the page server's socket.
+#[cfg(not(feature = "sd-only"))]
+#[embassy_executor::task]
+async fn stream_server_task(stack: &'static Stack<'static>) {
+    let mut rx_buffer = [0; config::STREAM_RX_BUFFER];
+    let mut tx_buffer = [0; config::STREAM_TX_BUFFER];
+    let mut buf = [0; config::MAX_REQUEST_LINE];
+
+    loop {
+        if !stack.is_link_up() {
+            stack.wait_link_up().await;
+        }
+        let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(config::SOCKET_TIMEOUT));
+        if let Err(e) = socket.accept(config::STREAM_PORT).await {
+            log_warn!("Stream accept error: {:?}", e);
+            Timer::after(Duration::from_millis(100)).await;
+            continue;
+        }
+
+        // Only the request line matters; the rest of the first read is ignored
+        let n = match embassy_time::with_timeout(config::IDLE_TIMEOUT, socket.read(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            _ => 0,
+        };
+        match parse_request(&buf[..n]) {
+            Some(request) if request.path == "/api/stream" && (request.method == "GET" || request.is_head()) => {
+                send_file_events(&mut socket, &request).await
+            }
+            Some(_) => send_plain(&mut socket, b"404 Not Found", b"Only /api/stream is served on this port\n").await,
+            None => {}
+        }
+
+        let _ = socket.flush().await;
+        socket.abort();
+        Timer::after(Duration::from_millis(50)).await;
+    }
+}
+
+/// The NDJSON event feed: a `hello` line, then one line per file added, removed or
+/// changed as scans notice them, `ping` lines while nothing happens, and a final `bye`
+/// once `config::STREAM_MAX_DURATION` is up. Ends early if the client goes away.
+#[cfg(not(feature = "sd-only"))]
+async fn send_file_events(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    ResponseBuilder::new(b"200 OK")
+        .content_type("application/x-ndjson")
+        .header("Cache-Control", b"no-store")
+        .send(socket)
+        .await;
+    if request.is_head() {
+        return;
+    }
+
+    // Events from before this client connected aren't its business
+    FILE_EVENTS.clear();
+    FILE_EVENTS_DROPPED.store(0, Ordering::Relaxed);
+    STREAM_ACTIVE.store(true, Ordering::Relaxed);
+    log_info!("Event stream client connected");
+
+    let mut line = heapless::String::<128>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!("{{\"event\":\"hello\",\"files\":{}}}\n", SD_FILES.lock().await.len()),
+    );
+    let mut connected = socket.write_all(line.as_bytes()).await.is_ok();
+
+    let deadline = Instant::now() + config::STREAM_MAX_DURATION;
+    while connected {
+        line.clear();
+        let wake = deadline.min(Instant::now() + config::STREAM_PING_INTERVAL);
+        match select(FILE_EVENTS.receive(), Timer::at(wake)).await {
+            Either::First(event) => {
+                let _ = core::fmt::Write::write_fmt(
+                    &mut line,
+                    format_args!("{{\"event\":\"{}\",\"name\":", event.kind.as_str()),
+                );
+                push_json_str(&mut line, &event.name);
+                let _ = core::fmt::Write::write_fmt(&mut line, format_args!(",\"size\":{}}}\n", event.size));
+            }
+            Either::Second(()) if Instant::now() >= deadline => {
+                let _ = socket.write_all(b"{\"event\":\"bye\",\"reason\":\"max duration reached\"}\n").await;
+                break;
+            }
+            Either::Second(()) => {
+                let _ = line.push_str("{\"event\":\"ping\"}\n");
+            }
+        }
+        let dropped = FILE_EVENTS_DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let _ = core::fmt::Write::write_fmt(
+                &mut line,
+                format_args!("{{\"event\":\"overflow\",\"dropped\":{}}}\n", dropped),
+            );
+        }
+        connected = socket.write_all(line.as_bytes()).await.is_ok() && socket.flush().await.is_ok();
+    }
+
+    STREAM_ACTIVE.store(false, Ordering::Relaxed);
+    log_info!("Event stream client disconnected");
+}
+
+/// A parsed request: the request line split into its parts plus the raw header block.
+#[cfg(not(feature = "sd-only"))]
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    version: &'a str,
+    // Everything after the request line, up to the end of the first read
+    headers: &'a [u8],
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl<'a> Request<'a> {
+    /// Looks up a header by case-insensitive name. Headers that aren't valid UTF-8 are skipped.
+    fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .split(|&b| b == b'\n')
+            .take_while(|line| !line.is_empty() && *line != b"\r")
+            .filter_map(|line| core::str::from_utf8(line).ok())
+            .find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+    }
+
+    /// The part of the body that arrived in the first read, or `None` if the headers
+    /// didn't fit in it.
+    fn body_prefix(&self) -> Option<&'a [u8]> {
+        if let Some(body) = self.headers.strip_prefix(b"\r\n") {
+            return Some(body);
+        }
+        let end = self.headers.windows(4).position(|w| w == b"\r\n\r\n")?;
+        Some(&self.headers[end + 4..])
+    }
+
+    fn content_length(&self) -> Option<usize> {
+        self.header("Content-Length")?.parse().ok()
+    }
+
+    /// Whether the client is waiting for `100 Continue` before sending the body. Only
+    /// HTTP/1.1 clients may ask.
+    fn expects_continue(&self) -> bool {
+        self.version == "HTTP/1.1" && self.header("Expect").is_some_and(|v| v.eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether `If-None-Match` lists `etag`. Compared weakly, as the header requires, so
+    /// a `W/` prefix on either side is ignored.
+    fn has_etag(&self, etag: &str) -> bool {
+        let etag = etag.trim_start_matches("W/");
+        self.header("If-None-Match").is_some_and(|tags| {
+            tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+    }
+
+    /// Every mainstream browser's User-Agent starts with `Mozilla/`; curl, wget and
+    /// scripting libraries don't.
+    fn is_browser(&self) -> bool {
+        self.header("User-Agent").is_some_and(|agent| agent.starts_with("Mozilla/"))
+    }
+
+    fn is_head(&self) -> bool {
+        self.method == "HEAD"
+    }
+
+    fn accepts_gzip(&self) -> bool {
+        self.header("Accept-Encoding").is_some_and(|value| {
+            value.split(',').any(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                params.next() == Some("gzip") && !params.any(|p| p == "q=0" || p == "q=0.0")
+            })
+        })
+    }
+}
+
+/// Parses the request line from the raw request bytes, leaving the headers unparsed.
+#[cfg(not(feature = "sd-only"))]
+fn parse_request(request: &[u8]) -> Option<Request<'_>> {
+    let end = request.iter().position(|&b| b == b'\n')?;
+    let line = request[..end].strip_suffix(b"\r").unwrap_or(&request[..end]);
+    let line = core::str::from_utf8(line).ok()?;
+
+    let mut parts = line.split(' ');
+    let (method, target, version) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some()
+        || method.is_empty()
+        || !method.bytes().all(|b| b.is_ascii_uppercase())
+        || !(target.starts_with('/') || target == "*")
+        || !version.starts_with("HTTP/")
+    {
+        return None;
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+
+    Some(Request {
+        method,
+        path,
+        query,
+        version,
+        headers: &request[end + 1..],
+    })
+}
+
+/// Methods the server handles. POST-only routes allow the last two.
+#[cfg(not(feature = "sd-only"))]
+const ALLOWED_METHODS: [&str; 4] = ["GET", "HEAD", "POST", "OPTIONS"];
+
+// Routes that only take POST; any other method gets 405
+#[cfg(not(feature = "sd-only"))]
+const POST_ONLY_ROUTES: [&str; 7] = [
+    "/api/errors/clear",
+    "/api/ops/cancel",
+    "/copy",
+    "/append",
+    "/download-selected",
+    "/wifi/ssid",
+    "/setup",
+];
+
+#[cfg(not(feature = "sd-only"))]
+fn is_post_only(path: &str) -> bool {
+    POST_ONLY_ROUTES.contains(&route_name(path))
+}
+
+/// The `Allow` header value for `path`.
+#[cfg(not(feature = "sd-only"))]
+fn allow_header(path: &str) -> heapless::String<32> {
+    let methods = if is_post_only(path) { &ALLOWED_METHODS[2..] } else { &ALLOWED_METHODS[..] };
+    let mut allow = heapless::String::new();
+    for (i, method) in methods.iter().enumerate() {
+        if i > 0 {
+            let _ = allow.push_str(", ");
+        }
+        let _ = allow.push_str(method);
+    }
+    allow
+}
+
+/// `204` listing the methods `path` (or the whole server, for `*`) accepts.
+#[cfg(not(feature = "sd-only"))]
+async fn send_options(socket: &mut TcpSocket<'_>, path: &str) {
+    ResponseBuilder::new(b"204 No Content")
+        .header("Allow", allow_header(path).as_bytes())
+        .send(socket)
+        .await;
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn send_method_not_allowed(socket: &mut TcpSocket<'_>, path: &str) {
+    let message = if is_post_only(path) { "Use POST" } else { "Method not allowed" };
+    let (body, content_type) = if path.starts_with("/api/") {
+        (api_error_body(b"405 Method Not Allowed", message, ""), "application/json")
+    } else {
+        (format_truncated(format_args!("{}\n", message)), "text/plain; charset=utf-8")
+    };
+    ResponseBuilder::new(b"405 Method Not Allowed")
+        .content_type(content_type)
+        .content_length(body.len() as u64)
+        .header("Allow", allow_header(path).as_bytes())
+        .send(socket)
+        .await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// Copies a `Content-Length`-delimited request body into `sink`: first the part that
+/// arrived with the headers, then the rest straight from the socket, however many
+/// segments it's split across.
+///
+/// A client that sent `Expect: 100-continue` holds the body back until it's told to go
+/// ahead, so that's sent here, once the handler has decided to accept the request.
+#[cfg(not(feature = "sd-only"))]
+async fn read_body<W: Write>(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+    content_length: usize,
+    sink: &mut W,
+) -> Result<(), &'static str> {
+    let already_buffered = request.body_prefix().unwrap_or(&[]);
+    let buffered = &already_buffered[..already_buffered.len().min(content_length)];
+    sink.write_all(buffered).await.map_err(|_| "Failed to store body")?;
+
+    if request.expects_continue() && buffered.len() < content_length {
+        // An interim response; the final status line follows once the body is in
+        if socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await.is_err() || socket.flush().await.is_err() {
+            return Err("Connection closed before end of body");
+        }
+    }
+
+    let mut remaining = content_length - buffered.len();
+    let mut chunk = [0u8; 512];
+    while remaining > 0 {
+        let want = remaining.min(chunk.len());
+        let n = match embassy_time::with_timeout(config::REQUEST_TIMEOUT, socket.read(&mut chunk[..want])).await {
+            Ok(Ok(0)) | Ok(Err(_)) => return Err("Connection closed before end of body"),
+            Ok(Ok(n)) => n,
+            Err(_) => return Err("Timed out waiting for body"),
+        };
+        sink.write_all(&chunk[..n]).await.map_err(|_| "Failed to store body")?;
+        remaining -= n;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn handle_client(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    let mut buf = [0; config::REQUEST_BUFFER];
+
+    // Read request with timeout. Nothing has arrived yet, so until it does this is an idle
+    // connection and gets the shorter idle timeout
+    let n = match embassy_time::with_timeout(config::IDLE_TIMEOUT, socket.read(&mut buf)).await {
+        Ok(Ok(n)) => n,
+        Ok(Err(e)) => {
+            log_warn!("Read error: {:?}", e);
+            return Err(e);
+        }
+        Err(_) => {
+            log_info!("Idle connection closed after {} s", config::IDLE_TIMEOUT.as_secs());
+            return Ok(());
+        }
+    };
+
+    if n == 0 {
+        log_info!("Empty request, closing");
+        return Ok(());
+    }
+
+    log_info!("HTTP Request ({} bytes)", n);
+
+    // Only the request line has to be text; headers and bodies may carry arbitrary bytes
+    let Some(request) = parse_request(&buf[..n]) else {
+        log_warn!("Malformed request line");
+        send_plain(socket, b"400 Bad Request", b"Malformed request line\n").await;
+        Timer::after(Duration::from_millis(100)).await;
+        return Ok(());
+    };
+    log_info!("Method: {}, Path: {}, {}", request.method, request.path, request.version);
+    let _in_flight = inflight::start(socket, request.is_head());
+
+    // Answered before anything else, since `OPTIONS *` isn't a path
+    if request.method == "OPTIONS" {
+        send_options(socket, request.path).await;
+        return Ok(());
+    }
+    if !ALLOWED_METHODS.contains(&request.method) {
+        send_method_not_allowed(socket, request.path).await;
+        return Ok(());
+    }
+
+    if let Some(mut location) = canonical_path(request.path).await {
+        if !request.query.is_empty() && (location.push('?').is_err() || location.push_str(request.query).is_err()) {
+            send_plain(socket, b"414 URI Too Long", b"Request target too long\n").await;
+            return Ok(());
+        }
+        // 301 lets clients turn a POST into a GET; 308 keeps the method and body
+        let status: &[u8] = if request.method == "GET" || request.is_head() {
+            b"301 Moved Permanently"
+        } else {
+            b"308 Permanent Redirect"
+        };
+        log_info!("Redirecting {} -> {}", request.path, location.as_str());
+        send_redirect(socket, status, location.as_bytes()).await;
+        Timer::after(Duration::from_millis(100)).await;
+        return Ok(());
+    }
+    count_route(request.path);
+
+    match request.path {
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/benchmark" => send_benchmark(socket).await,
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/handles" => send_handle_check(socket).await,
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/mbr" => send_mbr(socket).await,
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/fsck" => send_fsck(socket).await,
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/log" => send_debug_log(socket).await,
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/format" if request.method == "POST" => send_format(socket, &request).await,
+        #[cfg(feature = "debug-endpoints")]
+        "/debug/format" => send_format_page(socket).await,
+        "/view" => send_view(socket, &request).await,
+        "/download" => send_download(socket, &request).await,
+        "/download-dir" => send_download_dir(socket, &request).await,
+        "/thumb" => send_thumb(socket, &request).await,
+        "/rescan" => send_rescan(socket, &request).await,
+        "/metrics" => send_metrics(socket).await,
+        "/api/sysinfo" => send_sysinfo(socket).await,
+        "/api/scan" => send_wifi_scan(socket).await,
+        "/api/files" => send_api_files(socket, &request).await,
+        "/diag" => send_diag(socket).await,
+        "/api/errors" => send_errors(socket).await,
+        "/api/sd-health" => send_sd_health(socket).await,
+        "/api/ops" => send_ops(socket).await,
+        "/api/stream" => {
+            let mut location = heapless::String::<48>::new();
+            let _ = core::fmt::Write::write_fmt(
+                &mut location,
+                format_args!("http://{}:{}/api/stream", AP_ADDRESS, config::STREAM_PORT),
+            );
+            send_redirect(socket, b"307 Temporary Redirect", location.as_bytes()).await
+        }
+        "/api/errors/clear" if request.method == "POST" => send_errors_clear(socket).await,
+        "/api/errors/clear" => send_method_not_allowed(socket, request.path).await,
+        path if route_name(path) == "/api/ops/cancel" => {
+            if request.method == "POST" {
+                send_op_cancel(socket, path).await
+            } else {
+                send_method_not_allowed(socket, path).await
+            }
+        }
+        "/favicon.ico" => send_favicon(socket).await,
+        "/style.css" => send_stylesheet(socket, &request).await,
+        "/api/config" if request.method == "POST" => send_api_config_update(socket, &request).await,
+        "/api/config" => send_api_config(socket).await,
+        "/config.cfg" => send_config_file(socket).await,
+        "/copy" if request.method == "POST" => send_copy(socket, &request).await,
+        "/copy" => send_method_not_allowed(socket, request.path).await,
+        "/append" if request.method == "POST" => send_append(socket, &request).await,
+        "/append" => send_method_not_allowed(socket, request.path).await,
+        "/download-selected" if request.method == "POST" => send_download_selected(socket, &request).await,
+        "/download-selected" => send_method_not_allowed(socket, request.path).await,
+        "/wifi/ssid" if request.method == "POST" => send_wifi_ssid(socket, &request).await,
+        "/wifi/ssid" => send_method_not_allowed(socket, request.path).await,
+        "/setup" if request.method == "POST" => send_setup(socket, &request).await,
+        "/setup" => send_method_not_allowed(socket, request.path).await,
+        path if path.starts_with("/api/") => api_error(socket, b"404 Not Found", "No such API endpoint").await,
+        "/" if NEEDS_SETUP.load(Ordering::Relaxed)
+            && request.is_browser()
+            && query_param(request.query, "setup") != Some("skip") =>
+        {
+            send_setup_page(socket).await
+        }
+        "/" if ROOT_HAS_INDEX.load(Ordering::Relaxed) && query_param(request.query, "raw").is_none() => {
+            if let Err(e) = send_file(socket, &request, INDEX_FILE, "text/html; charset=utf-8", false).await {
+                log_warn!("Serving {} failed: {}", INDEX_FILE, e);
+                send_index(socket, &request).await;
+            }
+        }
+        _ => send_index(socket, &request).await,
+    }
+
+    // Writes only queue data; wait until the client has it all, since the caller aborts the
+    // socket afterwards and that discards anything still unsent
+    if let Err(e) = socket.flush().await {
+        log_warn!("Flush error: {:?}", e);
+    }
+    log_info!("Response sent successfully");
+
+    Timer::after(Duration::from_millis(100)).await;
+    Ok(())
+}
+
+// 512 KB: about half a second at the benchmark clock
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+const BENCHMARK_BLOCKS: u32 = 1024;
+
+// Blocks read between yields, so the radio and network tasks keep running
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+const BENCHMARK_BATCH: u32 = 16;
+
+// The timed reads run at 25 MHz, the most an SD card takes in SPI mode, rather than the
+// 400 kHz the card is opened at, so the result shows the card's speed and not the clock's.
+// Lower this if the benchmark fails on long jumper wires.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+const BENCHMARK_SPI_FREQUENCY: u32 = 25_000_000;
+
+/// Reads `BENCHMARK_BLOCKS` blocks from the start of the card, returning the bytes read and
+/// the microseconds spent reading them. Time spent yielding between batches isn't counted.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn run_benchmark(owner: &SdCardOwner) -> Result<(u32, u64), &'static str> {
+    use embedded_sdmmc::{Block, BlockDevice, BlockIdx};
+
+    // Initialising the card has to happen at the slow clock
+    let sd_card = open_sd_card(owner)?;
+    if sd_card.num_bytes().is_err() {
+        return Err("No SD card detected");
+    }
+    sd_card.spi(|device| device.bus_mut().set_frequency(BENCHMARK_SPI_FREQUENCY));
+
+    let mut block = [Block::new()];
+    let mut elapsed_us = 0;
+    for batch in (0..BENCHMARK_BLOCKS).step_by(BENCHMARK_BATCH as usize) {
+        let start = Instant::now();
+        for idx in batch..batch + BENCHMARK_BATCH {
+            if sd_card.read(&mut block, BlockIdx(idx)).is_err() {
+                return Err("Block read failed");
+            }
+        }
+        elapsed_us += start.elapsed().as_micros();
+        yield_now().await;
+    }
+
+    Ok((BENCHMARK_BLOCKS * Block::LEN_U32, elapsed_us.max(1)))
+}
+
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_benchmark(socket: &mut TcpSocket<'_>) {
+    log_info!("Running SD benchmark ({} blocks)...", BENCHMARK_BLOCKS);
+
+    // The benchmark holds the SD bus until it finishes, so card requests wait for it
+    let previous_status = {
+        let mut status = SD_STATUS.lock().await;
+        let previous = *status;
+        *status = "Benchmark running...";
+        previous
+    };
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        run_benchmark(&card).await
+    };
+    *SD_STATUS.lock().await = previous_status;
+
+    let mut body = heapless::String::<128>::new();
+    match result {
+        Ok((bytes, elapsed_us)) => {
+            let bytes_per_sec = bytes as u64 * 1_000_000 / elapsed_us;
+            let centi_mb = bytes_per_sec * 100 / (1024 * 1024);
+            let _ = core::fmt::Write::write_fmt(
+                &mut body,
+                format_args!(
+                    "Read {} bytes in {} ms: {}.{:02} MB/s\n",
+                    bytes,
+                    elapsed_us / 1000,
+                    centi_mb / 100,
+                    centi_mb % 100
+                ),
+            );
+            log_info!("SD benchmark: {} bytes in {} us", bytes, elapsed_us);
+        }
+        Err(e) => {
+            let _ = core::fmt::Write::write_fmt(&mut body, format_args!("Benchmark failed: {}\n", e));
+            log_warn!("SD benchmark failed: {}", e);
+        }
+    }
+
+    ResponseBuilder::from_block(OK_TEXT).send(socket).await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// Returns the canonical form of `path` if it differs: duplicate slashes collapsed and a
+/// trailing slash on directories, so relative links inside directory pages resolve
+/// against the directory rather than its parent.
+#[cfg(not(feature = "sd-only"))]
+async fn canonical_path(path: &str) -> Option<heapless::String<{ config::MAX_REQUEST_LINE }>> {
+    let mut canonical = heapless::String::<{ config::MAX_REQUEST_LINE }>::new();
+    let mut last = "";
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        canonical.push('/').ok()?;
+        canonical.push_str(segment).ok()?;
+        last = segment;
+    }
+
+    let is_dir = if path.ends_with('/') || canonical.is_empty() {
+        true
+    } else if canonical.len() == last.len() + 1 && !is_route(&canonical) {
+        // Only the root is scanned, so only top-level folders can be recognised. A folder
+        // named like a route (CONFIG, VIEW, ...) mustn't take the route's URL over.
+        match decode_file_name(last) {
+            Some(name) => SD_FILES
+                .lock()
+                .await
+                .iter()
+                .any(|file| file.is_dir && file.name.eq_ignore_ascii_case(&name)),
+            None => false,
+        }
+    } else {
+        false
+    };
+    if is_dir {
+        canonical.push('/').ok()?;
+    }
+
+    (canonical != path).then_some(canonical)
+}
+
+/// Returns the raw value of `key` from a `a=1&b=2` style query string.
+#[cfg(not(feature = "sd-only"))]
+fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| match pair.split_once('=') {
+        Some((k, v)) if k == key => Some(v),
+        None if pair == key => Some(""),
+        _ => None,
+    })
+}
+
+/// Percent-decodes a query value that names a file in the root directory.
+///
+/// Returns `None` for anything that isn't a single plain path component, so callers
+/// never hand `..` or nested paths to the volume manager.
+#[cfg(not(feature = "sd-only"))]
+fn decode_file_name(value: &str) -> Option<heapless::String<64>> {
+    let mut name = heapless::String::<64>::new();
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = core::str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+                i += 2;
+                u8::from_str_radix(hex, 16).ok()?
+            }
+            b'+' => b' ',
+            byte => byte,
+        };
+        if !byte.is_ascii() || byte.is_ascii_control() || byte == b'/' || byte == b'\\' {
+            return None;
+        }
+        name.push(byte as char).ok()?;
+        i += 1;
+    }
+
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name)
+}
+
+/// Percent-encodes a file name for use inside a query string.
+#[cfg(not(feature = "sd-only"))]
+fn url_encode(name: &str) -> heapless::String<192> {
+    let mut encoded = heapless::String::new();
+    for &byte in name.as_bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b'_' | b'~') {
+            let _ = encoded.push(byte as char);
+        } else {
+            let _ = core::fmt::Write::write_fmt(&mut encoded, format_args!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// How the listing lays out entries, selected with `?view=list|grid`.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy, PartialEq)]
+enum ListingView {
+    List,
+    Grid,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl ListingView {
+    fn from_query(query: &str) -> Self {
+        match query_param(query, "view") {
+            Some("grid") => ListingView::Grid,
+            _ => ListingView::List,
+        }
+    }
+}
+
+/// A field shown for each listing entry. `?cols=` picks them as a comma-separated list
+/// in display order, e.g. `?cols=icon,name,size,date,attr`.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+    Icon,
+    Name,
+    Size,
+    Date,
+    Attr,
+}
+
+// Columns shown without ?cols=, kept short for phone screens
+#[cfg(not(feature = "sd-only"))]
+const DEFAULT_COLUMNS: [Column; 2] = [Column::Name, Column::Size];
+
+#[cfg(not(feature = "sd-only"))]
+impl Column {
+    const ALL: [Column; 5] = [Column::Icon, Column::Name, Column::Size, Column::Date, Column::Attr];
+
+    /// The columns named in `?cols=`. Unknown and repeated names are skipped, and a list
+    /// with nothing usable falls back to the defaults.
+    fn list_from_query(query: &str) -> heapless::Vec<Column, 5> {
+        let mut columns = heapless::Vec::new();
+        for name in query_param(query, "cols").unwrap_or("").split(',') {
+            if let Some(&column) = Column::ALL.iter().find(|c| c.as_str() == name) {
+                if !columns.contains(&column) {
+                    let _ = columns.push(column);
+                }
+            }
+        }
+        if columns.is_empty() {
+            let _ = columns.extend_from_slice(&DEFAULT_COLUMNS);
+        }
+        columns
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Column::Icon => "icon",
+            Column::Name => "name",
+            Column::Size => "size",
+            Column::Date => "date",
+            Column::Attr => "attr",
+        }
+    }
+}
+
+/// Which entries the listing shows, selected with `?show=all|files|dirs`.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy, PartialEq)]
+enum ShowFilter {
+    All,
+    Files,
+    Dirs,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl ShowFilter {
+    fn from_query(query: &str) -> Self {
+        match query_param(query, "show") {
+            Some("files") => ShowFilter::Files,
+            Some("dirs") => ShowFilter::Dirs,
+            _ => ShowFilter::All,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ShowFilter::All => "all",
+            ShowFilter::Files => "files",
+            ShowFilter::Dirs => "dirs",
+        }
+    }
+
+    fn matches(self, file_info: &FileInfo) -> bool {
+        match self {
+            ShowFilter::All => true,
+            ShowFilter::Files => !file_info.is_dir,
+            ShowFilter::Dirs => file_info.is_dir,
+        }
+    }
+}
+
+// Status line and content type of the most common responses
+#[cfg(not(feature = "sd-only"))]
+const OK_TEXT: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n";
+#[cfg(not(feature = "sd-only"))]
+const OK_HTML: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n";
+
+// Ends every response's header block. There's no `Date`: the board has no RTC (files get
+// DummyTimesource's zero timestamp too), and a made-up date is worse than none.
+#[cfg(not(feature = "sd-only"))]
+const COMMON_HEADERS: &[u8] = concat!(
+    "Server: LT7689/",
+    env!("CARGO_PKG_VERSION"),
+    "\r\nConnection: close\r\n\r\n"
+)
+.as_bytes();
+
+// Room for the status line and headers; a redirect's Location can be a whole request line
+#[cfg(not(feature = "sd-only"))]
+const RESPONSE_HEAD_LEN: usize = config::MAX_REQUEST_LINE + 512;
+
+/// Collects a response's status line and headers so they go out in a single write, and
+/// adds the headers every response shares in one place.
+#[cfg(not(feature = "sd-only"))]
+struct ResponseBuilder {
+    head: heapless::Vec<u8, RESPONSE_HEAD_LEN>,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl ResponseBuilder {
+    /// Starts a response with the given status (e.g. `b"404 Not Found"`).
+    fn new(status: &[u8]) -> Self {
+        let mut head = heapless::Vec::new();
+        let _ = head.extend_from_slice(b"HTTP/1.1 ");
+        let _ = head.extend_from_slice(status);
+        let _ = head.extend_from_slice(b"\r\n");
+        ResponseBuilder { head }
+    }
+
+    /// Starts from a precomputed status line and headers such as `OK_TEXT`.
+    fn from_block(block: &[u8]) -> Self {
+        let mut head = heapless::Vec::new();
+        let _ = head.extend_from_slice(block);
+        ResponseBuilder { head }
+    }
+
+    fn header(mut self, name: &str, value: &[u8]) -> Self {
+        let _ = self.head.extend_from_slice(name.as_bytes());
+        let _ = self.head.extend_from_slice(b": ");
+        let _ = self.head.extend_from_slice(value);
+        let _ = self.head.extend_from_slice(b"\r\n");
+        self
+    }
+
+    fn content_type(self, content_type: &str) -> Self {
+        self.header("Content-Type", content_type.as_bytes())
+    }
+
+    fn content_length(self, length: u64) -> Self {
+        let mut value = heapless::String::<20>::new();
+        let _ = core::fmt::Write::write_fmt(&mut value, format_args!("{}", length));
+        self.header("Content-Length", value.as_bytes())
+    }
+
+    /// Appends already formatted `Name: value\r\n` lines.
+    fn raw(mut self, lines: &[u8]) -> Self {
+        let _ = self.head.extend_from_slice(lines);
+        self
+    }
+
+    /// Writes the status line and headers, ending the header block.
+    async fn send(mut self, socket: &mut TcpSocket<'_>) {
+        if let Some(timing) = inflight::timing_header(socket) {
+            let _ = self.head.extend_from_slice(timing.as_bytes());
+        }
+        let _ = self.head.extend_from_slice(COMMON_HEADERS);
+        let _ = socket.write_all(&self.head).await;
+        // A HEAD response ends here. Closing the sending side makes the stack refuse
+        // whatever body the handler writes next, so no handler has to check for HEAD.
+        if inflight::is_head(socket) {
+            socket.close();
+        }
+    }
+}
+
+/// Writes a `200 OK` status line and the entity headers, ending the header block.
+///
+/// `length` is omitted for compressed bodies whose size isn't known up front; with
+/// `Connection: close` the body simply ends when the socket does.
+#[cfg(not(feature = "sd-only"))]
+async fn send_ok_headers(
+    socket: &mut TcpSocket<'_>,
+    content_type: &str,
+    length: Option<u64>,
+    gzip: bool,
+    extra: &[u8],
+) {
+    let mut response = ResponseBuilder::new(b"200 OK").content_type(content_type);
+    if let Some(length) = length {
+        response = response.content_length(length);
+    }
+    if gzip {
+        response = response.header("Content-Encoding", b"gzip");
+    }
+    response.header("Vary", b"Accept-Encoding").raw(extra).send(socket).await;
+}
+
+/// Folder holding precompressed copies of root files under the same names, e.g.
+/// `GZ/APP.JS` for `APP.JS`. Short names can't carry a second extension like `APP.JS.GZ`.
+#[cfg(not(feature = "sd-only"))]
+const GZ_SIDECAR_DIR: &str = "GZ";
+
+/// `send_file`'s error for a file refused by `config::DOWNLOAD_CHAIN_CHECK`.
+#[cfg(not(feature = "sd-only"))]
+const DAMAGED_FILE: &str = "File is damaged: its size doesn't match its clusters (see /debug/fsck)";
+
+/// Streams a root-directory file as the response body. Errors are only returned before
+/// any of the response has been sent, so the caller can still answer with something else.
+#[cfg(not(feature = "sd-only"))]
+async fn send_file(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+    name: &str,
+    content_type: &str,
+    attachment: bool,
+) -> Result<(), &'static str> {
+    if let Some(store) = memory_store() {
+        return send_memory_file(socket, request, name, store.file(name)?, content_type, attachment).await;
+    }
+
+    let card = inflight::lock_sd(socket).await;
+    // Only downloads are checked: the index page is fetched on every refresh of `/`, and a
+    // HEAD sends no data that could be wrong
+    if config::DOWNLOAD_CHAIN_CHECK != config::ChainCheck::Off && attachment && !request.is_head() {
+        if let Some(problem) = init_sd_card(&card).ok().and_then(|sd_card| fat::check_file(&sd_card, name)) {
+            SIZE_MISMATCHES.fetch_add(1, Ordering::Relaxed);
+            log_warn!("{} looks damaged: {}", name, problem.as_str());
+            if config::DOWNLOAD_CHAIN_CHECK == config::ChainCheck::Refuse {
+                return Err(DAMAGED_FILE);
+            }
+        }
+    }
+    let mut volume_mgr = open_volume_manager(&card)?;
+    let mut volume = volume_mgr
+        .open_volume(embedded_sdmmc::VolumeIdx(0))
+        .map_err(|_| OPEN_VOLUME_FAILED)?;
+    let mut root_dir = volume.open_root_dir().map_err(|_| "Failed to open root directory")?;
+    let op = ops::Op::start("file", name);
+
+    let mut disposition = heapless::String::<96>::new();
+    if attachment {
+        let _ = core::fmt::Write::write_fmt(
+            &mut disposition,
+            format_args!("Content-Disposition: attachment; filename=\"{}\"\r\n", name),
+        );
+    }
+
+    // A precompressed copy is sent as stored, whatever the file type
+    if request.accepts_gzip() {
+        if let Ok(sidecar_dir) = root_dir.open_dir(GZ_SIDECAR_DIR) {
+            if let Ok(mut sidecar) = sidecar_dir.open_file_in_dir(name, embedded_sdmmc::Mode::ReadOnly) {
+                let length = Some(sidecar.length() as u64);
+                send_ok_headers(socket, content_type, length, true, disposition.as_bytes()).await;
+                if !request.is_head() {
+                    stream_file(&mut sidecar, socket, name, &op).await;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    let mut file = root_dir
+        .open_file_in_dir(name, embedded_sdmmc::Mode::ReadOnly)
+        .map_err(open_error)?;
+
+    // An empty file goes out as an empty body with `Content-Length: 0`; gzip would turn
+    // it into a 20-byte stream of nothing
+    let gzip = request.accepts_gzip() && gzip::is_compressible(content_type) && file.length() > 0;
+    // Take the length from the open file rather than the listing, which may be stale
+    let length = if gzip { None } else { Some(file.length() as u64) };
+    send_ok_headers(socket, content_type, length, gzip, disposition.as_bytes()).await;
+
+    if request.is_head() {
+        return Ok(());
+    }
+
+    if gzip {
+        let mut gz = GzipWriter::new(&mut *socket);
+        if stream_file(&mut file, &mut gz, name, &op).await {
+            let _ = gz.finish().await;
+        }
+    } else {
+        stream_file(&mut file, socket, name, &op).await;
+    }
+
+    Ok(())
+}
+
+/// `send_file` for a file held in the firmware (see `memory_store`).
+#[cfg(not(feature = "sd-only"))]
+async fn send_memory_file(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+    name: &str,
+    data: &[u8],
+    content_type: &str,
+    attachment: bool,
+) -> Result<(), &'static str> {
+    let mut disposition = heapless::String::<96>::new();
+    if attachment {
+        let _ = core::fmt::Write::write_fmt(
+            &mut disposition,
+            format_args!("Content-Disposition: attachment; filename=\"{}\"\r\n", name),
+        );
+    }
+
+    let gzip = request.accepts_gzip() && gzip::is_compressible(content_type) && !data.is_empty();
+    let length = if gzip { None } else { Some(data.len() as u64) };
+    send_ok_headers(socket, content_type, length, gzip, disposition.as_bytes()).await;
+
+    if request.is_head() {
+        return Ok(());
+    }
+
+    if gzip {
+        let mut gz = GzipWriter::new(&mut *socket);
+        if gz.write_all(data).await.is_ok() {
+            let _ = gz.finish().await;
+        }
+    } else {
+        let _ = socket.write_all(data).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "sd-only"))]
+type SdFile<'a> = embedded_sdmmc::File<'a, SdCardDevice, DummyTimesource, 4, 4, 1>;
+
+/// Copies the rest of `file` into `out`, returning `false` if the client went away or
+/// `op` was cancelled.
+///
+/// Two blocks are in flight: each block is handed to `out` with a single `write`, which
+/// only waits for room for some of it, then the next block is read from the card while
+/// the network drains the first. A slow client therefore stalls the SPI reads for the
+/// remainder of one block rather than a whole read-then-send round trip.
+#[cfg(not(feature = "sd-only"))]
+async fn stream_file<W: Write>(file: &mut SdFile<'_>, out: &mut W, name: &str, op: &ops::Op) -> bool {
+    let (mut front, mut back) = ([0u8; 512], [0u8; 512]);
+    let (mut pending, mut spare) = (&mut front, &mut back);
+    let mut len = read_chunk(file, pending, name);
+    while len > 0 {
+        if op.is_cancelled() {
+            log_warn!("Sending {} cancelled", name);
+            return false;
+        }
+        let sent = match out.write(&pending[..len]).await {
+            Ok(sent) => sent,
+            Err(_) => return false,
+        };
+        let next_len = read_chunk(file, spare, name);
+        if out.write_all(&pending[sent..len]).await.is_err() {
+            return false;
+        }
+        op.add_bytes(len);
+
+        core::mem::swap(&mut pending, &mut spare);
+        len = next_len;
+    }
+    true
+}
+
+/// `stream_file` for a file held in the firmware, a block at a time so a cancel is seen.
+#[cfg(not(feature = "sd-only"))]
+async fn stream_memory_file<W: Write>(data: &[u8], out: &mut W, name: &str, op: &ops::Op) -> bool {
+    for block in data.chunks(512) {
+        if op.is_cancelled() {
+            log_warn!("Sending {} cancelled", name);
+            return false;
+        }
+        if out.write_all(block).await.is_err() {
+            return false;
+        }
+        op.add_bytes(block.len());
+    }
+    true
+}
+
+/// Reads the next chunk of `file`, returning 0 at the end or on a read error.
+#[cfg(not(feature = "sd-only"))]
+fn read_chunk(file: &mut SdFile<'_>, chunk: &mut [u8], name: &str) -> usize {
+    if file.is_eof() {
+        return 0;
+    }
+    match file.read(chunk) {
+        Ok(n) => n,
+        Err(_) => {
+            // Headers are already out; all we can do is cut the body short
+            log_warn!("Read error while sending {}", name);
+            0
+        }
+    }
+}
+
+/// Content type for a file, chosen by extension.
+#[cfg(not(feature = "sd-only"))]
+fn mime_for(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext).unwrap_or("");
+    let known: &[(&str, &str)] = &[
+        ("HTM", "text/html; charset=utf-8"),
+        ("HTML", "text/html; charset=utf-8"),
+        ("TXT", "text/plain; charset=utf-8"),
+        ("LOG", "text/plain; charset=utf-8"),
+        ("MD", "text/plain; charset=utf-8"),
+        ("CSV", "text/csv; charset=utf-8"),
+        ("CSS", "text/css"),
+        ("XML", "text/xml"),
+        ("JS", "application/javascript"),
+        ("JSON", "application/json"),
+        ("SVG", "image/svg+xml"),
+        ("PNG", "image/png"),
+        ("JPG", "image/jpeg"),
+        ("JPEG", "image/jpeg"),
+        ("GIF", "image/gif"),
+        ("BMP", "image/bmp"),
+        ("ICO", "image/x-icon"),
+        ("WAV", "audio/wav"),
+        ("MP3", "audio/mpeg"),
+        ("MP4", "video/mp4"),
+        ("PDF", "application/pdf"),
+        ("ZIP", "application/zip"),
+        ("GZ", "application/gzip"),
+        ("TAR", "application/x-tar"),
+    ];
+
+    known
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, mime)| *mime)
+        .unwrap_or("application/octet-stream")
+}
+
+/// Listing icon for a file, picked by its content type.
+#[cfg(not(feature = "sd-only"))]
+fn icon_for(name: &str) -> &'static [u8] {
+    match mime_for(name) {
+        mime if mime.starts_with("image/") => b"\xF0\x9F\x96\xBC\xEF\xB8\x8F", // 🖼️
+        mime if mime.starts_with("audio/") => b"\xF0\x9F\x8E\xB5",             // 🎵
+        mime if mime.starts_with("video/") => b"\xF0\x9F\x8E\xAC",             // 🎬
+        mime if mime.starts_with("text/html") => b"\xF0\x9F\x8C\x90",          // 🌐
+        mime if mime.starts_with("text/csv") => b"\xF0\x9F\x93\x8A",           // 📊
+        "application/javascript" | "application/json" => b"\xF0\x9F\x93\x9C", // 📜
+        "application/zip" | "application/gzip" | "application/x-tar" => b"\xF0\x9F\x97\x9C\xEF\xB8\x8F", // 🗜️
+        "application/pdf" => b"\xF0\x9F\x93\x95", // 📕
+        _ => b"\xF0\x9F\x93\x84",                 // 📄
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn count_download(name: heapless::String<64>) {
+    let mut counts = DOWNLOAD_COUNTS.lock().await;
+    let count = match counts.iter().position(|entry| entry.name.eq_ignore_ascii_case(&name)) {
+        Some(i) => counts.remove(i).count + 1,
+        None => {
+            if counts.is_full() {
+                counts.remove(0);
+            }
+            1
+        }
+    };
+    let _ = counts.push(DownloadCount { name, count });
+}
+
+// Longest side of a generated thumbnail, in pixels
+#[cfg(not(feature = "sd-only"))]
+const THUMB_SIZE: u32 = 64;
+// Larger BMPs get the generic icon rather than tying up the SD bus for seconds
+#[cfg(not(feature = "sd-only"))]
+const MAX_THUMB_SOURCE: u32 = 1024 * 1024;
+
+// Shown for images that can't be scaled on the device
+#[cfg(not(feature = "sd-only"))]
+const GENERIC_IMAGE_ICON: &[u8] = b"<svg xmlns='http://www.w3.org/2000/svg' viewBox='0 0 64 64'>\
+<rect x='6' y='10' width='52' height='44' rx='4' fill='#e3f2fd' stroke='#2196f3' stroke-width='3'/>\
+<circle cx='22' cy='25' r='5' fill='#2196f3'/>\
+<path d='M10 50 L26 34 L36 44 L44 36 L54 50 Z' fill='#2196f3'/></svg>";
+
+/// Sends a downscaled copy of a BMP as a 24-bit BMP, sampling the nearest source pixel
+/// for each thumbnail pixel. Like `send_file`, errors are only returned before any of the
+/// response has been sent.
+#[cfg(not(feature = "sd-only"))]
+async fn send_bmp_thumbnail(
+    socket: &mut TcpSocket<'_>,
+    request: &Request<'_>,
+    name: &str,
+) -> Result<(), &'static str> {
+    let card = inflight::lock_sd(socket).await;
+    let mut volume_mgr = open_volume_manager(&card)?;
+    let mut volume = volume_mgr
+        .open_volume(embedded_sdmmc::VolumeIdx(0))
+        .map_err(|_| OPEN_VOLUME_FAILED)?;
+    let mut root_dir = volume.open_root_dir().map_err(|_| "Failed to open root directory")?;
+    let mut file = root_dir
+        .open_file_in_dir(name, embedded_sdmmc::Mode::ReadOnly)
+        .map_err(open_error)?;
+
+    if file.length() > MAX_THUMB_SOURCE {
+        return Err("Image too large to scale");
+    }
+    let mut header = [0u8; bmp::HEADER_LEN];
+    if file.read(&mut header).ok() != Some(header.len()) {
+        return Err("Failed to read image header");
+    }
+    let info = bmp::parse_header(&header).ok_or("Unsupported BMP format")?;
+    let (width, height) = info.thumb_size(THUMB_SIZE);
+    let stride = bmp::row_stride_24(width);
+
+    let length = (bmp::HEADER_LEN as u32 + stride * height) as u64;
+    send_ok_headers(socket, "image/bmp", Some(length), false, b"Cache-Control: max-age=60\r\n").await;
+    if request.is_head() {
+        return Ok(());
+    }
+    let _ = socket.write_all(&bmp::header(width, height)).await;
+
+    let mut chunk = [0u8; 512];
+    let mut row = [0u8; bmp::row_stride_24(THUMB_SIZE) as usize];
+    // Output is bottom-up, so walk the thumbnail rows from the bottom
+    for y in (0..height).rev() {
+        let row_offset = info.row_offset(y * info.height / height);
+        let (mut chunk_start, mut chunk_len) = (u32::MAX, 0);
+        for x in 0..width {
+            let offset = row_offset + x * info.width / width * info.bytes_per_pixel;
+            if offset < chunk_start || offset + 3 > chunk_start + chunk_len as u32 {
+                chunk_len = match file.seek_from_start(offset).ok().and_then(|_| file.read(&mut chunk).ok()) {
+                    Some(n) if n >= 3 => n,
+                    // Headers are already out; all we can do is cut the body short
+                    _ => {
+                        log_warn!("Read error while scaling {}", name);
+                        return Ok(());
+                    }
+                };
+                chunk_start = offset;
+            }
+            let at = (offset - chunk_start) as usize;
+            // Source and output are both BGR order
+            row[x as usize * 3..x as usize * 3 + 3].copy_from_slice(&chunk[at..at + 3]);
+        }
+        if socket.write_all(&row[..stride as usize]).await.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// `GET /thumb?name=..`: a small preview for the grid view. BMPs are scaled on the fly;
+/// every other image, and BMPs that are too big or unsupported, get a generic icon.
+#[cfg(not(feature = "sd-only"))]
+async fn send_thumb(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let Some(name) = query_param(request.query, "name").and_then(decode_file_name) else {
+        send_plain(socket, b"400 Bad Request", b"Missing or invalid ?name=\n").await;
+        return;
+    };
+
+    if mime_for(&name) == "image/bmp" {
+        match send_bmp_thumbnail(socket, request, &name).await {
+            Ok(()) => return,
+            Err("File not found") => {
+                send_plain(socket, b"404 Not Found", b"File not found\n").await;
+                return;
+            }
+            Err(e) => log_info!("No thumbnail for {}: {}", name.as_str(), e),
+        }
+    }
+
+    send_ok_headers(
+        socket,
+        "image/svg+xml",
+        Some(GENERIC_IMAGE_ICON.len() as u64),
+        false,
+        b"Cache-Control: max-age=86400\r\n",
+    )
+    .await;
+    if !request.is_head() {
+        let _ = socket.write_all(GENERIC_IMAGE_ICON).await;
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn send_download(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let Some(name) = query_param(request.query, "name").and_then(decode_file_name) else {
+        send_plain(socket, b"400 Bad Request", b"Missing or invalid ?name=\n").await;
+        return;
+    };
+
+    match send_file(socket, request, &name, mime_for(&name), true).await {
+        Ok(()) => {
+            log_info!("Sent {}", name.as_str());
+            if !request.is_head() {
+                count_download(name).await;
+            }
+        }
+        Err("File not found") => send_plain(socket, b"404 Not Found", b"File not found\n").await,
+        Err(DAMAGED_FILE) => send_plain(socket, b"422 Unprocessable Content", DAMAGED_FILE.as_bytes()).await,
+        Err(e) => {
+            log_warn!("Download of {} failed: {}", name.as_str(), e);
+            send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+        }
+    }
+}
+
+/// Headers of one file's part in a `multipart/mixed` download.
+#[cfg(not(feature = "sd-only"))]
+fn multipart_part_header(boundary: &str, name: &str, size: u64) -> heapless::String<224> {
+    let mut header = heapless::String::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut header,
+        format_args!(
+            "--{}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\nContent-Length: {}\r\n\r\n",
+            boundary,
+            mime_for(name),
+            name,
+            size
+        ),
+    );
+    header
+}
+
+/// Streams the files directly inside the root-level folder `dir` as `multipart/mixed`,
+/// one part per file. Errors are only returned before any of the response has been sent.
+#[cfg(not(feature = "sd-only"))]
+async fn send_multipart_dir(socket: &mut TcpSocket<'_>, request: &Request<'_>, dir: &str) -> Result<usize, HttpError> {
+    let card_error = |e: &'static str| (&b"503 Service Unavailable"[..], e);
+
+    let card = inflight::lock_sd(socket).await;
+
+    // Size every file first so the whole response's length can go in the headers
+    let mut entries = heapless::Vec::<(heapless::String<12>, u64), MAX_FILES>::new();
+    let mut skipped = 0;
+    let listed = file_store(&card).list(dir, &mut |entry| {
+        if entry.is_dir {
+            return;
+        }
+        let mut name = heapless::String::new();
+        let _ = name.push_str(entry.name);
+        if entries.push((name, entry.size)).is_err() {
+            skipped += 1;
+        }
+    });
+    match listed {
+        Ok(()) => {}
+        Err(store::NO_SUCH_FOLDER) => return Err((b"404 Not Found", store::NO_SUCH_FOLDER)),
+        Err(e) => return Err(card_error(e)),
+    }
+    if skipped > 0 {
+        log_warn!("Leaving {} files out of {}: more than {} in the folder", skipped, dir, MAX_FILES);
+    }
+
+    // Random enough that file contents won't contain it by accident
+    let mut boundary = heapless::String::<32>::new();
+    let _ = core::fmt::Write::write_fmt(&mut boundary, format_args!("lt7689-{:016x}", Instant::now().as_ticks()));
+    let closing_len = boundary.len() + 6; // "--" boundary "--\r\n"
+    let length = entries
+        .iter()
+        .map(|(name, size)| multipart_part_header(&boundary, name, *size).len() as u64 + size + 2)
+        .sum::<u64>()
+        + closing_len as u64;
+
+    let mut content_type = heapless::String::<64>::new();
+    let _ = core::fmt::Write::write_fmt(&mut content_type, format_args!("multipart/mixed; boundary={}", boundary));
+    send_ok_headers(socket, &content_type, Some(length), false, b"").await;
+    if request.is_head() {
+        return Ok(entries.len());
+    }
+
+    // Headers are already out, so from here on a failure can only cut the body short; the
+    // missing closing boundary tells the client
+    let op = ops::Op::start("folder", dir);
+    let complete = match memory_store() {
+        Some(store) => send_memory_parts(socket, store, dir, &entries, &boundary, &op).await,
+        None => send_card_parts(socket, &card, dir, &entries, &boundary, &op).await,
+    };
+    if complete {
+        let mut closing = heapless::String::<40>::new();
+        let _ = core::fmt::Write::write_fmt(&mut closing, format_args!("--{}--\r\n", boundary));
+        let _ = socket.write_all(closing.as_bytes()).await;
+    }
+    Ok(entries.len())
+}
+
+/// Writes a multipart part for each of `entries` in the card folder `dir`, returning
+/// `false` if one couldn't be sent whole.
+#[cfg(not(feature = "sd-only"))]
+async fn send_card_parts(
+    socket: &mut TcpSocket<'_>,
+    card: &SdCardOwner,
+    dir: &str,
+    entries: &[(heapless::String<12>, u64)],
+    boundary: &str,
+    op: &ops::Op,
+) -> bool {
+    let Ok(mut volume_mgr) = open_volume_manager(card) else { return false };
+    let Ok(mut volume) = volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)) else { return false };
+    let Ok(mut root_dir) = volume.open_root_dir() else { return false };
+    let Ok(mut folder) = root_dir.open_dir(dir) else { return false };
+    for (name, size) in entries {
+        let Ok(mut file) = folder.open_file_in_dir(name.as_str(), embedded_sdmmc::Mode::ReadOnly) else {
+            log_warn!("Failed to open {} for multipart download", name.as_str());
+            return false;
+        };
+        let header = multipart_part_header(boundary, name, *size);
+        if socket.write_all(header.as_bytes()).await.is_err() || !stream_file(&mut file, socket, name, op).await {
+            return false;
+        }
+        if file.offset() as u64 != *size || socket.write_all(b"\r\n").await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// `send_card_parts` for the built-in folder `dir`.
+#[cfg(not(feature = "sd-only"))]
+async fn send_memory_parts(
+    socket: &mut TcpSocket<'_>,
+    store: &MemoryStore,
+    dir: &str,
+    entries: &[(heapless::String<12>, u64)],
+    boundary: &str,
+    op: &ops::Op,
+) -> bool {
+    for (name, size) in entries {
+        let mut path = heapless::String::<32>::new();
+        let _ = core::fmt::Write::write_fmt(&mut path, format_args!("{}/{}", dir, name));
+        let Ok(data) = store.file(&path) else { return false };
+        let header = multipart_part_header(boundary, name, *size);
+        if socket.write_all(header.as_bytes()).await.is_err() || !stream_memory_file(data, socket, name, op).await {
+            return false;
+        }
+        if socket.write_all(b"\r\n").await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// `GET /download-dir?dir=NAME`: every file in the root-level folder NAME in one
+/// `multipart/mixed` response, for tools that would rather split parts than unpack a tar.
+/// Subfolders are left out, and an empty folder gives an envelope with no parts.
+#[cfg(not(feature = "sd-only"))]
+async fn send_download_dir(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let Some(dir) = query_param(request.query, "dir").and_then(decode_file_name) else {
+        send_plain(socket, b"400 Bad Request", b"Missing or invalid ?dir=\n").await;
+        return;
+    };
+
+    match send_multipart_dir(socket, request, &dir).await {
+        Ok(count) => log_info!("Sent {} files from {} as multipart", count, dir.as_str()),
+        Err((status, message)) => {
+            log_warn!("Multipart download of {} failed: {}", dir.as_str(), message);
+            send_plain(socket, status, message.as_bytes()).await;
+        }
+    }
+}
+
+/// Streams the named root-directory files as a tar archive. Names that aren't on the card
+/// are skipped, or with `skip_missing` off, fail the request. Errors are only returned
+/// before any of the response has been sent.
+#[cfg(not(feature = "sd-only"))]
+async fn send_tar(socket: &mut TcpSocket<'_>, names: &[heapless::String<64>], skip_missing: bool) -> Result<(), HttpError> {
+    let card_error = |e: &'static str| (&b"503 Service Unavailable"[..], e);
+
+    let card = inflight::lock_sd(socket).await;
+
+    // Size every entry first so the archive's length can go in the headers
+    let mut sizes = [None; config::MAX_SELECTED_FILES];
+    file_store(&card)
+        .list("", &mut |entry| {
+            if let Some(i) = names.iter().position(|name| !entry.is_dir && name.eq_ignore_ascii_case(entry.name)) {
+                sizes[i] = Some(entry.size);
+            }
+        })
+        .map_err(card_error)?;
+    let mut entries = heapless::Vec::<(&str, u64), { config::MAX_SELECTED_FILES }>::new();
+    for (name, size) in names.iter().zip(sizes) {
+        match size {
+            Some(size) => {
+                let _ = entries.push((name.as_str(), size));
+            }
+            None if skip_missing => log_info!("Skipping {} in archive: no such file", name.as_str()),
+            None => return Err((b"404 Not Found", "A selected file isn't on the card")),
+        }
+    }
+    if entries.is_empty() {
+        return Err((b"404 Not Found", "None of the selected files are on the card"));
+    }
+
+    let length = entries.iter().map(|&(_, size)| tar::entry_len(size)).sum::<u64>() + tar::TRAILER_LEN as u64;
+    send_ok_headers(
+        socket,
+        "application/x-tar",
+        Some(length),
+        false,
+        b"Content-Disposition: attachment; filename=\"selected.tar\"\r\n",
+    )
+    .await;
+
+    // Headers are already out, so from here on a failure can only cut the body short,
+    // which tells the client the archive is incomplete
+    let op = ops::Op::start("archive", "selected.tar");
+    let complete = match memory_store() {
+        Some(store) => send_memory_tar_entries(socket, store, &entries, &op).await,
+        None => send_card_tar_entries(socket, &card, &entries, &op).await,
+    };
+    if complete {
+        let _ = socket.write_all(&[0u8; tar::TRAILER_LEN]).await;
+    }
+    Ok(())
+}
+
+/// Writes a tar entry for each of the card's root files in `entries`, returning `false`
+/// if one couldn't be sent whole.
+#[cfg(not(feature = "sd-only"))]
+async fn send_card_tar_entries(
+    socket: &mut TcpSocket<'_>,
+    card: &SdCardOwner,
+    entries: &[(&str, u64)],
+    op: &ops::Op,
+) -> bool {
+    let Ok(mut volume_mgr) = open_volume_manager(card) else { return false };
+    let Ok(mut volume) = volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)) else { return false };
+    let Ok(mut root_dir) = volume.open_root_dir() else { return false };
+    for &(name, size) in entries {
+        let Ok(mut file) = root_dir.open_file_in_dir(name, embedded_sdmmc::Mode::ReadOnly) else {
+            log_warn!("Failed to open {} for archive", name);
+            return false;
+        };
+        if socket.write_all(&tar::header(name, size)).await.is_err() || !stream_file(&mut file, socket, name, op).await {
+            return false;
+        }
+        if file.offset() as u64 != size {
+            // A short file would shift every later entry out of place, so stop here
+            return false;
+        }
+        if socket.write_all(&[0u8; tar::BLOCK_LEN][..tar::padding(size)]).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// `send_card_tar_entries` for built-in files.
+#[cfg(not(feature = "sd-only"))]
+async fn send_memory_tar_entries(socket: &mut TcpSocket<'_>, store: &MemoryStore, entries: &[(&str, u64)], op: &ops::Op) -> bool {
+    for &(name, size) in entries {
+        let Ok(data) = store.file(name) else { return false };
+        if socket.write_all(&tar::header(name, size)).await.is_err() || !stream_memory_file(data, socket, name, op).await {
+            return false;
+        }
+        if socket.write_all(&[0u8; tar::BLOCK_LEN][..tar::padding(size)]).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// `POST /download-selected`: the files named by a form body of `name=` fields, as one
+/// tar archive. `?missing=fail` refuses the request if any of them is missing instead of
+/// leaving it out.
+#[cfg(not(feature = "sd-only"))]
+async fn send_download_selected(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let Some(length) = request.content_length() else {
+        send_plain(socket, b"411 Length Required", b"Content-Length is required\n").await;
+        return;
+    };
+    if length > config::MAX_SELECTION_BODY {
+        send_plain(socket, b"413 Content Too Large", b"Too many files selected\n").await;
+        return;
+    }
+    if request.body_prefix().is_none() {
+        send_plain(socket, b"431 Request Header Fields Too Large", b"Headers too large\n").await;
+        return;
+    }
+
+    let mut body = [0u8; config::MAX_SELECTION_BODY];
+    if let Err(e) = read_body(socket, request, length, &mut &mut body[..length]).await {
+        log_warn!("Selection body incomplete: {}", e);
+        send_plain(socket, b"400 Bad Request", b"Body shorter than Content-Length\n").await;
+        return;
+    }
+    let Ok(form) = core::str::from_utf8(&body[..length]) else {
+        send_plain(socket, b"400 Bad Request", b"Body is not a form\n").await;
+        return;
+    };
+
+    let mut names = heapless::Vec::<heapless::String<64>, { config::MAX_SELECTED_FILES }>::new();
+    for pair in form.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if key != "name" {
+            continue;
+        }
+        let Some(name) = decode_file_name(value) else {
+            send_plain(socket, b"400 Bad Request", b"Invalid file name in selection\n").await;
+            return;
+        };
+        if names.iter().any(|n| n.eq_ignore_ascii_case(&name)) {
+            continue;
+        }
+        if names.push(name).is_err() {
+            send_plain(socket, b"413 Content Too Large", b"Too many files selected\n").await;
+            return;
+        }
+    }
+    if names.is_empty() {
+        send_plain(socket, b"400 Bad Request", b"No files selected\n").await;
+        return;
+    }
+
+    let skip_missing = query_param(request.query, "missing") != Some("fail");
+    match send_tar(socket, &names, skip_missing).await {
+        Ok(()) => log_info!("Sent archive of {} files", names.len()),
+        Err((status, message)) => {
+            log_warn!("Archive failed: {}", message);
+            send_plain(socket, status, message.as_bytes()).await;
+        }
+    }
+}
+
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_handle_check(socket: &mut TcpSocket<'_>) {
+    let name = SD_FILES.lock().await.iter().find(|f| !f.is_dir).map(|f| f.name.clone());
+    let Some(name) = name else {
+        send_plain(socket, b"409 Conflict", b"Needs at least one file on the card\n").await;
+        return;
+    };
+
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        check_handle_release(&card, &name)
+    };
+
+    let mut body = heapless::String::<96>::new();
+    match result {
+        Ok(rounds) => {
+            let _ = core::fmt::Write::write_fmt(&mut body, format_args!("OK: opened and closed {} {} times\n", name, rounds));
+            send_plain(socket, b"200 OK", body.as_bytes()).await;
+        }
+        Err(e) => {
+            let _ = core::fmt::Write::write_fmt(&mut body, format_args!("FAILED: {}\n", e));
+            send_plain(socket, b"500 Internal Server Error", body.as_bytes()).await;
+        }
+    }
+}
+
+/// Reads the partition table and the boot sector the volume manager would mount.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+fn read_partitions(owner: &SdCardOwner) -> Result<(Option<[fat::Partition; 4]>, Option<fat::BootSector>), &'static str> {
+    let sd_card = init_sd_card(owner)?;
+    Ok((fat::read_mbr(&sd_card), fat::first_volume(&sd_card).map(|(_, boot)| boot)))
+}
+
+/// `GET /debug/mbr`: the MBR partition entries as JSON, to tell whether a card is GPT,
+/// exFAT, or has the FAT partition `VolumeIdx(0)` expects in its first slot.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_mbr(socket: &mut TcpSocket<'_>) {
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        read_partitions(&card)
+    };
+    let (partitions, volume) = match result {
+        Ok(found) => found,
+        Err(e) => {
+            send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+            return;
+        }
+    };
+
+    let mut body = heapless::String::<768>::new();
+    match partitions {
+        Some(partitions) => {
+            let _ = body.push_str("{\"signature\":true,\"partitions\":[");
+            for (i, partition) in partitions.iter().enumerate() {
+                let _ = core::fmt::Write::write_fmt(
+                    &mut body,
+                    format_args!(
+                        "{}{{\"type\":\"0x{:02x}\",\"name\":\"{}\",\"start_lba\":{},\"sectors\":{}}}",
+                        if i > 0 { "," } else { "" },
+                        partition.kind,
+                        fat::partition_type_name(partition.kind),
+                        partition.start_lba,
+                        partition.num_blocks
+                    ),
+                );
+            }
+            let _ = body.push_str("]");
+        }
+        None => {
+            let _ = body.push_str("{\"signature\":false,\"partitions\":[]");
+        }
+    }
+    // What the volume manager will find in the first partition
+    match volume {
+        Some(boot) => {
+            let _ = core::fmt::Write::write_fmt(
+                &mut body,
+                format_args!(
+                    ",\"volume0\":{{\"fat\":\"{}\",\"cluster_bytes\":{}}}}}\n",
+                    boot.fat_type().as_str(),
+                    boot.cluster_bytes()
+                ),
+            );
+        }
+        None => {
+            let _ = body.push_str(",\"volume0\":null}\n");
+        }
+    }
+
+    send_ok_headers(socket, "application/json", Some(body.len() as u64), false, b"").await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// Percent-decodes a form field value, with `+` as a space.
+#[cfg(not(feature = "sd-only"))]
+fn decode_form_value<const N: usize>(value: &str) -> Option<heapless::String<N>> {
+    let mut decoded = heapless::Vec::<u8, N>::new();
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = core::str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+                i += 2;
+                u8::from_str_radix(hex, 16).ok()?
+            }
+            b'+' => b' ',
+            byte => byte,
+        };
+        decoded.push(byte).ok()?;
+        i += 1;
+    }
+    heapless::String::from_utf8(decoded).ok()
+}
+
+/// First-run form shown at `/` until the card has a `WIFI.CFG`.
+#[cfg(not(feature = "sd-only"))]
+async fn send_setup_page(socket: &mut TcpSocket<'_>) {
+    let ssid = AP_SSID.lock().await.clone();
+    ResponseBuilder::from_block(OK_HTML)
+        .header("Cache-Control", b"no-store")
+        .send(socket)
+        .await;
+
+    let _ = socket.write_all(b"<!DOCTYPE html>\n<html>\n<head>\n<title>Pico 2W SD Card Browser setup</title>\n").await;
+    let _ = socket.write_all(b"<meta name='viewport' content='width=device-width, initial-scale=1'>\n").await;
+    let _ = socket.write_all(b"<link rel='stylesheet' href='/style.css'>\n</head>\n<body>\n<div class='container'>\n").await;
+    let _ = socket.write_all(b"<h1>Welcome</h1>\n").await;
+    let _ = socket.write_all(b"<p>This card has no WiFi settings yet. Choose the access point's name and password; ").await;
+    let _ = socket.write_all(b"they're saved to WIFI.CFG on the card and the board restarts with them.</p>\n").await;
+    let _ = socket.write_all(b"<form method='post' action='/setup'>\n").await;
+    let _ = socket.write_all(b"<p><label>Network name<br><input name='ssid' maxlength='32' required value='").await;
+    write_html_escaped(socket, &ssid).await;
+    let _ = socket.write_all(b"'></label></p>\n").await;
+    let _ = socket.write_all(b"<p><label>Password (8-63 characters)<br><input name='password' type='password' minlength='8' maxlength='63' required></label></p>\n").await;
+    let _ = socket.write_all(b"<p><label>Channel<br><select name='channel'>").await;
+    for channel in wifi_config::CHANNELS {
+        let mut option = heapless::String::<48>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut option,
+            format_args!(
+                "<option{}>{}</option>",
+                if channel == AP_CHANNEL { " selected" } else { "" },
+                channel
+            ),
+        );
+        let _ = socket.write_all(option.as_bytes()).await;
+    }
+    let _ = socket.write_all(b"</select></label></p>\n").await;
+    let _ = socket.write_all(b"<p><button type='submit'>Save and restart</button></p>\n</form>\n").await;
+    let _ = socket.write_all(b"<p><a href='/?setup=skip'>Skip to the files</a></p>\n").await;
+    let _ = socket.write_all(b"</div>\n</body>\n</html>\r\n").await;
+}
+
+/// `POST /setup`: saves the first-run form to `WIFI.CFG` and restarts the board with it.
+/// Only allowed while there's no config file, so it can't be used to take over a
+/// configured board.
+#[cfg(not(feature = "sd-only"))]
+async fn send_setup(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if !NEEDS_SETUP.load(Ordering::Relaxed) {
+        send_plain(socket, b"409 Conflict", b"Already set up (edit WIFI.CFG on the card to change it)\n").await;
+        return;
+    }
+    if let Some(reason) = write_block_reason() {
+        send_plain(socket, b"403 Forbidden", reason).await;
+        return;
+    }
+    let Some(length) = request.content_length() else {
+        send_plain(socket, b"411 Length Required", b"Content-Length is required\n").await;
+        return;
+    };
+    if length > wifi_config::MAX_LEN {
+        send_plain(socket, b"413 Content Too Large", b"Body too large\n").await;
+        return;
+    }
+    if request.body_prefix().is_none() {
+        send_plain(socket, b"431 Request Header Fields Too Large", b"Headers too large\n").await;
+        return;
+    }
+
+    let mut body = [0u8; wifi_config::MAX_LEN];
+    if let Err(e) = read_body(socket, request, length, &mut &mut body[..length]).await {
+        log_warn!("Setup body incomplete: {}", e);
+        send_plain(socket, b"400 Bad Request", b"Body shorter than Content-Length\n").await;
+        return;
+    }
+    let form = core::str::from_utf8(&body[..length]).unwrap_or("");
+
+    let Some(ssid) = query_param(form, "ssid").and_then(decode_form_value::<32>).filter(|ssid| !ssid.is_empty()) else {
+        send_plain(socket, b"400 Bad Request", b"Network name must be 1-32 bytes\n").await;
+        return;
+    };
+    let Some(password) = query_param(form, "password")
+        .and_then(decode_form_value::<64>)
+        .filter(|password| is_valid_wpa2_password(password))
+    else {
+        send_plain(socket, b"400 Bad Request", b"Password must be 8-63 printable ASCII characters\n").await;
+        return;
+    };
+    let Some(channel) = query_param(form, "channel")
+        .and_then(|channel| channel.parse::<u8>().ok())
+        .filter(|channel| wifi_config::CHANNELS.contains(channel))
+    else {
+        send_plain(socket, b"400 Bad Request", b"Channel must be 1-11\n").await;
+        return;
+    };
+    let settings = WifiConfig {
+        ssid,
+        password,
+        channel,
+        ..runtime_settings(default_wifi_config())
+    };
+
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        file_store(&card).write(wifi_config::FILE_NAME, settings.render().as_bytes())
+    };
+    if let Err(e) = result {
+        log_warn!("Saving {} failed: {}", wifi_config::FILE_NAME, e);
+        send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+        return;
+    }
+
+    info!("Setup saved for {}, restarting", settings.ssid.as_str());
+    let mut reply = heapless::String::<128>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut reply,
+        format_args!("Saved. Restarting - reconnect to the new network \"{}\".\n", settings.ssid),
+    );
+    send_plain(socket, b"200 OK", reply.as_bytes()).await;
+    // Make sure the reply is out before the radio goes down with the rest of the chip
+    let _ = socket.flush().await;
+    Timer::after(Duration::from_millis(500)).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+// Set when a format stopped partway, so the scanner can say so instead of just failing
+// to open the volume
+#[cfg(feature = "debug-endpoints")]
+static FORMAT_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "debug-endpoints")]
+const FORMAT_INTERRUPTED_STATUS: &str = "Format interrupted - card is blank, format it again";
+
+/// `GET /debug/format`: the form for `POST /debug/format`. The confirmation has to be
+/// typed, then confirmed again in a dialog.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_format_page(socket: &mut TcpSocket<'_>) {
+    ResponseBuilder::from_block(OK_HTML)
+        .header("Cache-Control", b"no-store")
+        .send(socket)
+        .await;
+    let _ = socket.write_all(b"<!DOCTYPE html>\n<html>\n<head>\n<title>Format SD card</title>\n").await;
+    let _ = socket.write_all(b"<meta name='viewport' content='width=device-width, initial-scale=1'>\n").await;
+    let _ = socket.write_all(b"<link rel='stylesheet' href='/style.css'>\n</head>\n<body>\n<div class='container'>\n").await;
+    let _ = socket.write_all(b"<h1>Format SD card</h1>\n").await;
+    let _ = socket.write_all(b"<p class='hw-info'>This erases <strong>everything</strong> on the card and creates an empty FAT32 volume. ").await;
+    let _ = socket.write_all(b"It can take several minutes on a large card; don't remove the card or power the board off meanwhile.</p>\n").await;
+    let _ = socket.write_all(
+        b"<form method='post' onsubmit=\"this.action='/debug/format?confirm='+encodeURIComponent(this.answer.value);\
+return window.confirm('Erase everything on the SD card?')\">\n",
+    )
+    .await;
+    let _ = socket.write_all(b"<p><label>Type YES to confirm<br><input name='answer' required pattern='YES' autocomplete='off'></label></p>\n").await;
+    let _ = socket.write_all(b"<p><button type='submit'>Format</button> <a href='/'>Cancel</a></p>\n</form>\n").await;
+    let _ = socket.write_all(b"</div>\n</body>\n</html>\n").await;
+}
+
+/// `POST /debug/format?confirm=YES`: erases the card and writes an empty FAT32 volume,
+/// reporting progress as plain text. Refused while writes are blocked. Holds the SD bus
+/// throughout, yielding between writes so the radio and network keep running.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_format(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if let Some(reason) = write_block_reason() {
+        send_plain(socket, b"403 Forbidden", reason).await;
+        return;
+    }
+    if query_param(request.query, "confirm") != Some("YES") {
+        send_plain(socket, b"400 Bad Request", b"Add ?confirm=YES to erase the card\n").await;
+        return;
+    }
+
+    let card = inflight::lock_sd(socket).await;
+    let layout = init_sd_card(&card).and_then(|sd_card| {
+        let blocks = sd_card.num_bytes().map_err(|_| "Failed to read card size")? / embedded_sdmmc::Block::LEN as u64;
+        let layout = fat::Fat32Layout::new(blocks.min(u32::MAX as u64) as u32, Instant::now().as_ticks() as u32)?;
+        Ok((sd_card, layout))
+    });
+    let (sd_card, layout) = match layout {
+        Ok(ready) => ready,
+        Err(e) => {
+            send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+            return;
+        }
+    };
+
+    // Loud on purpose, and regardless of the runtime log level
+    error!("FORMATTING SD CARD as FAT32 at a client's request; everything on it will be erased");
+    *SD_STATUS.lock().await = "Formatting...";
+    FORMAT_INTERRUPTED.store(true, Ordering::Relaxed);
+
+    ResponseBuilder::from_block(OK_TEXT).send(socket).await;
+    let mut line = heapless::String::<96>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "Formatting {} as FAT32 with {} byte clusters\n",
+            format_size(layout.boot.total_sectors as u64 * embedded_sdmmc::Block::LEN as u64),
+            layout.boot.cluster_bytes()
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    let mut formatter = fat::Formatter::new(layout);
+    let mut reported = 0;
+    let result = loop {
+        match formatter.zero_next(&sd_card) {
+            Ok(true) => {}
+            Ok(false) => break formatter.finish(&sd_card),
+            Err(e) => break Err(e),
+        }
+        let (done, total) = formatter.progress();
+        let percent = done as u64 * 100 / total as u64;
+        if percent >= reported + 10 {
+            reported = percent;
+            line.clear();
+            let _ = core::fmt::Write::write_fmt(&mut line, format_args!("{}%\n", percent));
+            // Keep going if the client leaves; stopping now would leave a blank card
+            let _ = socket.write_all(line.as_bytes()).await;
+        }
+        yield_now().await;
+    };
+
+    line.clear();
+    match result {
+        Ok(()) => {
+            FORMAT_INTERRUPTED.store(false, Ordering::Relaxed);
+            *SD_STATUS.lock().await = "Formatted, rescanning...";
+            error!("SD card formatted as FAT32");
+            let _ = line.push_str("Done. The card is empty.\n");
+        }
+        Err(e) => {
+            *SD_STATUS.lock().await = FORMAT_INTERRUPTED_STATUS;
+            error!("SD card format failed: {}", e);
+            let _ = core::fmt::Write::write_fmt(&mut line, format_args!("FAILED: {}. The card is left blank; format it again.\n", e));
+        }
+    }
+    let _ = socket.write_all(line.as_bytes()).await;
+    drop(card);
+    RESCAN.signal(());
+}
+
+/// `GET /debug/log`: the recent `log_info!` / `log_warn!` lines kept by `log_ring`,
+/// oldest first.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_debug_log(socket: &mut TcpSocket<'_>) {
+    let mut buf = [0u8; log_ring::CAPACITY];
+    let len = log_ring::snapshot(&mut buf);
+    ResponseBuilder::from_block(OK_TEXT)
+        .header("Cache-Control", b"no-store")
+        .content_length(len as u64)
+        .send(socket)
+        .await;
+    let _ = socket.write_all(&buf[..len]).await;
+}
+
+/// `GET /debug/fsck`: a read-only check of the root directory's cluster chains, to help
+/// decide whether a misbehaving card needs reformatting.
+#[cfg(all(feature = "debug-endpoints", not(feature = "sd-only")))]
+async fn send_fsck(socket: &mut TcpSocket<'_>) {
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        init_sd_card(&card).map(|sd_card| fat::check_root_dir(&sd_card))
+    };
+    let report = match result {
+        Ok(Some(report)) => report,
+        Ok(None) => {
+            send_plain(
+                socket,
+                b"422 Unprocessable Content",
+                b"Can't check this volume (unreadable FAT, FAT12, or sectors other than 512 bytes)\n",
+            )
+            .await;
+            return;
+        }
+        Err(e) => {
+            send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+            return;
+        }
+    };
+
+    ResponseBuilder::from_block(OK_TEXT).send(socket).await;
+
+    let mut line = heapless::String::<128>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "{} root directory: {} files, {} folders, {} bad entries\n",
+            report.fat_type.as_str(),
+            report.files,
+            report.dirs,
+            report.bad_entries
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    for (name, problem) in report.problems.iter() {
+        line.clear();
+        let _ = core::fmt::Write::write_fmt(
+            &mut line,
+            format_args!("  {}: {}\n", fat::short_name(name), problem.as_str()),
+        );
+        let _ = socket.write_all(line.as_bytes()).await;
+    }
+    if report.bad_entries as usize > report.problems.len() {
+        let _ = socket.write_all(b"  ...\n").await;
+    }
+    let verdict: &[u8] = if report.bad_entries == 0 {
+        b"OK: no problems found\n"
+    } else {
+        b"Problems found: copy off what you need and reformat the card\n"
+    };
+    let _ = socket.write_all(verdict).await;
+    log_info!("fsck: {} bad entries", report.bad_entries);
+}
+
+/// Sends a complete plain-text response with the given status line (e.g. `b"404 Not Found"`).
+#[cfg(not(feature = "sd-only"))]
+async fn send_plain(socket: &mut TcpSocket<'_>, status: &[u8], body: &[u8]) {
+    ResponseBuilder::new(status)
+        .content_type("text/plain; charset=utf-8")
+        .content_length(body.len() as u64)
+        .send(socket)
+        .await;
+    let _ = socket.write_all(body).await;
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn send_redirect(socket: &mut TcpSocket<'_>, status: &[u8], location: &[u8]) {
+    ResponseBuilder::new(status)
+        .header("Location", location)
+        .content_length(0)
+        .send(socket)
+        .await;
+}
+
+// Largest part of a file shown by /view at once
+#[cfg(not(feature = "sd-only"))]
+const PREVIEW_LIMIT: usize = 16 * 1024;
+
+/// How a preview's bytes are presented to the browser.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy, PartialEq)]
+enum PreviewEncoding {
+    Utf8,
+    Latin1,
+    Binary,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl PreviewEncoding {
+    fn detect(bytes: &[u8]) -> Self {
+        match core::str::from_utf8(bytes) {
+            Ok(_) => return PreviewEncoding::Utf8,
+            // A multi-byte character cut off by the preview limit is still UTF-8
+            Err(e) if e.error_len().is_none() && bytes.len() - e.valid_up_to() < 4 => {
+                return PreviewEncoding::Utf8;
+            }
+            Err(_) => {}
+        }
+
+        let control = bytes
+            .iter()
+            .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+            .count();
+        if bytes.contains(&0) || control * 10 > bytes.len() {
+            PreviewEncoding::Binary
+        } else {
+            PreviewEncoding::Latin1
+        }
+    }
+}
+
+/// Writes a `/view` path for another window of the same file.
+#[cfg(not(feature = "sd-only"))]
+fn write_view_link(out: &mut heapless::String<384>, name: &str, offset: u64, len: usize) {
+    let _ = core::fmt::Write::write_fmt(
+        out,
+        format_args!("/view?name={}&offset={}&len={}", url_encode(name), offset, len),
+    );
+}
+
+/// `GET /view?name=`: the start of a file as text, or a hex dump for binary files.
+/// `&offset=N&len=M` shows M bytes (at most `PREVIEW_LIMIT`) from byte N instead, and
+/// `&tail=1` the end of the file, each with the range and links to the neighbouring
+/// windows around it.
+#[cfg(not(feature = "sd-only"))]
+async fn send_view(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let Some(name) = query_param(request.query, "name").and_then(decode_file_name) else {
+        send_plain(socket, b"400 Bad Request", b"Missing or invalid ?name=\n").await;
+        return;
+    };
+    let offset = query_param(request.query, "offset").and_then(|offset| offset.parse::<u64>().ok());
+    let tail = query_param(request.query, "tail") == Some("1");
+    let window_len = query_param(request.query, "len")
+        .and_then(|len| len.parse::<usize>().ok())
+        .map_or(PREVIEW_LIMIT, |len| len.clamp(1, PREVIEW_LIMIT));
+    let windowed = offset.is_some() || tail || window_len < PREVIEW_LIMIT;
+    let window = if tail { FileWindow::Tail } else { FileWindow::From(offset.unwrap_or(0)) };
+
+    let mut buf = [0u8; PREVIEW_LIMIT];
+    let head = {
+        let card = inflight::lock_sd(socket).await;
+        file_store(&card).read(&name, window, &mut buf[..window_len])
+    };
+    let (len, start, file_len) = match head {
+        Ok(result) => result,
+        Err("File not found") => {
+            send_plain(socket, b"404 Not Found", b"File not found\n").await;
+            return;
+        }
+        Err(e) => {
+            log_warn!("Preview of {} failed: {}", name.as_str(), e);
+            send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+            return;
+        }
+    };
+    let mut bytes = &buf[..len];
+    // A window that starts mid-way through a UTF-8 character shouldn't look like Latin-1
+    let partial = if start > 0 {
+        bytes.iter().take(3).take_while(|&&b| b & 0xc0 == 0x80).count()
+    } else {
+        0
+    };
+    let encoding = PreviewEncoding::detect(&bytes[partial..]);
+    if encoding == PreviewEncoding::Utf8 {
+        bytes = &bytes[partial..];
+    }
+    let end = start + len as u64;
+    log_info!("Previewing {} (bytes {}-{} of {})", name.as_str(), start, end, file_len);
+
+    let response = match encoding {
+        PreviewEncoding::Utf8 | PreviewEncoding::Binary => ResponseBuilder::from_block(OK_TEXT),
+        PreviewEncoding::Latin1 => ResponseBuilder::new(b"200 OK").content_type("text/plain; charset=iso-8859-1"),
+    };
+    response.send(socket).await;
+
+    if windowed {
+        let mut line = heapless::String::<384>::new();
+        let _ = core::fmt::Write::write_fmt(&mut line, format_args!("[bytes {}-{} of {}]", start, end, file_len));
+        if start > 0 {
+            let _ = line.push_str("  prev: ");
+            write_view_link(&mut line, &name, start.saturating_sub(window_len as u64), window_len);
+        }
+        let _ = line.push_str("\n\n");
+        let _ = socket.write_all(line.as_bytes()).await;
+    }
+
+    if file_len == 0 {
+        // Otherwise an empty file is indistinguishable from a failed read
+        let _ = socket.write_all(b"(empty file)\n").await;
+    } else if encoding == PreviewEncoding::Binary {
+        // Classic hex dump: offset, 16 hex bytes, printable ASCII
+        for (row, chunk) in bytes.chunks(16).enumerate() {
+            let mut line = heapless::String::<80>::new();
+            let _ = core::fmt::Write::write_fmt(&mut line, format_args!("{:08x} ", start as usize + row * 16));
+            for col in 0..16 {
+                let _ = match chunk.get(col) {
+                    Some(b) => core::fmt::Write::write_fmt(&mut line, format_args!(" {:02x}", b)),
+                    None => line.push_str("   ").map_err(|_| core::fmt::Error),
+                };
+            }
+            let _ = line.push_str("  |");
+            for &b in chunk {
+                let _ = line.push(if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' });
+            }
+            let _ = line.push_str("|\n");
+            let _ = socket.write_all(line.as_bytes()).await;
+        }
+    } else {
+        let _ = socket.write_all(bytes).await;
+    }
+
+    if end < file_len {
+        let mut line = heapless::String::<384>::new();
+        let _ = line.push_str("\n\n[preview truncated]  next: ");
+        write_view_link(&mut line, &name, end, window_len);
+        let _ = line.push_str("\n");
+        let _ = socket.write_all(line.as_bytes()).await;
+    }
+}
+
+/// Copies a root-directory file, returning the number of bytes written.
+#[cfg(not(feature = "sd-only"))]
+fn copy_file(owner: &SdCardOwner, from: &str, to: &str, force: bool) -> Result<u64, HttpError> {
+    let card_error = |e: &'static str| (&b"503 Service Unavailable"[..], e);
+
+    let sd_card = open_sd_card(owner).map_err(card_error)?;
+    let free = fat::free_space(&sd_card);
+    let mut volume_mgr: SdVolumeManager = VolumeManager::new(sd_card, DummyTimesource);
+    let mut volume = volume_mgr
+        .open_volume(embedded_sdmmc::VolumeIdx(0))
+        .map_err(|_| card_error(OPEN_VOLUME_FAILED))?;
+    let mut root_dir = volume
+        .open_root_dir()
+        .map_err(|_| card_error("Failed to open root directory"))?;
+
+    let mut source = root_dir
+        .open_file_in_dir(from, embedded_sdmmc::Mode::ReadOnly)
+        .map_err(|e| match open_error(e) {
+            SD_BUSY => card_error(SD_BUSY),
+            _ => (&b"404 Not Found"[..], "Source file not found"),
+        })?;
+    let length = source.length();
+    if free.is_some_and(|free| !free.fits(length as u64)) {
+        return Err((b"507 Insufficient Storage", "Not enough free space for the copy"));
+    }
+
+    let mode = if force {
+        embedded_sdmmc::Mode::ReadWriteCreateOrTruncate
+    } else {
+        embedded_sdmmc::Mode::ReadWriteCreate
+    };
+    let mut dest = match root_dir.open_file_in_dir(to, mode) {
+        Ok(file) => file,
+        Err(embedded_sdmmc::Error::FileAlreadyExists) => {
+            return Err((b"409 Conflict", "Destination exists (add &force=1 to overwrite)"));
+        }
+        Err(e) if open_error(e) == SD_BUSY => return Err(card_error(SD_BUSY)),
+        Err(_) => return Err(card_error("Failed to create destination file")),
+    };
+
+    let mut chunk = [0u8; 512];
+    let mut copied = 0u64;
+    let result = loop {
+        let n = match source.read(&mut chunk) {
+            Ok(0) => break Ok(copied),
+            Ok(n) => n,
+            Err(_) => break Err(card_error("Failed to read source file")),
+        };
+        if dest.write(&chunk[..n]).is_err() {
+            break Err((&b"507 Insufficient Storage"[..], "Write failed (card full?)"));
+        }
+        copied += n as u64;
+    };
+
+    // Close both handles before touching the directory again
+    let flushed = dest.close().is_ok();
+    let _ = source.close();
+
+    match (result, flushed) {
+        (Ok(copied), true) => Ok(copied),
+        (result, _) => {
+            // Don't leave a partial copy behind
+            let _ = root_dir.delete_file_in_dir(to);
+            Err(result.err().unwrap_or(card_error("Failed to flush destination file")))
+        }
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn send_copy(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if let Some(reason) = write_block_reason() {
+        send_plain(socket, b"403 Forbidden", reason).await;
+        return;
+    }
+    let query = request.query;
+    let from = query_param(query, "from").and_then(decode_file_name);
+    let to = query_param(query, "to").and_then(decode_file_name);
+    let (Some(from), Some(to)) = (from, to) else {
+        send_plain(socket, b"400 Bad Request", b"Missing or invalid ?from= / &to=\n").await;
+        return;
+    };
+    if from.eq_ignore_ascii_case(&to) {
+        send_plain(socket, b"400 Bad Request", b"Source and destination are the same file\n").await;
+        return;
+    }
+    if refuse_upload_type(socket, &to).await {
+        return;
+    }
+    let force = query_param(query, "force") == Some("1");
+
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        file_store(&card).copy(&from, &to, force)
+    };
+
+    match result {
+        Ok(copied) => {
+            log_info!("Copied {} -> {} ({} bytes)", from.as_str(), to.as_str(), copied);
+            RESCAN.signal(());
+            send_redirect(socket, b"303 See Other", b"/").await;
+        }
+        Err((status, message)) => {
+            log_warn!("Copy {} -> {} failed: {}", from.as_str(), to.as_str(), message);
+            send_plain(socket, status, message.as_bytes()).await;
+        }
+    }
+}
+
+/// Appends `data` to a root-directory file, creating it if needed. Returns the new length.
+#[cfg(not(feature = "sd-only"))]
+fn append_file(owner: &SdCardOwner, name: &str, data: &[u8]) -> Result<u64, HttpError> {
+    let card_error = |e: &'static str| (&b"503 Service Unavailable"[..], e);
+
+    let sd_card = open_sd_card(owner).map_err(card_error)?;
+    if sd_card.num_bytes().is_err() {
+        return Err(card_error("No SD card detected"));
+    }
+    if fat::free_space(&sd_card).is_some_and(|free| !free.fits(data.len() as u64)) {
+        return Err((b"507 Insufficient Storage", "Not enough free space to append"));
+    }
+    let mut volume_mgr: SdVolumeManager = VolumeManager::new(sd_card, DummyTimesource);
+    let mut volume = volume_mgr
+        .open_volume(embedded_sdmmc::VolumeIdx(0))
+        .map_err(|_| card_error(OPEN_VOLUME_FAILED))?;
+    let mut root_dir = volume
+        .open_root_dir()
+        .map_err(|_| card_error("Failed to open root directory"))?;
+
+    let mut file = root_dir
+        .open_file_in_dir(name, embedded_sdmmc::Mode::ReadWriteCreateOrAppend)
+        .map_err(|e| card_error(open_error(e)))?;
+    let written = file.write(data);
+    let length = file.length() as u64;
+    let flushed = file.close().is_ok();
+
+    match (written, flushed) {
+        (Ok(()), true) => Ok(length),
+        (Err(_), _) => Err((b"507 Insufficient Storage", "Write failed (card full?)")),
+        (Ok(()), false) => Err(card_error("Failed to flush file")),
+    }
+}
+
+/// `POST /append?name=DATA.LOG`: appends the request body to a file, for sensors that
+/// log readings to the card.
+#[cfg(not(feature = "sd-only"))]
+async fn send_append(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if let Some(reason) = write_block_reason() {
+        send_plain(socket, b"403 Forbidden", reason).await;
+        return;
+    }
+    let Some(name) = query_param(request.query, "name").and_then(decode_file_name) else {
+        send_plain(socket, b"400 Bad Request", b"Missing or invalid ?name=\n").await;
+        return;
+    };
+    if refuse_upload_type(socket, &name).await {
+        return;
+    }
+    let Some(length) = request.content_length() else {
+        send_plain(socket, b"411 Length Required", b"Content-Length is required\n").await;
+        return;
+    };
+    if length > config::MAX_APPEND_SIZE {
+        send_plain(socket, b"413 Content Too Large", b"Body too large\n").await;
+        return;
+    }
+    if request.body_prefix().is_none() {
+        send_plain(socket, b"431 Request Header Fields Too Large", b"Headers too large\n").await;
+        return;
+    }
+
+    // Collect the whole body before touching the card so a slow client can't hold the bus
+    let mut body = [0u8; config::MAX_APPEND_SIZE];
+    if let Err(e) = read_body(socket, request, length, &mut &mut body[..length]).await {
+        log_warn!("Append body incomplete: {}", e);
+        send_plain(socket, b"400 Bad Request", b"Body shorter than Content-Length\n").await;
+        return;
+    }
+
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        file_store(&card).append(&name, &body[..length])
+    };
+
+    match result {
+        Ok(size) => {
+            log_info!("Appended {} bytes to {} (now {} bytes)", length, name.as_str(), size);
+            RESCAN.signal(());
+            let mut reply = heapless::String::<32>::new();
+            let _ = core::fmt::Write::write_fmt(&mut reply, format_args!("{}\n", size));
+            send_plain(socket, b"200 OK", reply.as_bytes()).await;
+        }
+        Err((status, message)) => {
+            log_warn!("Append to {} failed: {}", name.as_str(), message);
+            send_plain(socket, status, message.as_bytes()).await;
+        }
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+async fn send_rescan(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if query_param(request.query, "wait") != Some("1") {
+        RESCAN.signal(());
+        send_redirect(socket, b"303 See Other", b"/").await;
+        return;
+    }
+
+    // Drop any result from an earlier scan so we only report the one we asked for
+    SCAN_DONE.reset();
+    RESCAN.signal(());
+
+    let mut body = heapless::String::<64>::new();
+    match embassy_time::with_timeout(RESCAN_WAIT_TIMEOUT, SCAN_DONE.wait()).await {
+        Ok(Ok(count)) => {
+            let _ = core::fmt::Write::write_fmt(&mut body, format_args!("{}\n", count));
+            send_plain(socket, b"200 OK", body.as_bytes()).await;
+        }
+        Ok(Err(e)) => {
+            let _ = core::fmt::Write::write_fmt(&mut body, format_args!("Scan failed: {}\n", e));
+            send_plain(socket, b"503 Service Unavailable", body.as_bytes()).await;
+        }
+        Err(_) => {
+            log_warn!("Rescan did not finish within {} s", RESCAN_WAIT_TIMEOUT.as_secs());
+            send_plain(socket, b"504 Gateway Timeout", b"Scan did not finish in time\n").await;
+        }
+    }
+}
+
+/// Whether `path` is one of the routes in `ROUTE_HITS`.
+#[cfg(not(feature = "sd-only"))]
+fn is_route(path: &str) -> bool {
+    ROUTE_HITS.iter().any(|(route, _)| *route == path)
+}
+
+/// The route `path` belongs to. Cancels carry the operation's ID in the path, so they
+/// share one name.
+#[cfg(not(feature = "sd-only"))]
+fn route_name(path: &str) -> &str {
+    if path.starts_with("/api/ops/") && path.ends_with("/cancel") {
+        "/api/ops/cancel"
+    } else {
+        path
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn count_route(path: &str) {
+    let path = route_name(path);
+    let (_, counter) = ROUTE_HITS
+        .iter()
+        .find(|(route, _)| *route == path)
+        .unwrap_or(&ROUTE_HITS[ROUTE_HITS.len() - 1]);
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Prometheus-style text metrics.
+#[cfg(not(feature = "sd-only"))]
+async fn send_metrics(socket: &mut TcpSocket<'_>) {
+    ResponseBuilder::new(b"200 OK")
+        .content_type("text/plain; version=0.0.4")
+        .send(socket)
+        .await;
+
+    let _ = socket.write_all(b"# TYPE lt7689_requests_total counter\n").await;
+    for (route, counter) in ROUTE_HITS.iter() {
+        let mut line = heapless::String::<96>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut line,
+            format_args!("lt7689_requests_total{{route=\"{}\"}} {}\n", route, counter.load(Ordering::Relaxed)),
+        );
+        let _ = socket.write_all(line.as_bytes()).await;
+    }
+
+    let mut line = heapless::String::<96>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "# TYPE lt7689_scans_skipped_total counter\nlt7689_scans_skipped_total {}\n",
+            SCANS_SKIPPED.load(Ordering::Relaxed)
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    line.clear();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "# TYPE lt7689_size_mismatch_total counter\nlt7689_size_mismatch_total {}\n",
+            SIZE_MISMATCHES.load(Ordering::Relaxed)
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    let mut line = heapless::String::<128>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "# TYPE lt7689_listing_lock_max_us gauge\nlt7689_listing_lock_max_us {}\n",
+            LISTING_LOCK_MAX_US.load(Ordering::Relaxed)
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    let mut line = heapless::String::<128>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "# TYPE lt7689_active_connections gauge\nlt7689_active_connections {}\n",
+            ACTIVE_CONNECTIONS.load(Ordering::Relaxed)
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    let mut line = heapless::String::<256>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "# TYPE lt7689_backlogged_accepts_total counter\nlt7689_backlogged_accepts_total {}\n\
+             # TYPE lt7689_peak_concurrent_waiters gauge\nlt7689_peak_concurrent_waiters {}\n",
+            BACKLOGGED_ACCEPTS.load(Ordering::Relaxed),
+            PEAK_CONCURRENT_WAITERS.load(Ordering::Relaxed)
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    let mut line = heapless::String::<96>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!(
+            "# TYPE lt7689_ap_restarts_total counter\nlt7689_ap_restarts_total {}\n",
+            AP_RESTARTS.load(Ordering::Relaxed)
+        ),
+    );
+    let _ = socket.write_all(line.as_bytes()).await;
+
+    // There's no heap to report; tasks keep their state in static memory, so the main
+    // stack is the only one
+    let mut line = heapless::String::<192>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut line,
+        format_args!("# TYPE lt7689_stack_size_bytes gauge\nlt7689_stack_size_bytes {}\n", stack::size()),
+    );
+    if let Some(used) = stack::high_water() {
+        let _ = core::fmt::Write::write_fmt(
+            &mut line,
+            format_args!("# TYPE lt7689_stack_high_water_bytes gauge\nlt7689_stack_high_water_bytes {}\n", used),
+        );
+    }
+    let _ = socket.write_all(line.as_bytes()).await;
+}
+
+/// Appends `value` to `out` as a quoted JSON string.
+#[cfg(not(feature = "sd-only"))]
+fn push_json_str<const N: usize>(out: &mut heapless::String<N>, value: &str) {
+    let _ = out.push('"');
+    for c in value.chars() {
+        let _ = match c {
+            '"' => core::fmt::Write::write_str(out, "\\\""),
+            '\\' => core::fmt::Write::write_str(out, "\\\\"),
+            c if (c as u32) < 0x20 => core::fmt::Write::write_fmt(out, format_args!("\\u{:04x}", c as u32)),
+            c => core::fmt::Write::write_char(out, c),
+        };
+    }
+    let _ = out.push('"');
+}
+
+/// Device identity for dashboards: the same facts as the hardware block on the index page,
+/// as JSON.
+#[cfg(not(feature = "sd-only"))]
+async fn send_sysinfo(socket: &mut TcpSocket<'_>) {
+    let mut body = heapless::String::<384>::new();
+    let _ = body.push_str("{\"program\":");
+    push_json_str(&mut body, PROGRAM_NAME);
+    let _ = body.push_str(",\"version\":");
+    push_json_str(&mut body, env!("CARGO_PKG_VERSION"));
+    let _ = body.push_str(",\"mcu\":\"RP2350A\",\"wifi\":{\"mode\":\"ap\",\"ssid\":");
+    push_json_str(&mut body, &AP_SSID.lock().await);
+    let _ = body.push_str(if SSID_HIDDEN.load(Ordering::Relaxed) { ",\"hidden\":true" } else { ",\"hidden\":false" });
+    // No heap; stack use is on /metrics and /diag
+    let _ = core::fmt::Write::write_fmt(
+        &mut body,
+        format_args!(
+            ",\"address\":\"{}\"}},\"uptime_secs\":{}}}\n",
+            AP_ADDRESS,
+            Instant::now().as_secs()
+        ),
+    );
+
+    send_ok_headers(socket, "application/json", Some(body.len() as u64), false, b"").await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// One row of the `/diag` table. `value` is escaped, since some values (the SSID, the
+/// device name) come from the card.
+#[cfg(not(feature = "sd-only"))]
+async fn write_diag_row<W: Write>(out: &mut W, label: &str, value: &str) {
+    let _ = out.write_all(b"<tr><th>").await;
+    let _ = out.write_all(label.as_bytes()).await;
+    let _ = out.write_all(b"</th><td>").await;
+    write_html_escaped(out, value).await;
+    let _ = out.write_all(b"</td></tr>\n").await;
+}
+
+/// `GET /diag`: everything useful for troubleshooting on one plain page, to screenshot
+/// for a bug report. Reads the card's capacity live, so it waits for the SD bus.
+#[cfg(not(feature = "sd-only"))]
+async fn send_diag(socket: &mut TcpSocket<'_>) {
+    let capacity = {
+        let card = inflight::lock_sd(socket).await;
+        init_sd_card(&card).and_then(|sd_card| sd_card.num_bytes().map_err(|_| "Failed to read card size"))
+    };
+    let status = *SD_STATUS.lock().await;
+    let volume = *SD_VOLUME.lock().await;
+    let ssid = AP_SSID.lock().await.clone();
+    let device_name = DEVICE_NAME.lock().await.clone();
+    let entries = SD_FILES.lock().await.len();
+    let requests = ROUTE_HITS.iter().map(|(_, hits)| hits.load(Ordering::Relaxed)).sum::<u32>();
+    let uptime = Instant::now().as_secs();
+
+    ResponseBuilder::from_block(OK_HTML)
+        .header("Cache-Control", b"no-store")
+        .send(socket)
+        .await;
+    let _ = socket.write_all(b"<!DOCTYPE html>\n<html>\n<head>\n<title>Diagnostics</title>\n").await;
+    let _ = socket.write_all(b"<meta name='viewport' content='width=device-width, initial-scale=1'>\n").await;
+    let _ = socket.write_all(b"<link rel='stylesheet' href='/style.css'>\n</head>\n<body>\n<div class='container'>\n").await;
+    let _ = socket.write_all(b"<h1>Diagnostics</h1>\n<table class='diag'>\n").await;
+
+    let row = format_truncated::<96>;
+    write_diag_row(socket, "Firmware", &row(format_args!("{} {}", PROGRAM_NAME, env!("CARGO_PKG_VERSION")))).await;
+    write_diag_row(
+        socket,
+        "Uptime",
+        &row(format_args!("{}d {:02}:{:02}:{:02}", uptime / 86400, uptime / 3600 % 24, uptime / 60 % 60, uptime % 60)),
+    )
+    .await;
+    write_diag_row(socket, "Device name", if device_name.is_empty() { "(not set)" } else { device_name.as_str() }).await;
+    write_diag_row(socket, "MCU", &row(format_args!("RP2350A @ {} MHz", POWER_PRESET.sys_clock_mhz()))).await;
+
+    write_diag_row(socket, "WiFi mode", "Access point").await;
+    let hidden = if SSID_HIDDEN.load(Ordering::Relaxed) { " (hidden)" } else { "" };
+    write_diag_row(socket, "SSID", &row(format_args!("{}{}", ssid, hidden))).await;
+    write_diag_row(socket, "AP restarts", &row(format_args!("{}", AP_RESTARTS.load(Ordering::Relaxed)))).await;
+    write_diag_row(socket, "Address", &row(format_args!("{}", AP_ADDRESS))).await;
+    write_diag_row(
+        socket,
+        "Radio power mode",
+        PowerMode::from_u8(POWER_MODE.load(Ordering::Relaxed)).name(),
+    )
+    .await;
+    // The AP has no signal strength of its own, and the radio's station list isn't read
+    write_diag_row(socket, "RSSI / WiFi clients", "not available").await;
+    write_diag_row(socket, "HTTP connections", &row(format_args!("{}", ACTIVE_CONNECTIONS.load(Ordering::Relaxed)))).await;
+
+    write_diag_row(socket, "SD status", status).await;
+    let capacity = match capacity {
+        Ok(bytes) => row(format_args!("{} ({} bytes)", format_size(bytes), bytes)),
+        Err(e) => row(format_args!("{}", e)),
+    };
+    write_diag_row(socket, "SD capacity", &capacity).await;
+    let filesystem = match volume {
+        Some(boot) => row(format_args!(
+            "{}, {} byte clusters, {} byte sectors",
+            boot.fat_type().as_str(),
+            boot.cluster_bytes(),
+            boot.bytes_per_sector
+        )),
+        None => row(format_args!("unknown")),
+    };
+    write_diag_row(socket, "Filesystem", &filesystem).await;
+    // embedded-sdmmc reads the CID during init but doesn't hand it out
+    write_diag_row(socket, "SD CID", "not available").await;
+    let truncated = if LISTING_TRUNCATED.load(Ordering::Relaxed) { " (truncated)" } else { "" };
+    write_diag_row(socket, "Entries listed", &row(format_args!("{}{}", entries, truncated))).await;
+    write_diag_row(
+        socket,
+        "Last scan",
+        &row(format_args!(
+            "{} ms, every {} s, {} unchanged scans skipped",
+            LAST_SCAN_MS.load(Ordering::Relaxed),
+            SCAN_INTERVAL_SECS.load(Ordering::Relaxed),
+            SCANS_SKIPPED.load(Ordering::Relaxed)
+        )),
+    )
+    .await;
+
+    let stack_use = match stack::high_water() {
+        Some(used) => row(format_args!("{} of {} bytes at most", used, stack::size())),
+        None => row(format_args!("n/a")),
+    };
+    write_diag_row(socket, "Stack", &stack_use).await;
+    write_diag_row(socket, "Task stacks", "n/a (tasks run on the main stack)").await;
+
+    write_diag_row(socket, "Requests", &row(format_args!("{}", requests))).await;
+    write_diag_row(
+        socket,
+        "Errors",
+        &row(format_args!(
+            "{} failed requests, {} scan errors, {} SD retries",
+            FAILED_REQUESTS.load(Ordering::Relaxed),
+            SCAN_ERRORS.load(Ordering::Relaxed),
+            SD_RETRIES.load(Ordering::Relaxed)
+        )),
+    )
+    .await;
+    write_diag_row(
+        socket,
+        "Queued clients",
+        &row(format_args!(
+            "{} total, at most {} in a row",
+            BACKLOGGED_ACCEPTS.load(Ordering::Relaxed),
+            PEAK_CONCURRENT_WAITERS.load(Ordering::Relaxed)
+        )),
+    )
+    .await;
+    write_diag_row(
+        socket,
+        "Longest listing lock",
+        &row(format_args!("{} us", LISTING_LOCK_MAX_US.load(Ordering::Relaxed))),
+    )
+    .await;
+    write_diag_row(socket, "Free heap", "no heap, all memory is static").await;
+
+    let _ = socket.write_all(b"</table>\n<p><a href='/'>Back to the file list</a></p>\n</div>\n</body>\n</html>\n").await;
+}
+
+/// `GET /api/sd-health`. Wear data lives behind vendor-specific commands (CMD56 with a
+/// per-manufacturer layout) that the SD driver can't send, so this is always `501` for
+/// now, with the card's size for context. `503` if there's no card at all.
+#[cfg(not(feature = "sd-only"))]
+async fn send_sd_health(socket: &mut TcpSocket<'_>) {
+    let capacity = {
+        let card = inflight::lock_sd(socket).await;
+        init_sd_card(&card).and_then(|sd_card| sd_card.num_bytes().map_err(|_| "Failed to read card size"))
+    };
+    let capacity = match capacity {
+        Ok(capacity) => capacity,
+        Err(e) => {
+            api_error(socket, b"503 Service Unavailable", e).await;
+            return;
+        }
+    };
+
+    let mut extra = heapless::String::<64>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut extra,
+        format_args!(",\"supported\":false,\"capacity_bytes\":{}", capacity),
+    );
+    let body = api_error_body(
+        b"501 Not Implemented",
+        "health data needs vendor-specific SD commands, which aren't available over this driver",
+        &extra,
+    );
+    send_json(socket, b"501 Not Implemented", &body).await;
+}
+
+/// The error counters as JSON.
+#[cfg(not(feature = "sd-only"))]
+async fn send_errors(socket: &mut TcpSocket<'_>) {
+    let mut body = heapless::String::<160>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut body,
+        format_args!(
+            "{{\"scan_errors\":{},\"failed_requests\":{},\"sd_retries\":{},\"size_mismatches\":{},\"uptime_secs\":{}}}\n",
+            SCAN_ERRORS.load(Ordering::Relaxed),
+            FAILED_REQUESTS.load(Ordering::Relaxed),
+            SD_RETRIES.load(Ordering::Relaxed),
+            SIZE_MISMATCHES.load(Ordering::Relaxed),
+            Instant::now().as_secs()
+        ),
+    );
+
+    send_ok_headers(socket, "application/json", Some(body.len() as u64), false, b"").await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// `GET /api/files?after=INDEX&limit=N`: up to N listing entries (default and most
+/// `MAX_FILES`) following the one at INDEX, or from the start without `after`, for pages
+/// that load more as they scroll. Entries are in directory order and carry their `index`
+/// in it; `next` is the cursor for the following request, or `null` at the end.
+#[cfg(not(feature = "sd-only"))]
+async fn send_api_files(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let after = match query_param(request.query, "after").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(index)) => Some(index),
+        Some(Err(_)) => {
+            api_error(socket, b"400 Bad Request", "after must be an entry index").await;
+            return;
+        }
+    };
+    let limit = match query_param(request.query, "limit").map(str::parse::<usize>) {
+        None => MAX_FILES,
+        Some(Ok(limit)) => limit.clamp(1, MAX_FILES),
+        Some(Err(_)) => {
+            api_error(socket, b"400 Bad Request", "limit must be a number").await;
+            return;
+        }
+    };
+
+    // Copy the page out so the socket writes don't hold up the scanner
+    let (entries, first, more) = {
+        let files = SD_FILES.lock().await;
+        let first = after.map_or(0, |index| index.saturating_add(1)).min(files.len());
+        let end = first.saturating_add(limit).min(files.len());
+        let entries = heapless::Vec::<FileInfo, MAX_FILES>::from_slice(&files[first..end]).unwrap_or_default();
+        (entries, first, end < files.len())
+    };
+
+    send_ok_headers(socket, "application/json", None, false, b"Cache-Control: no-store\r\n").await;
+    let _ = socket.write_all(b"{\"files\":[").await;
+    for (i, file) in entries.iter().enumerate() {
+        let mut entry = heapless::String::<512>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut entry,
+            format_args!("{}{{\"index\":{},\"name\":", if i > 0 { "," } else { "" }, first + i),
+        );
+        push_json_str(&mut entry, &file.name);
+        let _ = core::fmt::Write::write_fmt(
+            &mut entry,
+            format_args!(",\"size\":{},\"dir\":{},\"modified\":", file.size, file.is_dir),
+        );
+        if file.has_timestamp() {
+            push_json_str(&mut entry, &format_timestamp(&file.modified));
+        } else {
+            let _ = entry.push_str("null");
+        }
+        let _ = entry.push('}');
+        if socket.write_all(entry.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+
+    let mut tail = heapless::String::<32>::new();
+    match entries.len().checked_sub(1) {
+        Some(last) if more => {
+            let _ = core::fmt::Write::write_fmt(&mut tail, format_args!("],\"next\":{}}}\n", first + last));
+        }
+        _ => {
+            let _ = tail.push_str("],\"next\":null}\n");
+        }
+    }
+    let _ = socket.write_all(tail.as_bytes()).await;
+}
+
+/// `GET /api/ops`: the transfers in progress, with how long they've run and how much of
+/// the card they've streamed.
+#[cfg(not(feature = "sd-only"))]
+async fn send_ops(socket: &mut TcpSocket<'_>) {
+    let ops = ops::snapshot();
+
+    send_ok_headers(socket, "application/json", None, false, b"Cache-Control: no-store\r\n").await;
+    let _ = socket.write_all(b"{\"ops\":[").await;
+    for (i, op) in ops.iter().enumerate() {
+        let mut entry = heapless::String::<192>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut entry,
+            format_args!(
+                "{}{{\"id\":{},\"kind\":\"{}\",\"name\":",
+                if i > 0 { "," } else { "" },
+                op.id,
+                op.kind
+            ),
+        );
+        push_json_str(&mut entry, &op.name);
+        let _ = core::fmt::Write::write_fmt(
+            &mut entry,
+            format_args!(
+                ",\"seconds\":{},\"bytes\":{},\"cancelled\":{}}}",
+                op.started.elapsed().as_secs(),
+                op.bytes,
+                op.cancelled
+            ),
+        );
+        if socket.write_all(entry.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+    let _ = socket.write_all(b"]}\n").await;
+}
+
+/// `POST /api/ops/ID/cancel`: stops a transfer at its next block. Its client sees the
+/// body cut short, as if the connection had dropped.
+#[cfg(not(feature = "sd-only"))]
+async fn send_op_cancel(socket: &mut TcpSocket<'_>, path: &str) {
+    let id = path
+        .strip_prefix("/api/ops/")
+        .and_then(|rest| rest.strip_suffix("/cancel"))
+        .and_then(|id| id.parse::<u32>().ok());
+    let Some(id) = id else {
+        api_error(socket, b"400 Bad Request", "Operation ID must be a number").await;
+        return;
+    };
+
+    if ops::cancel(id) {
+        log_info!("Cancelling operation {}", id);
+        send_json(socket, b"200 OK", "{\"ok\":true}\n").await;
+    } else {
+        api_error(socket, b"404 Not Found", "No such operation running").await;
+    }
+}
+
+/// Zeroes the error counters so a monitoring script can take a fresh baseline.
+#[cfg(not(feature = "sd-only"))]
+async fn send_errors_clear(socket: &mut TcpSocket<'_>) {
+    for counter in [&SCAN_ERRORS, &FAILED_REQUESTS, &SD_RETRIES, &SIZE_MISMATCHES] {
+        counter.store(0, Ordering::Relaxed);
+    }
+    info!("Error counters cleared");
+    send_plain(socket, b"200 OK", b"Error counters cleared\n").await;
+}
+
+/// Runs a WiFi scan on the cyw43 and collects up to MAX_SCAN_RESULTS networks. Access
+/// points seen on several channels or beacons are reported once per BSSID.
+#[cfg(not(feature = "sd-only"))]
+async fn scan_networks(control: &mut cyw43::Control<'_>) -> heapless::Vec<WifiNetwork, MAX_SCAN_RESULTS> {
+    let mut networks = heapless::Vec::<WifiNetwork, MAX_SCAN_RESULTS>::new();
+    let mut scanner = control.scan(Default::default()).await;
+    while let Some(bss) = scanner.next().await {
+        if networks.iter().any(|n| n.bssid == bss.bssid) {
+            continue;
+        }
+        let ssid_len = (bss.ssid_len as usize).min(bss.ssid.len());
+        let mut ssid = heapless::String::new();
+        // Hidden networks and non-UTF-8 names are reported with an empty SSID
+        if let Ok(name) = core::str::from_utf8(&bss.ssid[..ssid_len]) {
+            let _ = ssid.push_str(name);
+        }
+        let network = WifiNetwork {
+            ssid,
+            bssid: bss.bssid,
+            rssi: bss.rssi,
+            channel: (bss.chanspec & 0xff) as u8,
+        };
+        // Keep draining the scanner when full so the scan completes cleanly
+        let _ = networks.push(network);
+    }
+    networks
+}
+
+/// `GET /api/scan`: nearby networks as JSON, for site surveys and picking an AP channel.
+#[cfg(not(feature = "sd-only"))]
+async fn send_wifi_scan(socket: &mut TcpSocket<'_>) {
+    let mut last_scan = LAST_WIFI_SCAN.lock().await;
+    if let Some(wait) = last_scan.and_then(|at| (at + WIFI_SCAN_INTERVAL).checked_duration_since(Instant::now())) {
+        let mut retry = heapless::String::<20>::new();
+        let _ = core::fmt::Write::write_fmt(&mut retry, format_args!("{}", wait.as_secs() + 1));
+        let body = api_error_body(b"429 Too Many Requests", "Scanned recently, try again later", "");
+        ResponseBuilder::new(b"429 Too Many Requests")
+            .header("Retry-After", retry.as_bytes())
+            .content_type("application/json")
+            .content_length(body.len() as u64)
+            .send(socket)
+            .await;
+        let _ = socket.write_all(body.as_bytes()).await;
+        return;
+    }
+    *last_scan = Some(Instant::now());
+
+    log_info!("Starting WiFi scan");
+    WIFI_SCAN_DONE.reset();
+    WIFI_SCAN_REQUEST.signal(());
+    let Ok(networks) = embassy_time::with_timeout(WIFI_SCAN_TIMEOUT, WIFI_SCAN_DONE.wait()).await else {
+        log_warn!("WiFi scan did not finish within {} s", WIFI_SCAN_TIMEOUT.as_secs());
+        api_error(socket, b"504 Gateway Timeout", "Scan did not finish in time").await;
+        return;
+    };
+    drop(last_scan);
+
+    send_ok_headers(socket, "application/json", None, false, b"").await;
+    let _ = socket.write_all(b"[").await;
+    for (i, network) in networks.iter().enumerate() {
+        let mut entry = heapless::String::<160>::new();
+        if i > 0 {
+            let _ = entry.push(',');
+        }
+        let _ = entry.push_str("{\"ssid\":");
+        push_json_str(&mut entry, &network.ssid);
+        let b = network.bssid;
+        let _ = core::fmt::Write::write_fmt(
+            &mut entry,
+            format_args!(
+                ",\"bssid\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\",\"rssi\":{},\"channel\":{}}}",
+                b[0], b[1], b[2], b[3], b[4], b[5], network.rssi, network.channel
+            ),
+        );
+        let _ = socket.write_all(entry.as_bytes()).await;
+    }
+    let _ = socket.write_all(b"]\n").await;
+}
+
+/// `POST /wifi/ssid` with the new SSID as the body. The SSID is saved to `WIFI.CFG` first,
+/// then the reply goes out before the AP restarts, since the restart disconnects every
+/// client.
+#[cfg(not(feature = "sd-only"))]
+async fn send_wifi_ssid(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    if let Some(reason) = write_block_reason() {
+        send_plain(socket, b"403 Forbidden", reason).await;
+        return;
+    }
+    // Room for a 32-byte SSID plus the line ending `echo` adds
+    let mut body = [0u8; 34];
+    let Some(length) = request.content_length() else {
+        send_plain(socket, b"411 Length Required", b"Content-Length is required\n").await;
+        return;
+    };
+    if length > body.len() {
+        send_plain(socket, b"400 Bad Request", b"SSID must be 1-32 bytes\n").await;
+        return;
+    }
+    if request.body_prefix().is_none() {
+        send_plain(socket, b"431 Request Header Fields Too Large", b"Headers too large\n").await;
+        return;
+    }
+    if let Err(e) = read_body(socket, request, length, &mut &mut body[..length]).await {
+        log_warn!("SSID body incomplete: {}", e);
+        send_plain(socket, b"400 Bad Request", b"Body shorter than Content-Length\n").await;
+        return;
+    }
+
+    let body = &body[..length];
+    let body = body.strip_suffix(b"\n").unwrap_or(body);
+    let body = body.strip_suffix(b"\r").unwrap_or(body);
+    let ssid = match core::str::from_utf8(body) {
+        Ok(ssid) if (1..=32).contains(&ssid.len()) => ssid,
+        Ok(_) => {
+            send_plain(socket, b"400 Bad Request", b"SSID must be 1-32 bytes\n").await;
+            return;
+        }
+        Err(_) => {
+            send_plain(socket, b"400 Bad Request", b"SSID must be valid UTF-8\n").await;
+            return;
+        }
+    };
+    // A line break would end the `ssid=` line in WIFI.CFG
+    if ssid.chars().any(char::is_control) {
+        send_plain(socket, b"400 Bad Request", b"SSID must not contain control characters\n").await;
+        return;
+    }
+    let mut new_ssid = heapless::String::<32>::new();
+    let _ = new_ssid.push_str(ssid);
+
+    // Start from the saved file so the other keys are written back unchanged
+    let saved = match load_wifi_config(default_wifi_config()).await {
+        Ok(saved) => saved.unwrap_or_else(default_wifi_config),
+        Err(e) => {
+            log_warn!("SSID not saved, {} unreadable: {}", wifi_config::FILE_NAME, e);
+            send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+            return;
+        }
+    };
+    let settings = WifiConfig {
+        ssid: new_ssid.clone(),
+        ..runtime_settings(saved)
+    };
+    let result = {
+        let card = inflight::lock_sd(socket).await;
+        file_store(&card).write(wifi_config::FILE_NAME, settings.render().as_bytes())
+    };
+    if let Err(e) = result {
+        log_warn!("Saving {} failed: {}", wifi_config::FILE_NAME, e);
+        send_plain(socket, b"503 Service Unavailable", e.as_bytes()).await;
+        return;
+    }
+    // The card now has a config file, so there's nothing left to set up
+    NEEDS_SETUP.store(false, Ordering::Relaxed);
+
+    send_plain(socket, b"200 OK", b"SSID changed, the AP is restarting - reconnect to the new network\n").await;
+    let _ = socket.flush().await;
+
+    info!("Changing SSID to {}", ssid);
+    *AP_SSID.lock().await = new_ssid.clone();
+    AP_SSID_CHANGE.signal(new_ssid);
+}
+
+// Largest POST /api/config body accepted
+#[cfg(not(feature = "sd-only"))]
+const MAX_CONFIG_BODY: usize = 512;
+
+/// Appends the runtime settings in `settings` to `out` as a JSON object. The password is
+/// write-only and never included.
+#[cfg(not(feature = "sd-only"))]
+fn push_settings_json<const N: usize>(out: &mut heapless::String<N>, settings: &WifiConfig) {
+    let _ = core::fmt::Write::write_fmt(
+        out,
+        format_args!(
+            "{{\"lang\":\"{}\",\"log\":\"{}\",\"readonly\":{},\"heartbeat\":{},\"scan_interval\":{},\
+             \"refresh\":{},\"power_mode\":\"{}\"}}",
+            settings.lang.code(),
+            wifi_config::LOG_LEVELS[settings.log_level as usize % wifi_config::LOG_LEVELS.len()],
+            settings.read_only,
+            settings.heartbeat,
+            settings.scan_interval,
+            settings.refresh,
+            settings.power_mode.name()
+        ),
+    );
+}
+
+/// `GET /api/config`: the settings currently in effect.
+#[cfg(not(feature = "sd-only"))]
+async fn send_api_config(socket: &mut TcpSocket<'_>) {
+    let mut body = heapless::String::<256>::new();
+    push_settings_json(&mut body, &runtime_settings(default_wifi_config()));
+    let _ = body.push('\n');
+
+    send_ok_headers(socket, "application/json", Some(body.len() as u64), false, b"").await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// `GET /config.cfg`: the settings in effect as a WIFI.CFG to download, for backing up or
+/// moving to another board. Keys only WIFI.CFG holds come from the card's copy, or the
+/// built-in defaults if there isn't one. Anyone on the AP can fetch it, so the password
+/// is left out.
+#[cfg(not(feature = "sd-only"))]
+async fn send_config_file(socket: &mut TcpSocket<'_>) {
+    let base = match load_wifi_config(default_wifi_config()).await {
+        Ok(Some(saved)) => saved,
+        Ok(None) => default_wifi_config(),
+        Err(e) => {
+            log_warn!("Exporting settings without {}: {}", wifi_config::FILE_NAME, e);
+            default_wifi_config()
+        }
+    };
+    let rendered = runtime_settings(base).render();
+
+    let mut body = heapless::String::<{ wifi_config::MAX_LEN + 64 }>::new();
+    for line in rendered.lines() {
+        if line.starts_with("password=") {
+            let _ = body.push_str("# password left out; add password=... to set one\n");
+        } else {
+            let _ = body.push_str(line);
+            let _ = body.push('\n');
+        }
+    }
+
+    let mut disposition = heapless::String::<64>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut disposition,
+        format_args!(
+            "Content-Disposition: attachment; filename=\"{}\"\r\nCache-Control: no-store\r\n",
+            wifi_config::FILE_NAME
+        ),
+    );
+    send_ok_headers(socket, "text/plain; charset=utf-8", Some(body.len() as u64), false, disposition.as_bytes()).await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+#[cfg(not(feature = "sd-only"))]
+fn seconds(value: json::Value<'_>, range: &core::ops::RangeInclusive<u16>) -> Option<u16> {
+    match value {
+        json::Value::Int(secs) => u16::try_from(secs).ok().filter(|secs| range.contains(secs)),
+        _ => None,
+    }
+}
+
+/// Checks one member of a `POST /api/config` body and stores it in `settings`.
+#[cfg(not(feature = "sd-only"))]
+fn apply_setting(settings: &mut WifiConfig, key: &str, value: json::Value<'_>) -> Result<(), &'static str> {
+    use json::Value;
+    match (key, value) {
+        ("log", Value::Str(level)) => {
+            let level = wifi_config::LOG_LEVELS.iter().position(|&l| l == level);
+            settings.log_level = level.ok_or("must be info, warn or error")? as u8;
+        }
+        ("log", _) => return Err("must be info, warn or error"),
+        ("readonly", Value::Bool(read_only)) => settings.read_only = read_only,
+        ("heartbeat", Value::Bool(heartbeat)) => settings.heartbeat = heartbeat,
+        ("readonly" | "heartbeat", _) => return Err("must be true or false"),
+        ("lang", Value::Str(code)) => settings.lang = Lang::from_code(code).ok_or("must be en or de")?,
+        ("lang", _) => return Err("must be en or de"),
+        ("scan_interval", value) => {
+            settings.scan_interval = seconds(value, &wifi_config::SCAN_INTERVALS)
+                .ok_or("must be a number of seconds from 5 to 3600")?;
+        }
+        ("refresh", value) => {
+            settings.refresh = seconds(value, &wifi_config::REFRESH_INTERVALS)
+                .ok_or("must be a number of seconds from 0 (off) to 3600")?;
+        }
+        ("power_mode", Value::Str(name)) => {
+            settings.power_mode = PowerMode::from_name(name).ok_or("must be performance, powersave or aggressive")?;
+        }
+        ("power_mode", _) => return Err("must be performance, powersave or aggressive"),
+        ("password", Value::Str(raw)) => {
+            settings.password = json::unescape::<64>(raw)
+                .filter(|password| is_valid_wpa2_password(password))
+                .ok_or("must be 8-63 printable ASCII characters")?;
+        }
+        ("password", _) => return Err("must be a string"),
+        _ => return Err("unknown setting"),
+    }
+    Ok(())
+}
+
+/// `POST /api/config` with a JSON object of settings to change. Every field is checked
+/// before any is applied, and a failed check is reported per field. Accepted settings take
+/// effect at once and are saved to WIFI.CFG; a new password is used from the next boot.
+#[cfg(not(feature = "sd-only"))]
+async fn send_api_config_update(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let Some(length) = request.content_length() else {
+        api_error(socket, b"411 Length Required", "Content-Length is required").await;
+        return;
+    };
+    if length > MAX_CONFIG_BODY {
+        api_error(socket, b"413 Content Too Large", "Body too large").await;
+        return;
+    }
+    if request.body_prefix().is_none() {
+        api_error(socket, b"431 Request Header Fields Too Large", "Headers too large").await;
+        return;
+    }
+    let mut buf = [0u8; MAX_CONFIG_BODY];
+    if let Err(e) = read_body(socket, request, length, &mut &mut buf[..length]).await {
+        log_warn!("Config body incomplete: {}", e);
+        api_error(socket, b"400 Bad Request", "Body shorter than Content-Length").await;
+        return;
+    }
+
+    // Start from the saved file so keys this endpoint doesn't cover (the AP settings) are
+    // written back unchanged
+    let loaded = load_wifi_config(default_wifi_config()).await;
+    let mut settings = runtime_settings(match &loaded {
+        Ok(Some(saved)) => saved.clone(),
+        _ => default_wifi_config(),
+    });
+
+    let members = core::str::from_utf8(&buf[..length])
+        .map_err(|_| "body is not UTF-8")
+        .and_then(json::members);
+    let members = match members {
+        Ok(members) => members,
+        Err(e) => {
+            api_error(socket, b"400 Bad Request", e).await;
+            return;
+        }
+    };
+    // Room kept after the last field for what closes the `errors` object
+    const CLOSING: &str = "},\"more\":true";
+    // Sized so the envelope around it still fits `api_error_body`'s string
+    let mut fields = heapless::String::<576>::new();
+    let mut errors = 0;
+    let mut more = false;
+    for member in members {
+        let (key, result) = match member {
+            Ok((key, value)) => (key, apply_setting(&mut settings, key, value)),
+            Err(e) => ("", Err(e)),
+        };
+        let Err(e) = result else { continue };
+        // Fields that don't fit are left out, but still stop the update
+        let mut field = heapless::String::<160>::new();
+        let _ = field.push_str(if errors == 0 { ",\"errors\":{" } else { "," });
+        push_json_str(&mut field, key);
+        let _ = field.push(':');
+        push_json_str(&mut field, e);
+        // A full scratch string may have cut the field short
+        if field.len() < field.capacity()
+            && fields.len() + field.len() + CLOSING.len() <= fields.capacity()
+        {
+            let _ = fields.push_str(&field);
+            errors += 1;
+        } else {
+            more = true;
+        }
+    }
+    if errors == 0 && more {
+        let _ = fields.push_str(",\"errors\":{");
+    }
+    if errors > 0 || more {
+        let _ = fields.push_str(if more { CLOSING } else { "}" });
+        let body = api_error_body(b"400 Bad Request", "invalid settings", &fields);
+        send_json(socket, b"400 Bad Request", &body).await;
+        return;
+    }
+
+    // Checked before applying, so turning read-only on is itself still saved
+    let save_error = match (&loaded, write_block_reason()) {
+        (Err(e), _) => Some(*e),
+        (_, Some(reason)) => Some(core::str::from_utf8(reason).unwrap_or("").trim_end()),
+        (Ok(_), None) => None,
+    };
+    apply_runtime_settings(&settings);
+    info!("Settings updated");
+
+    let save_error = match save_error {
+        Some(e) => Some(e),
+        None => {
+            let card = inflight::lock_sd(socket).await;
+            file_store(&card).write(wifi_config::FILE_NAME, settings.render().as_bytes()).err()
+        }
+    };
+    match save_error {
+        Some(e) => log_warn!("Settings not saved to {}: {}", wifi_config::FILE_NAME, e),
+        // The card now has a config file, so there's nothing left to set up
+        None => NEEDS_SETUP.store(false, Ordering::Relaxed),
+    }
+
+    let mut body = heapless::String::<640>::new();
+    let _ = body.push_str("{\"ok\":true,\"saved\":");
+    let _ = body.push_str(if save_error.is_some() { "false" } else { "true" });
+    if let Some(e) = save_error {
+        let _ = body.push_str(",\"save_error\":");
+        push_json_str(&mut body, e);
+    }
+    let _ = body.push_str(",\"settings\":");
+    push_settings_json(&mut body, &settings);
+    let _ = body.push_str("}\n");
+
+    send_ok_headers(socket, "application/json", Some(body.len() as u64), false, b"").await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// Sends a complete JSON response with `status`.
+#[cfg(not(feature = "sd-only"))]
+async fn send_json(socket: &mut TcpSocket<'_>, status: &[u8], body: &str) {
+    ResponseBuilder::new(status)
+        .content_type("application/json")
+        .content_length(body.len() as u64)
+        .send(socket)
+        .await;
+    let _ = socket.write_all(body.as_bytes()).await;
+}
+
+/// The error envelope every `/api/*` route answers failures with,
+/// `{"error":"message","code":N}`, where N repeats the status code. `extra` is any further
+/// members, already rendered with a leading comma.
+#[cfg(not(feature = "sd-only"))]
+fn api_error_body(status: &[u8], message: &str, extra: &str) -> heapless::String<640> {
+    let code = status.get(..3).and_then(|code| core::str::from_utf8(code).ok()).unwrap_or("500");
+    let mut body = heapless::String::new();
+    let _ = body.push_str("{\"error\":");
+    push_json_str(&mut body, message);
+    let _ = body.push_str(",\"code\":");
+    let _ = body.push_str(code);
+    let _ = body.push_str(extra);
+    let _ = body.push_str("}\n");
+    body
+}
+
+/// Answers an `/api/*` request with `status` and the error envelope.
+#[cfg(not(feature = "sd-only"))]
+async fn api_error(socket: &mut TcpSocket<'_>, status: &[u8], message: &str) {
+    send_json(socket, status, &api_error_body(status, message, "")).await;
+}
+
+// Shared by every page. Served from /style.css so the auto-refreshing listing doesn't
+// resend it, unless the `inline-css` feature embeds it in each page instead.
+#[cfg(not(feature = "sd-only"))]
+const STYLESHEET: &[u8] = b"\
+body { font-family: Arial, sans-serif; margin: 20px; background: #f5f5f5; }
+h1 { color: #333; }
+.container { max-width: 900px; margin: 0 auto; background: white; padding: 30px; border-radius: 10px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); }
+.status { background: #e8f5e9; padding: 15px; border-radius: 5px; margin: 20px 0; border-left: 4px solid #4caf50; }
+ul { list-style: none; padding: 0; }
+li { padding: 12px; margin: 8px 0; background: #fafafa; border-radius: 5px; border-left: 3px solid #2196f3; }
+.info { color: #666; font-size: 0.9em; margin-top: 30px; padding-top: 20px; border-top: 2px solid #eee; }
+.spark { vertical-align: middle; }
+.spark polyline { fill: none; stroke: #4caf50; stroke-width: 1.5; }
+.diag th { text-align: left; padding: 3px 12px 3px 0; color: #555; vertical-align: top; }
+.diag td { font-family: monospace; }
+.hw-info { background: #fff3cd; padding: 10px; border-radius: 5px; margin: 10px 0; }
+.grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(150px, 1fr)); gap: 10px; }
+.grid li { margin: 0; display: flex; flex-direction: column; align-items: center; text-align: center; word-break: break-all; border-left: none; border-top: 3px solid #2196f3; }
+.grid .icon { font-size: 2.5em; }
+.grid .thumb { width: 64px; height: 64px; object-fit: contain; }
+.recent { background: #e3f2fd; padding: 10px; border-radius: 5px; margin: 10px 0; }
+.recent li { padding: 4px 8px; margin: 4px 0; }
+";
+
+// FNV-1a of the stylesheet, so the ETag changes whenever the CSS does
+#[cfg(not(feature = "sd-only"))]
+const STYLESHEET_HASH: u32 = {
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < STYLESHEET.len() {
+        hash ^= STYLESHEET[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+};
+
+#[cfg(not(feature = "sd-only"))]
+async fn send_stylesheet(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let mut etag = heapless::String::<12>::new();
+    let _ = core::fmt::Write::write_fmt(&mut etag, format_args!("\"{:08x}\"", STYLESHEET_HASH));
+
+    if request.has_etag(&etag) {
+        ResponseBuilder::new(b"304 Not Modified")
+            .header("ETag", etag.as_bytes())
+            .send(socket)
+            .await;
+        return;
+    }
+
+    let mut headers = heapless::String::<80>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut headers,
+        format_args!("Cache-Control: public, max-age=86400\r\nETag: {}\r\n", etag),
+    );
+    send_ok_headers(socket, "text/css", Some(STYLESHEET.len() as u64), false, headers.as_bytes()).await;
+    if !request.is_head() {
+        let _ = socket.write_all(STYLESHEET).await;
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+const FAVICON_SIZE: usize = 16;
+// ICONDIR + one ICONDIRENTRY + BITMAPINFOHEADER + BGRA pixels + 1-bit AND mask
+#[cfg(not(feature = "sd-only"))]
+const FAVICON_LEN: usize = 6 + 16 + 40 + FAVICON_SIZE * FAVICON_SIZE * 4 + FAVICON_SIZE * 4;
 
-static SD_STATUS: embassy_sync::mutex::Mutex<
-    embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
-    &str,
-> = embassy_sync::mutex::Mutex::new("Initializing...");
+/// Builds a 16x16 32-bit ICO showing a filled circle in the given colour.
+#[cfg(not(feature = "sd-only"))]
+fn favicon_ico(rgb: [u8; 3]) -> [u8; FAVICON_LEN] {
+    let mut ico = [0u8; FAVICON_LEN];
+    let image_len = (FAVICON_LEN - 22) as u32;
+    let pixel_len = (FAVICON_SIZE * FAVICON_SIZE * 4 + FAVICON_SIZE * 4) as u32;
 
-#[derive(Clone)]
-struct FileInfo {
-    name: heapless::String<64>,
-    size: u32,
-    is_dir: bool,
-}
+    // ICONDIR: reserved, type 1 (icon), one image
+    ico[2..4].copy_from_slice(&1u16.to_le_bytes());
+    ico[4..6].copy_from_slice(&1u16.to_le_bytes());
+    // ICONDIRENTRY
+    ico[6] = FAVICON_SIZE as u8;
+    ico[7] = FAVICON_SIZE as u8;
+    ico[10..12].copy_from_slice(&1u16.to_le_bytes());
+    ico[12..14].copy_from_slice(&32u16.to_le_bytes());
+    ico[14..18].copy_from_slice(&image_len.to_le_bytes());
+    ico[18..22].copy_from_slice(&22u32.to_le_bytes());
+    // BITMAPINFOHEADER; the height covers both the colour and AND mask halves
+    ico[22..26].copy_from_slice(&40u32.to_le_bytes());
+    ico[26..30].copy_from_slice(&(FAVICON_SIZE as u32).to_le_bytes());
+    ico[30..34].copy_from_slice(&(2 * FAVICON_SIZE as u32).to_le_bytes());
+    ico[34..36].copy_from_slice(&1u16.to_le_bytes());
+    ico[36..38].copy_from_slice(&32u16.to_le_bytes());
+    ico[42..46].copy_from_slice(&pixel_len.to_le_bytes());
 
-#[embassy_executor::task]
-async fn cyw43_task(
-    runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
-) -> ! {
-    runner.run().await
-}
+    // Pixels are stored bottom-up as BGRA; the circle is symmetric so row order doesn't matter
+    let centre = FAVICON_SIZE as i32 - 1;
+    let radius_sq = (FAVICON_SIZE * FAVICON_SIZE) as i32;
+    for y in 0..FAVICON_SIZE {
+        for x in 0..FAVICON_SIZE {
+            let (dx, dy) = (2 * x as i32 - centre, 2 * y as i32 - centre);
+            let offset = 62 + (y * FAVICON_SIZE + x) * 4;
+            if dx * dx + dy * dy <= radius_sq {
+                ico[offset..offset + 4].copy_from_slice(&[rgb[2], rgb[1], rgb[0], 0xFF]);
+            }
+        }
+    }
+    // The AND mask stays all zero; transparency comes from the alpha channel
 
-#[embassy_executor::task]
-async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
-    runner.run().await
+    ico
 }
 
-#[embassy_executor::task]
-async fn sd_card_task() {
-    info!("SD card task started, waiting for system to stabilize...");
-    Timer::after(Duration::from_secs(3)).await;
+/// Serves a favicon coloured by SD status so the browser tab shows device health.
+#[cfg(not(feature = "sd-only"))]
+async fn send_favicon(socket: &mut TcpSocket<'_>) {
+    let rgb = match *SD_STATUS.lock().await {
+        status if sd_ready(status) => [0x4c, 0xaf, 0x50],
+        "Initializing..." => [0xff, 0xc1, 0x07],
+        _ => [0xf4, 0x43, 0x36],
+    };
+    let ico = favicon_ico(rgb);
 
-    loop {
-        info!("Attempting to read SD card...");
+    ResponseBuilder::new(b"200 OK")
+        .content_type("image/x-icon")
+        .content_length(ico.len() as u64)
+        // Status can change at any moment, so never let the browser reuse an old icon
+        .header("Cache-Control", b"no-store")
+        .send(socket)
+        .await;
+    let _ = socket.write_all(&ico).await;
+}
 
-        match read_sd_card() {
-            Ok(file_list) => {
-                // Update shared state
-                {
-                    let mut files = SD_FILES.lock().await;
-                    files.clear();
-                    for file in &file_list {
-                        let _ = files.push(file.clone());
-                    }
-                }
+/// Everything the index page shows, captured once so repeated renders produce identical output.
+#[cfg(not(feature = "sd-only"))]
+struct IndexPage<'a> {
+    files: &'a [FileInfo],
+    status: &'a str,
+    ssid: &'a str,
+    ssid_hidden: bool,
+    // Title and heading in place of the built-in ones, if set
+    device_name: &'a str,
+    scans_skipped: u32,
+    // Entries read so far by a scan that's still running
+    scan_progress: Option<u32>,
+    show: ShowFilter,
+    view: ListingView,
+    // The root has an INDEX_FILE, so this listing was requested explicitly with ?raw=1
+    has_index: bool,
+    raw: bool,
+    show_hidden: bool,
+    write_protected: bool,
+    truncated: bool,
+    // No auto-refresh, stylesheet or device info, for scripts scraping the listing
+    static_page: bool,
+    // Checkboxes for POST /download-selected; the page stops refreshing so they stay ticked
+    select: bool,
+    volume: Option<fat::BootSector>,
+    downloads: &'a [DownloadCount],
+    lang: Lang,
+    cols: heapless::Vec<Column, 5>,
+    // Seconds between reloads, 0 for none
+    refresh_secs: u32,
+    // Entry counts of recent scans, oldest first
+    count_history: heapless::Vec<u8, FILE_COUNT_SAMPLES>,
+}
 
-                {
-                    let mut status = SD_STATUS.lock().await;
-                    *status = "Ready";
-                }
+#[cfg(not(feature = "sd-only"))]
+impl IndexPage<'_> {
+    fn text(&self, key: Text) -> &'static str {
+        self.lang.text(key)
+    }
 
-                info!("SD card read successfully, found {} files", file_list.len());
-            }
-            Err(e) => {
-                {
-                    let mut status = SD_STATUS.lock().await;
-                    *status = e;
+    fn is_listed(&self, file_info: &FileInfo) -> bool {
+        self.show.matches(file_info) && (self.show_hidden || !file_info.is_hidden())
+    }
+
+    /// Writes a link back to the listing with the view settings changed, keeping `cols`,
+    /// `raw` and `static`.
+    async fn write_link<W: Write>(
+        &self,
+        out: &mut W,
+        show: ShowFilter,
+        show_hidden: bool,
+        view: ListingView,
+        label: &str,
+    ) {
+        let _ = out.write_all(b"<a href='/?show=").await;
+        let _ = out.write_all(show.as_str().as_bytes()).await;
+        if show_hidden {
+            let _ = out.write_all(b"&amp;hidden=1").await;
+        }
+        if view == ListingView::Grid {
+            let _ = out.write_all(b"&amp;view=grid").await;
+        }
+        if self.cols != DEFAULT_COLUMNS {
+            let _ = out.write_all(b"&amp;cols=").await;
+            for (i, column) in self.cols.iter().enumerate() {
+                if i > 0 {
+                    let _ = out.write_all(b",").await;
                 }
-                warn!("SD card error: {}", e);
+                let _ = out.write_all(column.as_str().as_bytes()).await;
             }
         }
+        if self.raw {
+            let _ = out.write_all(b"&amp;raw=1").await;
+        }
+        if self.static_page {
+            let _ = out.write_all(b"&amp;static=1").await;
+        }
+        let _ = out.write_all(b"'>").await;
+        let _ = out.write_all(label.as_bytes()).await;
+        let _ = out.write_all(b"</a> ").await;
+    }
+}
 
-        // Scan every 30 seconds
-        Timer::after(Duration::from_secs(30)).await;
+/// Writes `text` with the characters that are special in HTML text and attributes escaped.
+#[cfg(not(feature = "sd-only"))]
+async fn write_html_escaped<W: Write>(out: &mut W, text: &str) {
+    let mut rest = text;
+    while let Some(i) = rest.find(['&', '<', '>', '"', '\'']) {
+        let _ = out.write_all(rest[..i].as_bytes()).await;
+        let entity: &[u8] = match rest.as_bytes()[i] {
+            b'&' => b"&amp;",
+            b'<' => b"&lt;",
+            b'>' => b"&gt;",
+            b'"' => b"&quot;",
+            _ => b"&#39;",
+        };
+        let _ = out.write_all(entity).await;
+        rest = &rest[i + 1..];
     }
+    let _ = out.write_all(rest.as_bytes()).await;
 }
 
-fn read_sd_card() -> Result<heapless::Vec<FileInfo, 32>, &'static str> {
-    let mut file_list: heapless::Vec<FileInfo, 32> = heapless::Vec::new();
+/// `Write` sink that only counts bytes, used to size a page before sending it, and takes
+/// their FNV-1a hash for an ETag.
+#[cfg(not(feature = "sd-only"))]
+struct CountingSink {
+    len: usize,
+    hash: u32,
+}
 
-    // Create SPI for SD card
-    let mut sd_spi_config = SpiConfig::default();
-    sd_spi_config.frequency = 400_000;
+#[cfg(not(feature = "sd-only"))]
+impl CountingSink {
+    fn new() -> Self {
+        CountingSink { len: 0, hash: 0x811c_9dc5 }
+    }
+}
 
-    let spi = Spi::new_blocking(
-        unsafe { embassy_rp::peripherals::SPI0::steal() },
-        unsafe { embassy_rp::peripherals::PIN_18::steal() },
-        unsafe { embassy_rp::peripherals::PIN_19::steal() },
-        unsafe { embassy_rp::peripherals::PIN_16::steal() },
-        sd_spi_config,
-    );
+#[cfg(not(feature = "sd-only"))]
+impl embedded_io_async::ErrorType for CountingSink {
+    type Error = core::convert::Infallible;
+}
 
-    let cs = Output::new(
-        unsafe { embassy_rp::peripherals::PIN_17::steal() },
-        Level::High,
-    );
+#[cfg(not(feature = "sd-only"))]
+impl Write for CountingSink {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.len += buf.len();
+        for &byte in buf {
+            self.hash ^= byte as u32;
+            self.hash = self.hash.wrapping_mul(0x0100_0193);
+        }
+        Ok(buf.len())
+    }
+}
 
-    // Create SD card instance
-    let spi_device = match ExclusiveDevice::new(spi, cs, embassy_time::Delay) {
-        Ok(dev) => dev,
-        Err(_) => return Err("Failed to create SPI device"),
-    };
-    let sd_card = SdCard::new(spi_device, embassy_time::Delay);
+#[cfg(not(feature = "sd-only"))]
+async fn send_index(socket: &mut TcpSocket<'_>, request: &Request<'_>) {
+    let query = request.query;
+    if query_param(query, "setup") == Some("skip") {
+        // Don't bounce back to the setup form from the listing's own links until next boot
+        NEEDS_SETUP.store(false, Ordering::Relaxed);
+    }
 
-    // Initialize SD card
-    match sd_card.num_bytes() {
-        Ok(size) => {
-            info!("SD card detected: {} bytes", size);
-        }
-        Err(_) => {
-            return Err("No SD card detected");
-        }
+    // Get SD card status and file list
+    let status = *SD_STATUS.lock().await;
+    if SD_REQUIRED.load(Ordering::Relaxed) && !sd_ready(status) {
+        send_not_ready(socket, request).await;
+        return;
+    }
+    let volume = *SD_VOLUME.lock().await;
+    let downloads = DOWNLOAD_COUNTS.lock().await.clone();
+    let ssid = AP_SSID.lock().await.clone();
+    let device_name = DEVICE_NAME.lock().await.clone();
+    let count_history = FILE_COUNT_HISTORY.lock().await.iter().copied().collect();
+    let files = SD_FILES.lock().await;
+    let locked_at = Instant::now();
+    let page = IndexPage {
+        files: &files,
+        status,
+        ssid: &ssid,
+        ssid_hidden: SSID_HIDDEN.load(Ordering::Relaxed),
+        device_name: &device_name,
+        scans_skipped: SCANS_SKIPPED.load(Ordering::Relaxed),
+        scan_progress: SCAN_IN_PROGRESS
+            .load(Ordering::Relaxed)
+            .then(|| SCAN_PROGRESS.load(Ordering::Relaxed)),
+        show: ShowFilter::from_query(query),
+        view: ListingView::from_query(query),
+        has_index: ROOT_HAS_INDEX.load(Ordering::Relaxed),
+        raw: query_param(query, "raw").is_some(),
+        show_hidden: query_param(query, "hidden") == Some("1"),
+        write_protected: WRITE_PROTECTED.load(Ordering::Relaxed),
+        truncated: LISTING_TRUNCATED.load(Ordering::Relaxed),
+        static_page: query_param(query, "static") == Some("1") || !request.is_browser(),
+        select: query_param(query, "select") == Some("1"),
+        volume,
+        downloads: &downloads,
+        lang: Lang::from_u8(LANG.load(Ordering::Relaxed)),
+        cols: Column::list_from_query(query),
+        refresh_secs: REFRESH_SECS.load(Ordering::Relaxed),
+        count_history,
     };
+    let gzip = request.accepts_gzip();
 
-    // Create volume manager
-    let mut volume_mgr: VolumeManager<_, _, 4, 4, 1> = VolumeManager::new(sd_card, DummyTimesource);
+    // Render the page once up front to size and hash it. The ETag covers everything the
+    // page shows, so an auto-refresh of an unchanged page gets a bodiless 304. It's weak
+    // since the gzipped and plain pages share it.
+    let mut counter = CountingSink::new();
+    render_index(&mut counter, &page).await;
+    let mut etag = heapless::String::<16>::new();
+    let _ = core::fmt::Write::write_fmt(&mut etag, format_args!("W/\"{:08x}\"", counter.hash));
+    if request.has_etag(&etag) {
+        drop(files);
+        let held_us = locked_at.elapsed().as_micros().min(u32::MAX as u64) as u32;
+        LISTING_LOCK_MAX_US.fetch_max(held_us, Ordering::Relaxed);
+        ResponseBuilder::new(b"304 Not Modified")
+            .header("ETag", etag.as_bytes())
+            .header("Cache-Control", b"no-cache")
+            .send(socket)
+            .await;
+        return;
+    }
 
-    // Open volume
-    let mut volume = match volume_mgr.open_volume(embedded_sdmmc::VolumeIdx(0)) {
-        Ok(v) => v,
-        Err(_) => {
-            return Err("Failed to open volume (format as FAT32)");
-        }
+    // HEAD reports the Content-Length a GET would get. A compressed GET goes without
+    // rather than compressing the page twice.
+    let length = if !gzip {
+        Some(counter.len as u64)
+    } else if request.is_head() {
+        let mut compressed = CountingSink::new();
+        let mut gz = GzipWriter::new(&mut compressed);
+        render_index(&mut gz, &page).await;
+        let _ = gz.finish().await;
+        Some(compressed.len as u64)
+    } else {
+        None
     };
 
-    // Open root directory
-    let mut root_dir = match volume.open_root_dir() {
-        Ok(dir) => dir,
-        Err(_) => {
-            return Err("Failed to open root directory");
+    // Send HTTP response
+    let mut headers = heapless::String::<64>::new();
+    let _ = core::fmt::Write::write_fmt(&mut headers, format_args!("Cache-Control: no-cache\r\nETag: {}\r\n", etag));
+    send_ok_headers(socket, "text/html; charset=utf-8", length, gzip, headers.as_bytes()).await;
+
+    if !request.is_head() {
+        if gzip {
+            let mut gz = GzipWriter::new(&mut *socket);
+            render_index(&mut gz, &page).await;
+            let _ = gz.finish().await;
+        } else {
+            render_index(socket, &page).await;
         }
-    };
+    }
+    drop(files);
+    let held_us = locked_at.elapsed().as_micros().min(u32::MAX as u64) as u32;
+    LISTING_LOCK_MAX_US.fetch_max(held_us, Ordering::Relaxed);
+}
 
-    // Iterate through directory
-    let _ = root_dir.iterate_dir(|entry| {
-        let mut name = heapless::String::new();
+// Files shown in the "recently modified" box above the listing
+#[cfg(not(feature = "sd-only"))]
+const RECENT_FILES: usize = 5;
 
-        // Convert filename to string - use core::fmt::Write explicitly
-        let _ = core::fmt::Write::write_fmt(&mut name, format_args!("{}", entry.name));
+/// The newest few files by modification date. Left out when no file on the card has a
+/// real date.
+#[cfg(not(feature = "sd-only"))]
+async fn render_recent<W: Write>(out: &mut W, page: &IndexPage<'_>) {
+    let mut recent: heapless::Vec<&FileInfo, MAX_FILES> = page
+        .files
+        .iter()
+        .filter(|f| !f.is_dir && f.has_timestamp() && (page.show_hidden || !f.is_hidden()))
+        .collect();
+    if recent.is_empty() {
+        return;
+    }
+    recent.sort_unstable_by(|a, b| b.modified.cmp(&a.modified));
 
-        let file_info = FileInfo {
-            name,
-            size: entry.size,
-            is_dir: entry.attributes.is_directory(),
-        };
+    let _ = out.write_all(b"<div class='recent'>\n<strong>").await;
+    let _ = out.write_all(page.text(Text::RecentlyModified).as_bytes()).await;
+    let _ = out.write_all(b"</strong>\n<ul>\n").await;
+    for file_info in recent.iter().take(RECENT_FILES) {
+        let _ = out.write_all(b"<li><a href='/view?name=").await;
+        let _ = out.write_all(url_encode(&file_info.name).as_bytes()).await;
+        let _ = out.write_all(b"'>").await;
+        let _ = out.write_all(file_info.name.as_bytes()).await;
+        let _ = out.write_all(b"</a> <span style='color:#999'>").await;
+        let _ = out.write_all(format_timestamp(&file_info.modified).as_bytes()).await;
+        let _ = out.write_all(b"</span></li>\n").await;
+    }
+    let _ = out.write_all(b"</ul>\n</div>\n").await;
+}
 
-        let _ = file_list.push(file_info);
-    });
+/// Writes one column of a listing entry.
+#[cfg(not(feature = "sd-only"))]
+async fn render_column<W: Write>(out: &mut W, page: &IndexPage<'_>, file_info: &FileInfo, column: Column) {
+    match column {
+        Column::Icon => {
+            if file_info.is_dir {
+                let _ = out.write_all(b"<span class='icon'>\xF0\x9F\x93\x81</span>").await; // 📁
+            } else if page.view == ListingView::Grid && mime_for(&file_info.name).starts_with("image/") {
+                let _ = out.write_all(b"<img class='thumb' loading='lazy' alt='' src='/thumb?name=").await;
+                let _ = out.write_all(url_encode(&file_info.name).as_bytes()).await;
+                let _ = out.write_all(b"'>").await;
+            } else {
+                let _ = out.write_all(b"<span class='icon'>").await;
+                let _ = out.write_all(icon_for(&file_info.name)).await;
+                let _ = out.write_all(b"</span>").await;
+            }
+        }
+        Column::Name => {
+            if file_info.is_dir {
+                let _ = out.write_all(file_info.name.as_bytes()).await;
+            } else {
+                let _ = out.write_all(b"<a href='/view?name=").await;
+                let _ = out.write_all(url_encode(&file_info.name).as_bytes()).await;
+                let _ = out.write_all(b"'>").await;
+                let _ = out.write_all(file_info.name.as_bytes()).await;
+                let _ = out.write_all(b"</a> <a href='/download?name=").await;
+                let _ = out.write_all(url_encode(&file_info.name).as_bytes()).await;
+                let _ = out.write_all(b"' title='").await;
+                let _ = out.write_all(page.text(Text::Download).as_bytes()).await;
+                let _ = out.write_all(b"'>\xE2\xAC\x87</a>").await; // ⬇
+            }
+        }
+        Column::Size => {
+            let _ = out.write_all(b"<span style='color:#999'>(").await;
+            if file_info.is_dir {
+                let _ = out.write_all(page.text(Text::Directory).as_bytes()).await;
+            } else {
+                let size_str = format_size(file_info.size);
+                let _ = out.write_all(size_str.as_bytes()).await;
+                let downloads = page
+                    .downloads
+                    .iter()
+                    .find(|entry| entry.name.eq_ignore_ascii_case(&file_info.name))
+                    .map_or(0, |entry| entry.count);
+                if downloads > 0 {
+                    let mut downloads_str = heapless::String::<32>::new();
+                    let unit = page.text(if downloads == 1 { Text::DownloadCount } else { Text::DownloadsCount });
+                    let _ = core::fmt::Write::write_fmt(&mut downloads_str, format_args!(", {} {}", downloads, unit));
+                    let _ = out.write_all(downloads_str.as_bytes()).await;
+                }
+            }
+            let _ = out.write_all(b")</span>").await;
+        }
+        Column::Date => {
+            let _ = out.write_all(b"<span style='color:#999'>").await;
+            if file_info.has_timestamp() {
+                let _ = out.write_all(format_timestamp(&file_info.modified).as_bytes()).await;
+            } else {
+                let _ = out.write_all(b"-").await;
+            }
+            let _ = out.write_all(b"</span>").await;
+        }
+        Column::Attr => {
+            let _ = out.write_all(b"<code style='color:#999'>").await;
+            let _ = out.write_all(&file_info.attribute_flags()).await;
+            let _ = out.write_all(b"</code>").await;
+        }
+    }
+}
 
-    // Clean up
-    root_dir.close().ok();
+/// An inline SVG line of `counts` over the last `FILE_COUNT_SAMPLES` scans, newest at the
+/// right. Until that many scans have run the line starts part-way in. Needs two samples.
+#[cfg(not(feature = "sd-only"))]
+async fn render_sparkline<W: Write>(out: &mut W, counts: &[u8]) {
+    const STEP: usize = 2;
+    const WIDTH: usize = (FILE_COUNT_SAMPLES - 1) * STEP;
+    const HEIGHT: usize = 14;
+    if counts.len() < 2 {
+        return;
+    }
 
-    Ok(file_list)
+    let min = counts.iter().copied().min().unwrap_or(0) as usize;
+    let max = counts.iter().copied().max().unwrap_or(0) as usize;
+    let mut svg = heapless::String::<640>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut svg,
+        format_args!(
+            " <svg class='spark' width='{w}' height='{h}' viewBox='0 -1 {w} {h}'><title>Entries over the last {} scans</title><polyline points='",
+            counts.len(),
+            w = WIDTH,
+            h = HEIGHT + 2,
+        ),
+    );
+    let first_x = (FILE_COUNT_SAMPLES - counts.len()) * STEP;
+    for (i, &count) in counts.iter().enumerate() {
+        // A steady count is drawn along the middle rather than the bottom
+        let y = match max - min {
+            0 => HEIGHT / 2,
+            range => HEIGHT - (count as usize - min) * HEIGHT / range,
+        };
+        let _ = core::fmt::Write::write_fmt(&mut svg, format_args!("{},{} ", first_x + i * STEP, y));
+    }
+    let _ = svg.push_str("'/></svg>");
+    let _ = out.write_all(svg.as_bytes()).await;
 }
 
-fn format_size(bytes: u32) -> heapless::String<16> {
-    let mut result = heapless::String::new();
+#[cfg(not(feature = "sd-only"))]
+async fn render_index<W: Write>(out: &mut W, page: &IndexPage<'_>) {
+    let file_count = page.files.len();
 
-    if bytes < 1024 {
-        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} B", bytes));
-    } else if bytes < 1024 * 1024 {
-        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} KB", bytes / 1024));
+    // HTML content
+    let _ = out.write_all(b"<!DOCTYPE html>\n").await;
+    let _ = out.write_all(b"<html lang='").await;
+    let _ = out.write_all(page.lang.code().as_bytes()).await;
+    let _ = out.write_all(b"'>\n<head>\n").await;
+    let _ = out.write_all(b"<title>").await;
+    if page.device_name.is_empty() {
+        let _ = out.write_all(b"Pico 2W SD Card Browser").await;
     } else {
-        let _ = core::fmt::Write::write_fmt(&mut result, format_args!("{} MB", bytes / (1024 * 1024)));
+        write_html_escaped(out, page.device_name).await;
+    }
+    let _ = out.write_all(b"</title>\n").await;
+    let _ = out.write_all(b"<meta name='viewport' content='width=device-width, initial-scale=1'>\n").await;
+    if !page.static_page {
+        if page.refresh_secs > 0 && !page.select {
+            let mut meta = heapless::String::<64>::new();
+            let _ = core::fmt::Write::write_fmt(
+                &mut meta,
+                format_args!("<meta http-equiv='refresh' content='{}'>\n", page.refresh_secs),
+            );
+            let _ = out.write_all(meta.as_bytes()).await;
+        }
+        let _ = out.write_all(b"<link rel='icon' href='/favicon.ico'>\n").await;
+        #[cfg(feature = "inline-css")]
+        {
+            let _ = out.write_all(b"<style>\n").await;
+            let _ = out.write_all(STYLESHEET).await;
+            let _ = out.write_all(b"</style>\n").await;
+        }
+        #[cfg(not(feature = "inline-css"))]
+        let _ = out.write_all(b"<link rel='stylesheet' href='/style.css'>\n").await;
+    }
+    let _ = out.write_all(b"</head>\n<body>\n").await;
+    let _ = out.write_all(b"<div class='container'>\n").await;
+    let _ = out.write_all(b"<h1>\xF0\x9F\x97\x82\xEF\xB8\x8F ").await;
+    if page.device_name.is_empty() {
+        let _ = out.write_all(page.text(Text::Heading).as_bytes()).await;
+    } else {
+        write_html_escaped(out, page.device_name).await;
+    }
+    let _ = out.write_all(b"</h1>\n").await;
+    let _ = out.write_all(b"<p>Running on <strong>Raspberry Pi Pico 2W</strong> (RP2350)</p>\n").await;
+    let _ = out.write_all(b"<div class='status'>\n").await;
+    let _ = out.write_all(b"<strong>\xE2\x9C\x85 ").await;
+    let _ = out.write_all(page.text(Text::WifiActive).as_bytes()).await;
+    let _ = out.write_all(b"</strong> ").await;
+    write_html_escaped(out, page.ssid).await;
+    if page.ssid_hidden {
+        let _ = out.write_all(b" (").await;
+        let _ = out.write_all(page.text(Text::HiddenSsid).as_bytes()).await;
+        let _ = out.write_all(b")").await;
     }
+    let mut address_str = heapless::String::<16>::new();
+    let _ = core::fmt::Write::write_fmt(&mut address_str, format_args!("{}", AP_ADDRESS));
+    let _ = out.write_all(b"<br><strong>\xE2\x9C\x85 ").await;
+    let _ = out.write_all(page.text(Text::IpAddress).as_bytes()).await;
+    let _ = out.write_all(b"</strong> ").await;
+    let _ = out.write_all(address_str.as_bytes()).await;
+    let _ = out.write_all(b"\n").await;
+    let _ = out.write_all(b"<br><strong>\xE2\x9C\x85 ").await;
+    let _ = out.write_all(page.text(Text::WebServer).as_bytes()).await;
+    let _ = out.write_all(b"</strong> ").await;
+    let _ = out.write_all(page.text(Text::RunningOnPort).as_bytes()).await;
+    let _ = out.write_all(b"\n").await;
+    let _ = out.write_all(b"</div>\n").await;
 
-    result
-}
+    let _ = out.write_all(b"<h2>").await;
+    let _ = out.write_all(page.text(Text::FilesOnCard).as_bytes()).await;
+    let _ = out.write_all(b"</h2>\n").await;
 
-#[embassy_executor::task]
-async fn http_server_task(stack: &'static Stack<'static>) {
-    info!("HTTP server task started");
-    Timer::after(Duration::from_millis(500)).await;
-    info!("Starting HTTP server on 192.168.4.1:80");
+    if page.has_index {
+        let _ = out.write_all(b"<p>This card contains ").await;
+        let _ = out.write_all(INDEX_FILE.as_bytes()).await;
+        let _ = out.write_all(b" - <a href='/'>view the site</a></p>\n").await;
+    }
 
-    let mut rx_buffer = [0; 8192];
-    let mut tx_buffer = [0; 8192];
-    let mut request_count = 0u32;
+    if file_count == 0 {
+        let _ = out.write_all(b"<div class='hw-info'>\n").await;
+        let _ = out.write_all(b"<strong>\xE2\x9A\xA0\xEF\xB8\x8F ").await;
+        let _ = out.write_all(page.text(Text::Status).as_bytes()).await;
+        let _ = out.write_all(b"</strong> ").await;
+        let _ = out.write_all(page.status.as_bytes()).await;
+        let _ = out.write_all(b"</div>\n").await;
+        let _ = out.write_all(b"<p style='color:#999'>").await;
+        let _ = out.write_all(page.text(Text::NoFilesFound).as_bytes()).await;
+        let _ = out.write_all(b"</p>\n").await;
+        let _ = out.write_all(b"<ul style='color:#999'>\n").await;
+        for hint in [Text::ProperlyInserted, Text::FormattedFat32, Text::CorrectPins] {
+            let _ = out.write_all(b"<li>").await;
+            let _ = out.write_all(page.text(hint).as_bytes()).await;
+            let _ = out.write_all(b"</li>\n").await;
+        }
+        let _ = out.write_all(b"</ul>\n").await;
+    } else {
+        let _ = out.write_all(b"<div style='background:#e8f5e9;padding:10px;border-radius:5px;margin-bottom:15px'>\n").await;
+        let _ = out.write_all(b"<strong>\xE2\x9C\x85 ").await;
+        let _ = out.write_all(page.text(Text::CardStatus).as_bytes()).await;
+        let _ = out.write_all(b"</strong> ").await;
+        let _ = out.write_all(page.status.as_bytes()).await;
+        let _ = out.write_all(b" | <strong>").await;
+        let _ = out.write_all(page.text(Text::FilesFound).as_bytes()).await;
+        let _ = out.write_all(b"</strong> ").await;
 
-    loop {
-        let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
-        socket.set_timeout(Some(Duration::from_secs(30)));
+        let mut count_str = heapless::String::<8>::new();
+        let _ = core::fmt::Write::write_fmt(&mut count_str, format_args!("{}", file_count));
+        let _ = out.write_all(count_str.as_bytes()).await;
+        if !page.static_page {
+            render_sparkline(out, &page.count_history).await;
+        }
+        let total_size = page.files.iter().filter(|f| !f.is_dir).map(|f| f.size).sum::<u64>();
+        let _ = out.write_all(b" | <strong>").await;
+        let _ = out.write_all(page.text(Text::TotalSize).as_bytes()).await;
+        let _ = out.write_all(b"</strong> ").await;
+        let _ = out.write_all(format_size(total_size).as_bytes()).await;
+        if page.write_protected {
+            let _ = out.write_all(b" | \xF0\x9F\x94\x92 ").await; // 🔒
+            let _ = out.write_all(page.text(Text::WriteProtected).as_bytes()).await;
+        }
+        let _ = out.write_all(b"</div>\n").await;
 
-        info!(
-            "Listening on TCP:80... (requests served: {})",
-            request_count
-        );
-        if let Err(e) = socket.accept(80).await {
-            warn!("Accept error: {:?}", e);
-            Timer::after(Duration::from_millis(100)).await;
-            continue;
+        if page.truncated {
+            let mut banner = heapless::String::<64>::new();
+            let _ = core::fmt::Write::write_fmt(
+                &mut banner,
+                format_args!("Showing only the first {} entries", MAX_FILES),
+            );
+            let _ = out.write_all(b"<div class='hw-info'>\n<strong>\xE2\x9A\xA0\xEF\xB8\x8F ").await; // ⚠️
+            let _ = out.write_all(banner.as_bytes()).await;
+            let _ = out.write_all(b"</strong> - the root folder has more. Move files into subfolders to keep the listing complete; ").await;
+            let _ = out.write_all(b"unlisted files can still be opened with <code>/view?name=</code> or <code>/download?name=</code>.\n</div>\n").await;
         }
 
-        info!("Received connection from {:?}", socket.remote_endpoint());
-        request_count += 1;
+        if !page.select {
+            render_recent(out, page).await;
+        }
 
-        match handle_client(&mut socket).await {
-            Ok(_) => info!("Request #{} completed successfully", request_count),
-            Err(e) => warn!("Request #{} failed: {:?}", request_count, e),
+        let _ = out.write_all(b"<p><strong>").await;
+        let _ = out.write_all(page.text(Text::Show).as_bytes()).await;
+        let _ = out.write_all(b"</strong> ").await;
+        for (filter, label) in [
+            (ShowFilter::All, page.text(Text::All)),
+            (ShowFilter::Files, page.text(Text::Files)),
+            (ShowFilter::Dirs, page.text(Text::Folders)),
+        ] {
+            if filter == page.show {
+                let _ = out.write_all(b"<strong>").await;
+                let _ = out.write_all(label.as_bytes()).await;
+                let _ = out.write_all(b"</strong> ").await;
+            } else {
+                page.write_link(out, filter, page.show_hidden, page.view, label).await;
+            }
+        }
+        let _ = out.write_all(b"| ").await;
+        if page.show_hidden {
+            page.write_link(out, page.show, false, page.view, page.text(Text::HideHidden)).await;
+        } else {
+            page.write_link(out, page.show, true, page.view, page.text(Text::ShowHidden)).await;
+        }
+        let _ = out.write_all(b"| ").await;
+        match page.view {
+            ListingView::List => {
+                page.write_link(out, page.show, page.show_hidden, ListingView::Grid, page.text(Text::GridView)).await
+            }
+            ListingView::Grid => {
+                page.write_link(out, page.show, page.show_hidden, ListingView::List, page.text(Text::ListView)).await
+            }
+        }
+        if !page.static_page {
+            if page.select {
+                let _ = out.write_all(b"| <a href='/'>").await;
+                let _ = out.write_all(page.text(Text::CancelSelection).as_bytes()).await;
+            } else {
+                let _ = out.write_all(b"| <a href='/?select=1'>").await;
+                let _ = out.write_all(page.text(Text::SelectFiles).as_bytes()).await;
+            }
+            let _ = out.write_all(b"</a>").await;
         }
+        let _ = out.write_all(b"</p>\n").await;
 
-        socket.abort();
-        Timer::after(Duration::from_millis(50)).await;
-    }
-}
+        if page.select {
+            let _ = out.write_all(b"<form method='post' action='/download-selected'>\n").await;
+        }
+        match page.view {
+            ListingView::List => {
+                let _ = out.write_all(b"<ul>\n").await;
+            }
+            ListingView::Grid => {
+                let _ = out.write_all(b"<ul class='grid'>\n").await;
+            }
+        }
 
-async fn handle_client(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
-    let mut buf = [0; 2048];
+        let mut shown = 0;
+        for file_info in page.files.iter().filter(|f| page.is_listed(f)) {
+            shown += 1;
+            let _ = out.write_all(b"<li>").await;
+            if page.select && !file_info.is_dir {
+                let _ = out.write_all(b"<input type='checkbox' name='name' value='").await;
+                write_html_escaped(out, &file_info.name).await;
+                let _ = out.write_all(b"'> ").await;
+            }
 
-    // Read request with timeout
-    let n = match embassy_time::with_timeout(Duration::from_secs(5), socket.read(&mut buf)).await {
-        Ok(Ok(n)) => n,
-        Ok(Err(e)) => {
-            warn!("Read error: {:?}", e);
-            return Err(e);
+            // Grid cards are built around the icon or thumbnail, so they always get one
+            let grid_icon = page.view == ListingView::Grid && !page.cols.contains(&Column::Icon);
+            let columns = grid_icon.then_some(Column::Icon).into_iter().chain(page.cols.iter().copied());
+            for (i, column) in columns.enumerate() {
+                if i > 0 {
+                    let _ = out.write_all(b" ").await;
+                }
+                render_column(out, page, file_info, column).await;
+            }
+            let _ = out.write_all(b"</li>\n").await;
         }
-        Err(_) => {
-            warn!("Read timeout");
-            return Ok(());
+
+        let _ = out.write_all(b"</ul>\n").await;
+        if page.select {
+            let _ = out.write_all(b"<button type='submit'>").await;
+            let _ = out.write_all(page.text(Text::DownloadSelected).as_bytes()).await;
+            let _ = out.write_all(b"</button>\n</form>\n").await;
         }
-    };
 
-    if n == 0 {
-        info!("Empty request, closing");
-        return Ok(());
+        if shown == 0 {
+            let _ = out.write_all(b"<p style='color:#999'>").await;
+            let _ = out.write_all(page.text(Text::NoMatches).as_bytes()).await;
+            let _ = out.write_all(b"</p>\n").await;
+        }
     }
 
-    let request = core::str::from_utf8(&buf[..n]).unwrap_or("");
-    info!("HTTP Request ({} bytes)", n);
-
-    // Parse HTTP request
-    if let Some(first_line) = request.lines().next() {
-        let parts: heapless::Vec<&str, 3> = first_line.split_whitespace().collect();
-        if parts.len() >= 2 {
-            let method = parts[0];
-            let path = parts[1];
-            info!("Method: {}, Path: {}", method, path);
-
-            // Get SD card status and file list
-            let sd_status = SD_STATUS.lock().await;
-            let files = SD_FILES.lock().await;
-            let file_count = files.len();
-            let status_str = *sd_status;
-            drop(sd_status);
-
-            // Send HTTP response
-            let _ = socket.write_all(b"HTTP/1.1 200 OK\r\n").await;
-            let _ = socket.write_all(b"Content-Type: text/html; charset=utf-8\r\n").await;
-            let _ = socket.write_all(b"Connection: close\r\n").await;
-            let _ = socket.write_all(b"\r\n").await;
-
-            // HTML content
-            let _ = socket.write_all(b"<!DOCTYPE html>\n").await;
-            let _ = socket.write_all(b"<html>\n<head>\n").await;
-            let _ = socket.write_all(b"<title>Pico 2W SD Card Browser</title>\n").await;
-            let _ = socket.write_all(b"<meta name='viewport' content='width=device-width, initial-scale=1'>\n").await;
-            let _ = socket.write_all(b"<meta http-equiv='refresh' content='5'>\n").await;
-            let _ = socket.write_all(b"<style>\n").await;
-            let _ = socket.write_all(b"body { font-family: Arial, sans-serif; margin: 20px; background: #f5f5f5; }\n").await;
-            let _ = socket.write_all(b"h1 { color: #333; }\n").await;
-            let _ = socket.write_all(b".container { max-width: 900px; margin: 0 auto; background: white; padding: 30px; border-radius: 10px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); }\n").await;
-            let _ = socket.write_all(b".status { background: #e8f5e9; padding: 15px; border-radius: 5px; margin: 20px 0; border-left: 4px solid #4caf50; }\n").await;
-            let _ = socket.write_all(b"ul { list-style: none; padding: 0; }\n").await;
-            let _ = socket.write_all(b"li { padding: 12px; margin: 8px 0; background: #fafafa; border-radius: 5px; border-left: 3px solid #2196f3; }\n").await;
-            let _ = socket.write_all(b".info { color: #666; font-size: 0.9em; margin-top: 30px; padding-top: 20px; border-top: 2px solid #eee; }\n").await;
-            let _ = socket.write_all(b".hw-info { background: #fff3cd; padding: 10px; border-radius: 5px; margin: 10px 0; }\n").await;
-            let _ = socket.write_all(b"</style>\n</head>\n<body>\n").await;
-            let _ = socket.write_all(b"<div class='container'>\n").await;
-            let _ = socket.write_all(b"<h1>\xF0\x9F\x97\x82\xEF\xB8\x8F SD Card File Browser</h1>\n").await;
-            let _ = socket.write_all(b"<p>Running on <strong>Raspberry Pi Pico 2W</strong> (RP2350)</p>\n").await;
-            let _ = socket.write_all(b"<div class='status'>\n").await;
-            let _ = socket.write_all(b"<strong>\xE2\x9C\x85 WiFi AP Active:</strong> ").await;
-            let _ = socket.write_all(WIFI_SSID.as_bytes()).await;
-            let _ = socket.write_all(b"<br><strong>\xE2\x9C\x85 IP Address:</strong> 192.168.4.1\n").await;
-            let _ = socket.write_all(b"<br><strong>\xE2\x9C\x85 Web Server:</strong> Running on port 80\n").await;
-            let _ = socket.write_all(b"</div>\n").await;
-
-            let _ = socket.write_all(b"<h2>Files on SD Card:</h2>\n").await;
-
-            if file_count == 0 {
-                let _ = socket.write_all(b"<div class='hw-info'>\n").await;
-                let _ = socket.write_all(b"<strong>\xE2\x9A\xA0\xEF\xB8\x8F Status:</strong> ").await;
-                let _ = socket.write_all(status_str.as_bytes()).await;
-                let _ = socket.write_all(b"</div>\n").await;
-                let _ = socket.write_all(b"<p style='color:#999'>No files found. Make sure SD card is:</p>\n").await;
-                let _ = socket.write_all(b"<ul style='color:#999'>\n").await;
-                let _ = socket.write_all(b"<li>Properly inserted</li>\n").await;
-                let _ = socket.write_all(b"<li>Formatted as FAT32</li>\n").await;
-                let _ = socket.write_all(b"<li>Connected to correct SPI pins</li>\n").await;
-                let _ = socket.write_all(b"</ul>\n").await;
-            } else {
-                let _ = socket.write_all(b"<div style='background:#e8f5e9;padding:10px;border-radius:5px;margin-bottom:15px'>\n").await;
-                let _ = socket.write_all(b"<strong>\xE2\x9C\x85 SD Card Status:</strong> ").await;
-                let _ = socket.write_all(status_str.as_bytes()).await;
-                let _ = socket.write_all(b" | <strong>Files found:</strong> ").await;
+    if !page.static_page {
+        render_device_info(out, page).await;
+    }
+    let _ = out.write_all(b"</div>\n</body>\n</html>\r\n").await;
+}
 
-                let mut count_str = heapless::String::<8>::new();
-                let _ = core::fmt::Write::write_fmt(&mut count_str, format_args!("{}", file_count));
-                let _ = socket.write_all(count_str.as_bytes()).await;
-                let _ = socket.write_all(b"</div>\n").await;
+/// The status, hardware and instructions blocks under the listing, plus the footer.
+#[cfg(not(feature = "sd-only"))]
+async fn render_device_info<W: Write>(out: &mut W, page: &IndexPage<'_>) {
+    let file_count = page.files.len();
 
-                let _ = socket.write_all(b"<ul>\n").await;
+    let _ = out.write_all(b"<div class='info'>\n").await;
+    let _ = out.write_all(b"<p><strong>").await;
+    let _ = out.write_all(page.text(Text::CurrentStatus).as_bytes()).await;
+    let _ = out.write_all(b"</strong></p>\n").await;
+    let _ = out.write_all(b"<ul>\n").await;
+    let _ = out.write_all(b"<li>\xE2\x9C\x85 WiFi Access Point: Active</li>\n").await;
+    let _ = out.write_all(b"<li>\xE2\x9C\x85 HTTP Server: Running</li>\n").await;
+    let _ = out.write_all(b"<li>\xE2\x9C\x85 SPI Interface: Initialized</li>\n").await;
 
-                for file_info in files.iter() {
-                    let _ = socket.write_all(b"<li>").await;
+    if file_count > 0 {
+        let _ = out.write_all(b"<li>\xE2\x9C\x85 SD Card Reader: Active</li>\n").await;
+        let mut skipped_str = heapless::String::<48>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut skipped_str,
+            format_args!("<li>Unchanged scans skipped: {}</li>\n", page.scans_skipped),
+        );
+        let _ = out.write_all(skipped_str.as_bytes()).await;
+    } else {
+        let _ = out.write_all(b"<li>\xE2\x9A\xA0\xEF\xB8\x8F SD Card Reader: ").await;
+        let _ = out.write_all(page.status.as_bytes()).await;
+        let _ = out.write_all(b"</li>\n").await;
+    }
+    if let Some(progress) = page.scan_progress {
+        let mut progress_str = heapless::String::<48>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut progress_str,
+            format_args!("<li>Scanning... {} entries read</li>\n", progress),
+        );
+        let _ = out.write_all(progress_str.as_bytes()).await;
+    }
+    let _ = out.write_all(b"</ul>\n").await;
 
-                    if file_info.is_dir {
-                        let _ = socket.write_all(b"\xF0\x9F\x93\x81 ").await; // 📁
-                    } else {
-                        let _ = socket.write_all(b"\xF0\x9F\x93\x84 ").await; // 📄
-                    }
+    let _ = out.write_all(b"<p><strong>").await;
+    let _ = out.write_all(page.text(Text::HardwareConfiguration).as_bytes()).await;
+    let _ = out.write_all(b"</strong></p>\n").await;
+    let _ = out.write_all(b"<ul>\n").await;
+    let mut mcu_str = heapless::String::<80>::new();
+    let _ = core::fmt::Write::write_fmt(
+        &mut mcu_str,
+        format_args!(
+            "<li><strong>MCU:</strong> RP2350A (Dual Cortex-M33 @ {}MHz)</li>\n",
+            POWER_PRESET.sys_clock_mhz()
+        ),
+    );
+    let _ = out.write_all(mcu_str.as_bytes()).await;
+    let _ = out.write_all(b"<li><strong>WiFi:</strong> CYW43439 (2.4GHz 802.11n)</li>\n").await;
+    let _ = out.write_all(b"<li><strong>SD Card SPI:</strong> SCK=GP18, MOSI=GP19, MISO=GP16, CS=GP17</li>\n").await;
+    if let Some(boot) = page.volume {
+        let mut fs_str = heapless::String::<96>::new();
+        let cluster_bytes = boot.cluster_bytes();
+        let _ = core::fmt::Write::write_fmt(
+            &mut fs_str,
+            format_args!("<li><strong>Filesystem:</strong> {}, ", boot.fat_type().as_str()),
+        );
+        let _ = if cluster_bytes >= 1024 {
+            core::fmt::Write::write_fmt(&mut fs_str, format_args!("{} KB clusters", cluster_bytes / 1024))
+        } else {
+            core::fmt::Write::write_fmt(&mut fs_str, format_args!("{} byte clusters", cluster_bytes))
+        };
+        let _ = core::fmt::Write::write_fmt(
+            &mut fs_str,
+            format_args!(" ({}-byte sectors)</li>\n", boot.bytes_per_sector),
+        );
+        let _ = out.write_all(fs_str.as_bytes()).await;
+    }
+    let _ = out.write_all(b"</ul>\n").await;
 
-                    let _ = socket.write_all(file_info.name.as_bytes()).await;
-                    let _ = socket.write_all(b" <span style='color:#999'>(").await;
+    let _ = out.write_all(b"<p style='color:#666;font-size:0.85em;margin-top:20px'>\n").await;
+    let _ = out.write_all(b"<strong>").await;
+    let _ = out.write_all(page.text(Text::Instructions).as_bytes()).await;
+    let _ = out.write_all(b"</strong><br>\n").await;
+    let _ = out.write_all(b"1. Connect SD card module: CS->GP17, SCK->GP18, MOSI->GP19, MISO->GP16, VCC->3.3V, GND->GND<br>\n").await;
+    let _ = out.write_all(b"2. Format SD card as FAT32<br>\n").await;
+    let _ = out.write_all(b"3. Add files to SD card<br>\n").await;
+    let _ = out.write_all(b"4. Files will be listed here when SD reading is implemented<br>\n").await;
+    let _ = out.write_all(b"</p>\n").await;
+    let _ = out.write_all(b"</div>\n").await;
 
-                    if file_info.is_dir {
-                        let _ = socket.write_all(b"directory").await;
-                    } else {
-                        let size_str = format_size(file_info.size);
-                        let _ = socket.write_all(size_str.as_bytes()).await;
-                    }
+    let _ = out.write_all(b"<p style='text-align:center;color:#999;font-size:0.8em;margin-top:30px'>\n").await;
+    let _ = out.write_all(b"LT7689").await;
+    if page.refresh_secs > 0 {
+        let mut refresh = heapless::String::<96>::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut refresh,
+            format_args!(
+                " - {} {} {}",
+                page.text(Text::AutoRefresh),
+                page.refresh_secs,
+                page.text(Text::Seconds)
+            ),
+        );
+        let _ = out.write_all(refresh.as_bytes()).await;
+    }
+    let _ = out.write_all(b"\n").await;
+    let _ = out.write_all(b"</p>\n").await;
+}
 
-                    let _ = socket.write_all(b")</span></li>\n").await;
-                }
+// A stalled firmware load leaves the cyw43 control calls waiting forever
+#[cfg(not(feature = "sd-only"))]
+const CYW43_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(not(feature = "sd-only"))]
+const CYW43_INIT_ATTEMPTS: u32 = 3;
 
-                let _ = socket.write_all(b"</ul>\n").await;
+/// Runs a cyw43 init call with a timeout, retrying a few times before resetting the
+/// board through the watchdog, so a stuck radio reboots instead of bricking silently.
+#[cfg(not(feature = "sd-only"))]
+macro_rules! cyw43_init_step {
+    ($watchdog:expr, $step:expr, $call:expr) => {{
+        let mut attempt = 1;
+        while embassy_time::with_timeout(CYW43_INIT_TIMEOUT, $call).await.is_err() {
+            if attempt == CYW43_INIT_ATTEMPTS {
+                error!("{} timed out {} times, resetting", $step, attempt);
+                Timer::after(Duration::from_millis(100)).await;
+                $watchdog.trigger_reset();
+                loop {
+                    cortex_m::asm::nop();
+                }
             }
+            error!("{} timed out (attempt {}/{}), retrying", $step, attempt, CYW43_INIT_ATTEMPTS);
+            attempt += 1;
+        }
+    }};
+}
 
-            let _ = socket.write_all(b"<div class='info'>\n").await;
-            let _ = socket.write_all(b"<p><strong>Current Status:</strong></p>\n").await;
-            let _ = socket.write_all(b"<ul>\n").await;
-            let _ = socket.write_all(b"<li>\xE2\x9C\x85 WiFi Access Point: Active</li>\n").await;
-            let _ = socket.write_all(b"<li>\xE2\x9C\x85 HTTP Server: Running</li>\n").await;
-            let _ = socket.write_all(b"<li>\xE2\x9C\x85 SPI Interface: Initialized</li>\n").await;
-
-            if file_count > 0 {
-                let _ = socket.write_all(b"<li>\xE2\x9C\x85 SD Card Reader: Active</li>\n").await;
-            } else {
-                let _ = socket.write_all(b"<li>\xE2\x9A\xA0\xEF\xB8\x8F SD Card Reader: ").await;
-                let _ = socket.write_all(status_str.as_bytes()).await;
-                let _ = socket.write_all(b"</li>\n").await;
-            }
-            let _ = socket.write_all(b"</ul>\n").await;
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    #[cfg(not(feature = "sd-only"))]
+    stack::paint();
+    info!("Starting LT7689 - Pico 2W SD Card Browser");
+    let p = embassy_rp::init(POWER_PRESET.rp_config());
+    let watchdog = embassy_rp::watchdog::Watchdog::new(p.WATCHDOG);
+    info!("System clock: {} MHz", POWER_PRESET.sys_clock_mhz());
 
-            let _ = socket.write_all(b"<p><strong>Hardware Configuration:</strong></p>\n").await;
-            let _ = socket.write_all(b"<ul>\n").await;
-            let _ = socket.write_all(b"<li><strong>MCU:</strong> RP2350A (Dual Cortex-M33 @ 150MHz)</li>\n").await;
-            let _ = socket.write_all(b"<li><strong>WiFi:</strong> CYW43439 (2.4GHz 802.11n)</li>\n").await;
-            let _ = socket.write_all(b"<li><strong>SD Card SPI:</strong> SCK=GP18, MOSI=GP19, MISO=GP16, CS=GP17</li>\n").await;
-            let _ = socket.write_all(b"</ul>\n").await;
+    #[cfg(feature = "sd-only")]
+    run_sd_only(spawner, p).await;
+    #[cfg(not(feature = "sd-only"))]
+    run_access_point(spawner, p, watchdog).await;
+}
 
-            let _ = socket.write_all(b"<p style='color:#666;font-size:0.85em;margin-top:20px'>\n").await;
-            let _ = socket.write_all(b"<strong>Instructions:</strong><br>\n").await;
-            let _ = socket.write_all(b"1. Connect SD card module: CS->GP17, SCK->GP18, MOSI->GP19, MISO->GP16, VCC->3.3V, GND->GND<br>\n").await;
-            let _ = socket.write_all(b"2. Format SD card as FAT32<br>\n").await;
-            let _ = socket.write_all(b"3. Add files to SD card<br>\n").await;
-            let _ = socket.write_all(b"4. Files will be listed here when SD reading is implemented<br>\n").await;
-            let _ = socket.write_all(b"</p>\n").await;
-            let _ = socket.write_all(b"</div>\n").await;
+/// Logs the listing over RTT after each scan, with the radio left off. For checking card
+/// handling on boards or builds without the CYW43 firmware blobs.
+#[cfg(feature = "sd-only")]
+async fn run_sd_only(spawner: Spawner, p: embassy_rp::Peripherals) {
+    warn!("Built with sd-only: WiFi and the web server are disabled");
+    spawner.spawn(sd_card_task().unwrap());
 
-            let _ = socket.write_all(b"<p style='text-align:center;color:#999;font-size:0.8em;margin-top:30px'>\n").await;
-            let _ = socket.write_all(b"LT7689 - Page auto-refreshes every 5 seconds\n").await;
-            let _ = socket.write_all(b"</p>\n").await;
-            let _ = socket.write_all(b"</div>\n</body>\n</html>\r\n").await;
+    #[cfg(feature = "sd-write-protect")]
+    spawner.spawn(write_protect_task(embassy_rp::gpio::Input::new(p.PIN_20, embassy_rp::gpio::Pull::Up)).unwrap());
+    #[cfg(not(feature = "sd-write-protect"))]
+    let _ = p;
 
-            info!("Response sent successfully");
+    // The scanner already logs errors; only list the files when they've changed
+    let mut last_signature = None;
+    loop {
+        if SCAN_DONE.wait().await.is_err() {
+            last_signature = None;
+            continue;
+        }
+        let files = SD_FILES.lock().await;
+        let signature = Some(ScanSignature::of(&files));
+        if signature == last_signature {
+            continue;
+        }
+        last_signature = signature;
+        info!("SD card listing: {} entries", files.len());
+        for file in files.iter() {
+            info!("  {} {} bytes{}", file.name.as_str(), file.size, if file.is_dir { " (dir)" } else { "" });
         }
     }
-
-    Timer::after(Duration::from_millis(100)).await;
-    Ok(())
 }
 
-#[embassy_executor::main]
-async fn main(spawner: Spawner) {
-    info!("Starting LT7689 - Pico 2W SD Card Browser");
-    let p = embassy_rp::init(Default::default());
-
-    // Initialize WiFi firmware blobs
+#[cfg(not(feature = "sd-only"))]
+async fn run_access_point(spawner: Spawner, p: embassy_rp::Peripherals, mut watchdog: embassy_rp::watchdog::Watchdog) {
+    // Initialize WiFi firmware blobs (build.rs checks they're there)
     let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
     let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
 
@@ -441,10 +5788,12 @@ async fn main(spawner: Spawner) {
     let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
     spawner.spawn(cyw43_task(runner).unwrap());
 
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::Performance)
-        .await;
+    cyw43_init_step!(watchdog, "CYW43 CLM load", control.init(clm));
+    cyw43_init_step!(
+        watchdog,
+        "CYW43 power management",
+        control.set_power_management(cyw43::PowerManagementMode::Performance)
+    );
 
     info!("CYW43 initialized successfully");
 
@@ -454,8 +5803,8 @@ async fn main(spawner: Spawner) {
     // Configure network stack for AP mode with static IP
     info!("Configuring network stack...");
     let config = Config::ipv4_static(embassy_net::StaticConfigV4 {
-        address: embassy_net::Ipv4Cidr::new(embassy_net::Ipv4Address::new(192, 168, 4, 1), 24),
-        gateway: Some(embassy_net::Ipv4Address::new(192, 168, 4, 1)),
+        address: embassy_net::Ipv4Cidr::new(AP_ADDRESS, AP_PREFIX_LEN),
+        gateway: Some(AP_GATEWAY),
         dns_servers: heapless::Vec::new(),
     });
 
@@ -469,18 +5818,60 @@ async fn main(spawner: Spawner) {
         RESOURCES.init(StackResources::<16>::new()),
         seed,
     );
-    let stack = STACK.init(stack);
+    let stack: &'static Stack<'static> = STACK.init(stack);
 
     spawner.spawn(net_task(runner).unwrap());
 
+    // Built-in settings, overridden by WIFI.CFG on the card if there is one
+    let defaults = default_wifi_config();
+    let wifi = match load_wifi_config(defaults.clone()).await {
+        Ok(Some(wifi)) => {
+            info!("Loaded WiFi settings from {}", wifi_config::FILE_NAME);
+            wifi
+        }
+        Ok(None) => {
+            info!("No {} on the card, offering first-run setup", wifi_config::FILE_NAME);
+            NEEDS_SETUP.store(true, Ordering::Relaxed);
+            defaults
+        }
+        Err(e) => {
+            warn!("Using built-in WiFi settings: {}", e);
+            defaults
+        }
+    };
+
     // Start WiFi AP
     info!("Starting WiFi Access Point...");
-    info!("SSID: {}, Password: {}", WIFI_SSID, WIFI_PASSWORD);
+    let password = if is_valid_wpa2_password(&wifi.password) {
+        wifi.password.as_str()
+    } else {
+        error!(
+            "WiFi password must be 8-63 printable ASCII characters or 64 hex digits (got {} bytes), using the built-in one",
+            wifi.password.len()
+        );
+        WIFI_PASSWORD
+    };
+    apply_runtime_settings(&wifi);
+    // The radio came up in performance mode; the signal from applying is handled here
+    if POWER_MODE_CHANGE.try_take().is_some() {
+        control.set_power_management(cyw43_power_mode(wifi.power_mode)).await;
+    }
+    let ssid = wifi.ssid.as_str();
+    info!("SSID: {}, Password: {}, Channel: {}", ssid, password, wifi.channel);
 
-    control.start_ap_wpa2(WIFI_SSID, WIFI_PASSWORD, 5).await;
+    let _ = AP_SSID.lock().await.push_str(ssid);
+    *DEVICE_NAME.lock().await = wifi.name.clone();
+    *UPLOAD_TYPES.lock().await = wifi.upload_types.clone();
+    SD_REQUIRED.store(wifi.sd_required, Ordering::Relaxed);
+    cyw43_init_step!(watchdog, "WiFi AP start", control.start_ap_wpa2(ssid, password, wifi.channel));
+    if wifi.hidden {
+        hide_ssid(&mut control).await;
+        SSID_HIDDEN.store(true, Ordering::Relaxed);
+        info!("SSID hidden from beacons");
+    }
     info!("WiFi AP started successfully!");
-    info!("Connect to WiFi: {}", WIFI_SSID);
-    info!("Then browse to: http://192.168.4.1");
+    info!("Connect to WiFi: {}", ssid);
+    info!("Then browse to: http://{}", AP_ADDRESS);
 
     // Wait for network stack to be ready
     Timer::after(Duration::from_secs(2)).await;
@@ -491,17 +5882,75 @@ async fn main(spawner: Spawner) {
     spawner.spawn(sd_card_task().unwrap());
     info!("SD card scanner task spawned");
 
+    #[cfg(feature = "sd-write-protect")]
+    spawner.spawn(write_protect_task(embassy_rp::gpio::Input::new(p.PIN_20, embassy_rp::gpio::Pull::Up)).unwrap());
+
     // Spawn HTTP server
     info!("Starting HTTP server task...");
-    spawner.spawn(http_server_task(stack).unwrap());
+    spawn_http_servers(&spawner, &[stack]);
+    spawner.spawn(stream_server_task(stack).unwrap());
     info!("HTTP server task spawned successfully");
 
     // Blink LED to indicate system is running
     info!("System ready! LED blinking to indicate AP is active.");
+    let ap_restart_after = Duration::from_secs(wifi.ap_restart_hours as u64 * 3600);
+    let mut ap_started_at = Instant::now();
     loop {
-        control.gpio_set(0, true).await;
-        Timer::after(Duration::from_millis(100)).await;
+        // With the heartbeat paused the LED just stays off
+        if HEARTBEAT.load(Ordering::Relaxed) {
+            control.gpio_set(0, true).await;
+            Timer::after(Duration::from_millis(100)).await;
+        }
         control.gpio_set(0, false).await;
-        Timer::after(Duration::from_millis(900)).await;
+        // Scans, AP restarts and power mode changes need the control handle, so they're run
+        // here between blinks
+        match select4(
+            Timer::after(Duration::from_millis(900)),
+            WIFI_SCAN_REQUEST.wait(),
+            AP_SSID_CHANGE.wait(),
+            POWER_MODE_CHANGE.wait(),
+        )
+        .await
+        {
+            Either4::First(()) => {
+                // Count quiet time from the later of the last connection and the last AP
+                // start, so a restart gets a full window to bring clients back
+                let last_request = Instant::from_secs(LAST_REQUEST_SECS.load(Ordering::Relaxed) as u64);
+                let quiet_since = ap_started_at.max(last_request);
+                if wifi.ap_restart_hours > 0 && quiet_since.elapsed() >= ap_restart_after {
+                    let ssid = AP_SSID.lock().await.clone();
+                    warn!(
+                        "No connection for {} h, restarting the WiFi AP",
+                        wifi.ap_restart_hours
+                    );
+                    control.close_ap().await;
+                    control.start_ap_wpa2(&ssid, password, wifi.channel).await;
+                    if wifi.hidden {
+                        hide_ssid(&mut control).await;
+                    }
+                    ap_started_at = Instant::now();
+                    AP_RESTARTS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Either4::Second(()) => {
+                WIFI_SCAN_DONE.signal(scan_networks(&mut control).await);
+                log_info!("WiFi scan finished");
+            }
+            Either4::Third(ssid) => {
+                // Let the handler's reply reach the client before the radio drops it
+                Timer::after(Duration::from_millis(500)).await;
+                control.close_ap().await;
+                control.start_ap_wpa2(&ssid, password, wifi.channel).await;
+                if wifi.hidden {
+                    hide_ssid(&mut control).await;
+                }
+                ap_started_at = Instant::now();
+                info!("WiFi AP restarted as {}", ssid.as_str());
+            }
+            Either4::Fourth(mode) => {
+                control.set_power_management(cyw43_power_mode(mode)).await;
+                info!("Radio power mode set to {}", mode.name());
+            }
+        }
     }
 }
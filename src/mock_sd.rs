@@ -0,0 +1,98 @@
+//! A made-up card for the `mock-sd` feature, for working on the web UI on a board with no
+//! SD card wired up.
+//!
+//! The scanner lists these entries instead of reading the card, and the listing, `/view`,
+//! `/download`, the archives, `/download-dir` and the JSON APIs serve them from flash.
+//! Writes are refused, and the debug routes, which walk the card themselves, still report
+//! it missing.
+
+use crate::store::MemoryFile;
+
+/// Big enough that `/view` has to page through it.
+const PATTERN_LEN: usize = 40 * 1024;
+
+static PATTERN: [u8; PATTERN_LEN] = pattern();
+
+/// Every byte value in turn, so the hex dump and range requests are easy to check.
+const fn pattern() -> [u8; PATTERN_LEN] {
+    let mut data = [0u8; PATTERN_LEN];
+    let mut i = 0;
+    while i < PATTERN_LEN {
+        data[i] = (i % 256) as u8;
+        i += 1;
+    }
+    data
+}
+
+pub static FILES: &[MemoryFile] = &[
+    MemoryFile {
+        name: "README.TXT",
+        data: b"This board was built with the mock-sd feature.\nThese files live in flash; no SD card is read.\n",
+        is_dir: false,
+        fat_date: (45 << 9) | (1 << 5) | 15,
+        fat_time: (9 << 11) | (30 << 5),
+    },
+    MemoryFile {
+        name: "DATA.CSV",
+        data: b"time,temperature,humidity\n08:00,19.5,48\n09:00,20.1,47\n10:00,21.4,45\n",
+        is_dir: false,
+        fat_date: (45 << 9) | (3 << 5) | 2,
+        fat_time: (10 << 11) | (5 << 5),
+    },
+    MemoryFile {
+        name: "NOTES.MD",
+        data: "# Notes\n\n- Non-ASCII text: caf\u{e9}, \u{fc}ber, \u{2713}\n".as_bytes(),
+        is_dir: false,
+        fat_date: (45 << 9) | (3 << 5) | 9,
+        fat_time: (16 << 11) | (42 << 5) | 10,
+    },
+    MemoryFile {
+        name: "EMPTY.TXT",
+        data: b"",
+        is_dir: false,
+        fat_date: (45 << 9) | (3 << 5) | 9,
+        fat_time: (16 << 11) | (43 << 5),
+    },
+    MemoryFile {
+        name: "PATTERN.BIN",
+        data: &PATTERN,
+        is_dir: false,
+        fat_date: (44 << 9) | (12 << 5) | 31,
+        fat_time: (23 << 11) | (59 << 5) | 29,
+    },
+    MemoryFile {
+        name: "LOGS",
+        data: b"",
+        is_dir: true,
+        fat_date: (45 << 9) | (1 << 5) | 1,
+        fat_time: 0,
+    },
+    MemoryFile {
+        name: "LOGS/BOOT.LOG",
+        data: b"boot ok\nsd: mock card, 6 entries\n",
+        is_dir: false,
+        fat_date: (45 << 9) | (3 << 5) | 9,
+        fat_time: (8 << 11) | (1 << 5),
+    },
+    MemoryFile {
+        name: "LOGS/ERRORS.LOG",
+        data: b"",
+        is_dir: false,
+        fat_date: (45 << 9) | (3 << 5) | 9,
+        fat_time: (8 << 11) | (1 << 5),
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::store::{FileStore, FileWindow, MemoryStore};
+
+    #[test]
+    fn empty_file_reads_as_nothing() {
+        let store = MemoryStore { files: super::FILES };
+        let mut buf = [0xaa; 16];
+        assert_eq!(store.read("EMPTY.TXT", FileWindow::From(0), &mut buf), Ok((0, 0, 0)));
+        assert_eq!(store.read("empty.txt", FileWindow::Tail, &mut buf), Ok((0, 0, 0)));
+        assert_eq!(store.file("EMPTY.TXT"), Ok(&b""[..]));
+    }
+}
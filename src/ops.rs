@@ -0,0 +1,91 @@
+//! Long-running transfers (file downloads, archives, multipart folders), registered while
+//! they run so `GET /api/ops` can list them and `POST /api/ops/ID/cancel` can stop one
+//! from another connection.
+//!
+//! A transfer checks its cancel flag between blocks and stops as if the client had gone
+//! away. The table is small: a transfer started while it's full still runs, it just can't
+//! be listed or cancelled.
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+
+use crate::SharedRawMutex;
+
+/// Most operations tracked at once.
+pub const MAX_OPS: usize = 8;
+
+/// One row of the table, as listed by `GET /api/ops`.
+#[derive(Clone)]
+pub struct OpInfo {
+    pub id: u32,
+    pub kind: &'static str,
+    pub name: String<64>,
+    pub started: Instant,
+    /// File bytes streamed so far, before any compression
+    pub bytes: u64,
+    pub cancelled: bool,
+}
+
+// A blocking mutex, since `Op` deregisters in `Drop`. It's only held to update a row.
+static TABLE: Mutex<SharedRawMutex, RefCell<Vec<OpInfo, MAX_OPS>>> = Mutex::new(RefCell::new(Vec::new()));
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+/// A registered operation, removed from the table when dropped.
+pub struct Op {
+    // 0 if the table was full, which matches no row
+    id: u32,
+}
+
+impl Op {
+    pub fn start(kind: &'static str, name: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let info = OpInfo {
+            id,
+            kind,
+            name: crate::text::format_truncated(format_args!("{}", name)),
+            started: Instant::now(),
+            bytes: 0,
+            cancelled: false,
+        };
+        let registered = TABLE.lock(|table| table.borrow_mut().push(info).is_ok());
+        Op { id: if registered { id } else { 0 } }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.with(|op| op.cancelled).unwrap_or(false)
+    }
+
+    pub fn add_bytes(&self, len: usize) {
+        self.with(|op| op.bytes += len as u64);
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut OpInfo) -> R) -> Option<R> {
+        TABLE.lock(|table| table.borrow_mut().iter_mut().find(|op| op.id == self.id).map(f))
+    }
+}
+
+impl Drop for Op {
+    fn drop(&mut self) {
+        TABLE.lock(|table| table.borrow_mut().retain(|op| op.id != self.id));
+    }
+}
+
+/// Flags operation `id` to stop at its next block. Returns `false` if it isn't running.
+pub fn cancel(id: u32) -> bool {
+    TABLE.lock(|table| match table.borrow_mut().iter_mut().find(|op| op.id == id) {
+        Some(op) => {
+            op.cancelled = true;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Copies the table, oldest operation first.
+pub fn snapshot() -> Vec<OpInfo, MAX_OPS> {
+    TABLE.lock(|table| table.borrow().clone())
+}
@@ -0,0 +1,63 @@
+//! High-water mark of the main stack, for `/metrics` and `/diag`.
+//!
+//! Every task runs on the one thread-mode executor, so its state lives in the static task
+//! pool and only the main stack grows: deep call chains inside a poll, and interrupt
+//! handlers on top of them. At boot the unused part of it is filled with a pattern, and
+//! the lowest word that no longer holds the pattern marks the deepest use since.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+extern "C" {
+    // From cortex-m-rt's link.x: the top of RAM, where the stack starts, and the end of
+    // the statics, which it grows down towards
+    static _stack_start: u32;
+    static __sheap: u32;
+}
+
+const PAINT: u32 = 0x5AC5_5AC5;
+
+/// Left unpainted below the current stack pointer, for `paint`'s own frame and anything
+/// an interrupt pushes while it runs.
+const MARGIN: usize = 256;
+
+static PAINTED: AtomicBool = AtomicBool::new(false);
+
+fn bounds() -> (usize, usize) {
+    // SAFETY: only the symbols' addresses are taken
+    unsafe { (core::ptr::addr_of!(__sheap) as usize, core::ptr::addr_of!(_stack_start) as usize) }
+}
+
+/// Fills the stack below the current depth with the pattern. Call once, early in `main`.
+pub fn paint() {
+    let (bottom, _) = bounds();
+    let limit = cortex_m::register::msp::read() as usize - MARGIN;
+    let mut word = bottom as *mut u32;
+    while (word as usize) < limit {
+        // SAFETY: between the statics and the live part of the stack nothing is in use
+        unsafe {
+            word.write_volatile(PAINT);
+            word = word.add(1);
+        }
+    }
+    PAINTED.store(true, Ordering::Relaxed);
+}
+
+/// Bytes available to the stack.
+pub fn size() -> usize {
+    let (bottom, top) = bounds();
+    top - bottom
+}
+
+/// Deepest stack use since boot in bytes, or `None` if `paint` hasn't run.
+pub fn high_water() -> Option<usize> {
+    if !PAINTED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let (bottom, top) = bounds();
+    let mut word = bottom as *const u32;
+    // SAFETY: reads stay within the stack region; the scan stops at the first word in use
+    while (word as usize) < top && unsafe { word.read_volatile() } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+    Some(top - word as usize)
+}
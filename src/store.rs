@@ -0,0 +1,189 @@
+//! Where the HTTP layer's file reads and writes go: the SD card, or files held in the
+//! firmware (`mock-sd`, and `bundled-files` while no card is readable).
+//!
+//! Handlers and the scanner take a `&dyn FileStore` from `crate::file_store` instead of
+//! calling the card helpers directly. Each call opens and closes what it needs, so file
+//! bodies are still streamed outside the trait: `send_file`, the archives and
+//! `/download-dir` hold a card file open across awaits, and take a built-in file's bytes
+//! from `MemoryStore::file`.
+
+/// Status line and message for a request that could not be completed.
+#[cfg(not(feature = "sd-only"))]
+pub type HttpError = (&'static [u8], &'static str);
+
+/// Where in a file `FileStore::read` starts reading.
+#[cfg(not(feature = "sd-only"))]
+#[derive(Clone, Copy)]
+pub enum FileWindow {
+    From(u64),
+    /// The last `buf.len()` bytes
+    Tail,
+}
+
+#[cfg(not(feature = "sd-only"))]
+impl FileWindow {
+    /// Where a read of up to `buf_len` bytes starts in a file of `length` bytes. A start
+    /// past the end reads the tail instead, and an empty file is read from 0.
+    pub fn start(self, length: u64, buf_len: usize) -> u64 {
+        match self {
+            FileWindow::From(offset) if offset < length => offset,
+            FileWindow::From(0) => 0,
+            FileWindow::From(_) | FileWindow::Tail => length.saturating_sub(buf_len as u64),
+        }
+    }
+}
+
+/// One directory entry, as `FileStore::list` reports it.
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    pub is_dir: bool,
+    /// FAT attribute bits, as in a directory entry
+    pub attributes: u8,
+    /// Last modified, as FAT packs it (see `MemoryFile`)
+    pub fat_date: u16,
+    pub fat_time: u16,
+}
+
+/// `FileStore::list`'s error for a folder that doesn't exist.
+pub const NO_SUCH_FOLDER: &str = "No such folder";
+
+pub trait FileStore {
+    /// Calls `each` for every entry in the root-level folder `dir`, or in the root
+    /// directory itself if `dir` is empty.
+    fn list(&self, dir: &str, each: &mut dyn FnMut(&Entry<'_>)) -> Result<(), &'static str>;
+
+    /// Reads up to `buf.len()` bytes of `name` from `window`, returning the number of
+    /// bytes read, the offset they start at, and the file's full length. A start past the
+    /// end of the file reads the tail instead.
+    #[cfg(not(feature = "sd-only"))]
+    fn read(
+        &self,
+        name: &str,
+        window: FileWindow,
+        buf: &mut [u8],
+    ) -> Result<(usize, u64, u64), &'static str>;
+
+    /// Replaces the contents of `name` with `data`, creating it if needed.
+    #[cfg(not(feature = "sd-only"))]
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), &'static str>;
+
+    /// Appends `data` to `name`, creating it if needed. Returns the new length.
+    #[cfg(not(feature = "sd-only"))]
+    fn append(&self, name: &str, data: &[u8]) -> Result<u64, HttpError>;
+
+    /// Copies `from` to `to`, returning the number of bytes written.
+    #[cfg(not(feature = "sd-only"))]
+    fn copy(&self, from: &str, to: &str, force: bool) -> Result<u64, HttpError>;
+}
+
+/// A file or folder compiled into the firmware. Files inside a root-level folder are
+/// named with its name and a `/`, e.g. `LOGS/BOOT.LOG`.
+#[cfg(any(not(feature = "sd-only"), feature = "mock-sd", feature = "bundled-files"))]
+pub struct MemoryFile {
+    pub name: &'static str,
+    pub data: &'static [u8],
+    pub is_dir: bool,
+    /// Last modified, as FAT packs it: `((year - 1980) << 9) | (month << 5) | day` and
+    /// `(hour << 11) | (minute << 5) | (second / 2)`
+    pub fat_date: u16,
+    pub fat_time: u16,
+}
+
+/// Read-only files compiled into the firmware, matched case-insensitively like FAT names.
+#[cfg(any(not(feature = "sd-only"), feature = "mock-sd", feature = "bundled-files"))]
+pub struct MemoryStore {
+    pub files: &'static [MemoryFile],
+}
+
+#[cfg(any(not(feature = "sd-only"), feature = "mock-sd", feature = "bundled-files"))]
+impl MemoryStore {
+    /// The contents of `name`.
+    #[cfg(any(not(feature = "sd-only"), feature = "bundled-files"))]
+    pub fn file(&self, name: &str) -> Result<&'static [u8], &'static str> {
+        self.files
+            .iter()
+            .find(|file| !file.is_dir && file.name.eq_ignore_ascii_case(name))
+            .map(|file| file.data)
+            .ok_or("File not found")
+    }
+}
+
+#[cfg(not(feature = "sd-only"))]
+const READ_ONLY: HttpError = (b"403 Forbidden", "Built-in files are read-only");
+
+/// The FAT read-only attribute, which every built-in entry has since writes are refused.
+#[cfg(any(not(feature = "sd-only"), feature = "mock-sd", feature = "bundled-files"))]
+const READ_ONLY_ATTRIBUTE: u8 = 0x01;
+
+#[cfg(any(not(feature = "sd-only"), feature = "mock-sd", feature = "bundled-files"))]
+impl FileStore for MemoryStore {
+    fn list(&self, dir: &str, each: &mut dyn FnMut(&Entry<'_>)) -> Result<(), &'static str> {
+        let is_dir = |file: &MemoryFile| file.is_dir && file.name.eq_ignore_ascii_case(dir);
+        if !dir.is_empty() && !self.files.iter().any(is_dir) {
+            return Err(NO_SUCH_FOLDER);
+        }
+        for file in self.files {
+            let (folder, name) = file.name.rsplit_once('/').unwrap_or(("", file.name));
+            if folder.eq_ignore_ascii_case(dir) {
+                each(&Entry {
+                    name,
+                    size: file.data.len() as u64,
+                    is_dir: file.is_dir,
+                    attributes: READ_ONLY_ATTRIBUTE,
+                    fat_date: file.fat_date,
+                    fat_time: file.fat_time,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn read(
+        &self,
+        name: &str,
+        window: FileWindow,
+        buf: &mut [u8],
+    ) -> Result<(usize, u64, u64), &'static str> {
+        let data = self.file(name)?;
+        let length = data.len() as u64;
+        let start = window.start(length, buf.len()) as usize;
+        let filled = buf.len().min(data.len() - start);
+        buf[..filled].copy_from_slice(&data[start..start + filled]);
+        Ok((filled, start as u64, length))
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn write(&self, _name: &str, _data: &[u8]) -> Result<(), &'static str> {
+        Err(READ_ONLY.1)
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn append(&self, _name: &str, _data: &[u8]) -> Result<u64, HttpError> {
+        Err(READ_ONLY)
+    }
+
+    #[cfg(not(feature = "sd-only"))]
+    fn copy(&self, _from: &str, _to: &str, _force: bool) -> Result<u64, HttpError> {
+        Err(READ_ONLY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileWindow;
+
+    #[test]
+    fn every_window_on_an_empty_file_starts_at_0() {
+        for window in [FileWindow::From(0), FileWindow::From(512), FileWindow::Tail] {
+            assert_eq!(window.start(0, 16 * 1024), 0);
+        }
+    }
+
+    #[test]
+    fn a_start_past_the_end_reads_the_tail() {
+        assert_eq!(FileWindow::From(5000).start(4096, 1024), 3072);
+        assert_eq!(FileWindow::From(100).start(4096, 1024), 100);
+    }
+}
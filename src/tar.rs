@@ -0,0 +1,53 @@
+//! Just enough ustar to stream several files as one download.
+//!
+//! Each entry is a 512-byte header followed by the file data padded to a whole block, and
+//! the archive ends with two zeroed blocks. Sizes are known up front, so the length of the
+//! whole archive can be sent as `Content-Length` before any data is read.
+
+/// Size of a header and of the blocks file data is padded to.
+pub const BLOCK_LEN: usize = 512;
+
+/// The two empty blocks that mark the end of an archive.
+pub const TRAILER_LEN: usize = 2 * BLOCK_LEN;
+
+/// Bytes an entry for a file of `size` bytes takes up, header included.
+pub const fn entry_len(size: u64) -> u64 {
+    BLOCK_LEN as u64 + size.div_ceil(BLOCK_LEN as u64) * BLOCK_LEN as u64
+}
+
+/// Zero bytes needed after a file of `size` bytes to reach the next block boundary.
+pub const fn padding(size: u64) -> usize {
+    (BLOCK_LEN - (size % BLOCK_LEN as u64) as usize) % BLOCK_LEN
+}
+
+/// Writes `value` as zero-padded octal filling `field` except for a trailing NUL.
+fn octal(field: &mut [u8], mut value: u64) {
+    let digits = field.len() - 1;
+    for slot in field[..digits].iter_mut().rev() {
+        *slot = b'0' + (value & 7) as u8;
+        value >>= 3;
+    }
+    field[digits] = 0;
+}
+
+/// Header for a regular file. There's no clock, so every entry is dated 1970.
+pub fn header(name: &str, size: u64) -> [u8; BLOCK_LEN] {
+    let mut header = [0u8; BLOCK_LEN];
+    // Root-directory names are at most 64 bytes, well inside the 100-byte field
+    let name = &name.as_bytes()[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is taken with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum = header.iter().map(|&b| b as u64).sum::<u64>();
+    octal(&mut header[148..155], checksum);
+    header
+}
@@ -0,0 +1,64 @@
+//! Fitting formatted text into the fixed-size strings the firmware keeps it in.
+
+/// `core::fmt::Write` into a fixed-size string that keeps whole characters only, and
+/// notes whether anything had to be left out.
+struct TruncatingWriter<'a, const N: usize> {
+    out: &'a mut heapless::String<N>,
+    cut: bool,
+}
+
+impl<const N: usize> core::fmt::Write for TruncatingWriter<'_, N> {
+    fn write_str(&mut self, text: &str) -> core::fmt::Result {
+        for c in text.chars() {
+            if self.cut || self.out.push(c).is_err() {
+                self.cut = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `args` into at most `N` bytes. Text that doesn't fit is cut on a character
+/// boundary and ends with "…", so the result is always valid UTF-8 and visibly shortened.
+pub fn format_truncated<const N: usize>(args: core::fmt::Arguments<'_>) -> heapless::String<N> {
+    const ELLIPSIS: char = '\u{2026}';
+    let mut out = heapless::String::new();
+    let mut writer = TruncatingWriter { out: &mut out, cut: false };
+    let _ = core::fmt::Write::write_fmt(&mut writer, args);
+    if writer.cut {
+        while out.len() + ELLIPSIS.len_utf8() > N && out.pop().is_some() {}
+        let _ = out.push(ELLIPSIS);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_truncated;
+
+    #[test]
+    fn cuts_before_a_character_that_straddles_the_limit() {
+        // The é would take bytes 7 and 8 of an 8-byte string
+        let name = format_truncated::<8>(format_args!("{}", "abcdefg\u{e9}h"));
+        assert_eq!(name.as_str(), "abcde\u{2026}");
+    }
+
+    #[test]
+    fn leaves_an_exact_fit_alone() {
+        let name = format_truncated::<8>(format_args!("{}", "abcdef\u{e9}"));
+        assert_eq!(name.as_str(), "abcdef\u{e9}");
+    }
+
+    #[test]
+    fn cuts_an_all_multibyte_name_on_a_character_boundary() {
+        let mut long = heapless::String::<80>::new();
+        for _ in 0..40 {
+            long.push('\u{e9}').unwrap();
+        }
+        let name = format_truncated::<64>(format_args!("{}", long));
+        assert!(name.len() <= 64);
+        assert!(name.ends_with('\u{2026}'));
+        assert_eq!(name.chars().filter(|&c| c == '\u{e9}').count(), 30);
+    }
+}
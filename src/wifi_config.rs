@@ -0,0 +1,332 @@
+//! The optional `WIFI.CFG` in the card's root directory, which overrides the built-in
+//! access point and runtime settings at boot.
+//!
+//! The file is plain text with one `key=value` per line:
+//!
+//! ```text
+//! ssid=PicoW_SD_Browser
+//! password=12345678
+//! channel=5
+//! hidden=0
+//! ap_restart=0
+//! name=Workshop
+//! uploads=TXT,CSV
+//! sd_required=0
+//! lang=en
+//! log=info
+//! readonly=0
+//! heartbeat=1
+//! scan_interval=30
+//! refresh=5
+//! power_mode=performance
+//! ```
+//!
+//! Missing keys keep their built-in value. Blank lines, `#` comments and unknown keys are
+//! ignored, so a file written by a newer firmware still loads.
+
+use heapless::String;
+
+use crate::i18n::Lang;
+
+pub const FILE_NAME: &str = "WIFI.CFG";
+
+/// Largest config file read at boot; anything past this is ignored.
+pub const MAX_LEN: usize = 384;
+
+/// Channels usable for the AP in every regulatory domain the CLM blob covers.
+pub const CHANNELS: core::ops::RangeInclusive<u8> = 1..=11;
+
+/// Log level names, indexed by the value stored in `LOG_LEVEL`.
+pub const LOG_LEVELS: [&str; 3] = ["info", "warn", "error"];
+
+/// Seconds between SD card rescans.
+pub const SCAN_INTERVALS: core::ops::RangeInclusive<u16> = 5..=3600;
+
+/// Seconds between listing page reloads; 0 turns auto-refresh off.
+pub const REFRESH_INTERVALS: core::ops::RangeInclusive<u16> = 0..=3600;
+
+/// Hours without a connection before the AP is restarted; 0 never restarts it.
+pub const AP_RESTART_HOURS: core::ops::RangeInclusive<u16> = 0..=168;
+
+/// The radio's power saving, from fastest to most frugal.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PowerMode {
+    Performance,
+    PowerSave,
+    Aggressive,
+}
+
+impl PowerMode {
+    pub const ALL: [PowerMode; 3] = [PowerMode::Performance, PowerMode::PowerSave, PowerMode::Aggressive];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PowerMode::Performance => "performance",
+            PowerMode::PowerSave => "powersave",
+            PowerMode::Aggressive => "aggressive",
+        }
+    }
+
+    /// Index for storing the mode in an atomic.
+    pub fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_u8(value: u8) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or(PowerMode::Performance)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct WifiConfig {
+    pub ssid: String<32>,
+    pub password: String<64>,
+    pub channel: u8,
+    pub lang: Lang,
+    pub log_level: u8,
+    pub read_only: bool,
+    pub heartbeat: bool,
+    pub scan_interval: u16,
+    pub refresh: u16,
+    pub power_mode: PowerMode,
+    /// Leave the SSID out of beacons; only applied when the AP starts.
+    pub hidden: bool,
+    /// Restart the AP after this many hours without a connection, 0 for never. Works
+    /// around the radio firmware occasionally ceasing to accept clients.
+    pub ap_restart_hours: u16,
+    /// Shown as the listing page's title and heading, to tell boards apart; empty for the
+    /// built-in heading.
+    pub name: String<32>,
+    /// Extensions `/copy` and `/append` may write, upper case and comma separated; empty
+    /// allows any name.
+    pub upload_types: String<48>,
+    /// Answer `/` with `503` while the card isn't readable, instead of the listing page
+    /// with a warning.
+    pub sd_required: bool,
+}
+
+fn parse_flag(value: &str, error: &'static str) -> Result<bool, &'static str> {
+    match value {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Parses an `uploads=` list of extensions such as `txt, .csv` into `TXT,CSV`.
+fn parse_upload_types(value: &str) -> Result<String<48>, &'static str> {
+    const ERROR: &str = "WIFI.CFG uploads must be a comma-separated list of extensions";
+    let mut types = String::new();
+    for extension in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        // 8.3 names carry at most three characters of extension
+        if extension.is_empty() || extension.len() > 3 || !extension.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(ERROR);
+        }
+        if !types.is_empty() {
+            types.push(',').map_err(|_| ERROR)?;
+        }
+        for c in extension.chars() {
+            types.push(c.to_ascii_uppercase()).map_err(|_| ERROR)?;
+        }
+    }
+    Ok(types)
+}
+
+/// Whether `name` may be written under an `uploads=` list in the form `parse_upload_types`
+/// produces. Names without an extension only pass an empty list.
+pub fn upload_allowed(types: &str, name: &str) -> bool {
+    if types.is_empty() {
+        return true;
+    }
+    match name.rsplit_once('.') {
+        Some((_, extension)) => types.split(',').any(|allowed| allowed.eq_ignore_ascii_case(extension)),
+        None => false,
+    }
+}
+
+impl WifiConfig {
+    /// Applies the settings in `text` on top of `self`. Values that don't fit or don't
+    /// parse fail the whole file rather than starting the AP half-configured.
+    pub fn parse(mut self, text: &str) -> Result<Self, &'static str> {
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err("WIFI.CFG line without '='");
+            };
+            match key.trim() {
+                "ssid" => {
+                    self.ssid.clear();
+                    self.ssid.push_str(value).map_err(|_| "WIFI.CFG ssid longer than 32 bytes")?;
+                }
+                "password" => {
+                    self.password.clear();
+                    self.password
+                        .push_str(value)
+                        .map_err(|_| "WIFI.CFG password longer than 64 bytes")?;
+                }
+                "channel" => {
+                    self.channel = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|channel| CHANNELS.contains(channel))
+                        .ok_or("WIFI.CFG channel must be 1-11")?;
+                }
+                "hidden" => self.hidden = parse_flag(value.trim(), "WIFI.CFG hidden must be 0 or 1")?,
+                "ap_restart" => {
+                    self.ap_restart_hours = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|hours| AP_RESTART_HOURS.contains(hours))
+                        .ok_or("WIFI.CFG ap_restart must be 0-168")?;
+                }
+                "name" => {
+                    self.name.clear();
+                    self.name.push_str(value.trim()).map_err(|_| "WIFI.CFG name longer than 32 bytes")?;
+                }
+                "uploads" => self.upload_types = parse_upload_types(value)?,
+                "sd_required" => {
+                    self.sd_required = parse_flag(value.trim(), "WIFI.CFG sd_required must be 0 or 1")?;
+                }
+                "lang" => {
+                    self.lang = Lang::from_code(value.trim()).ok_or("WIFI.CFG lang must be en or de")?;
+                }
+                "log" => {
+                    self.log_level = LOG_LEVELS
+                        .iter()
+                        .position(|&level| level == value.trim())
+                        .ok_or("WIFI.CFG log must be info, warn or error")? as u8;
+                }
+                "readonly" => self.read_only = parse_flag(value.trim(), "WIFI.CFG readonly must be 0 or 1")?,
+                "heartbeat" => self.heartbeat = parse_flag(value.trim(), "WIFI.CFG heartbeat must be 0 or 1")?,
+                "scan_interval" => {
+                    self.scan_interval = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|secs| SCAN_INTERVALS.contains(secs))
+                        .ok_or("WIFI.CFG scan_interval must be 5-3600")?;
+                }
+                "refresh" => {
+                    self.refresh = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|secs| REFRESH_INTERVALS.contains(secs))
+                        .ok_or("WIFI.CFG refresh must be 0-3600")?;
+                }
+                "power_mode" => {
+                    self.power_mode = PowerMode::from_name(value.trim())
+                        .ok_or("WIFI.CFG power_mode must be performance, powersave or aggressive")?;
+                }
+                _ => {}
+            }
+        }
+        if self.ssid.is_empty() {
+            return Err("WIFI.CFG ssid is empty");
+        }
+        Ok(self)
+    }
+
+    /// The file contents that `parse` reads back as these settings.
+    pub fn render(&self) -> String<MAX_LEN> {
+        let mut text = String::new();
+        let _ = core::fmt::Write::write_fmt(
+            &mut text,
+            format_args!(
+                "ssid={}\npassword={}\nchannel={}\nhidden={}\nap_restart={}\nname={}\nuploads={}\nsd_required={}\nlang={}\nlog={}\nreadonly={}\nheartbeat={}\n\
+                 scan_interval={}\nrefresh={}\npower_mode={}\n",
+                self.ssid,
+                self.password,
+                self.channel,
+                self.hidden as u8,
+                self.ap_restart_hours,
+                self.name,
+                self.upload_types,
+                self.sd_required as u8,
+                self.lang.code(),
+                LOG_LEVELS[self.log_level as usize % LOG_LEVELS.len()],
+                self.read_only as u8,
+                self.heartbeat as u8,
+                self.scan_interval,
+                self.refresh,
+                self.power_mode.name()
+            ),
+        );
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string<const N: usize>(text: &str) -> String<N> {
+        String::try_from(text).unwrap()
+    }
+
+    /// Settings that differ from `longest` in every field, so a key `parse` skips shows up.
+    fn other() -> WifiConfig {
+        WifiConfig {
+            ssid: string("a"),
+            password: string("12345678"),
+            channel: 1,
+            lang: Lang::En,
+            log_level: 0,
+            read_only: false,
+            heartbeat: true,
+            scan_interval: 30,
+            refresh: 5,
+            power_mode: PowerMode::PowerSave,
+            hidden: false,
+            ap_restart_hours: 0,
+            name: String::new(),
+            upload_types: String::new(),
+            sd_required: false,
+        }
+    }
+
+    /// Every field at its longest rendering.
+    fn longest() -> WifiConfig {
+        WifiConfig {
+            ssid: string("\u{e9}".repeat(16).as_str()),
+            password: string("p".repeat(64).as_str()),
+            channel: 11,
+            lang: Lang::De,
+            log_level: 2,
+            read_only: true,
+            heartbeat: false,
+            scan_interval: 3600,
+            refresh: 3600,
+            power_mode: PowerMode::Performance,
+            hidden: true,
+            ap_restart_hours: 168,
+            name: string("N".repeat(32).as_str()),
+            upload_types: string("AAA,BBB,CCC,DDD,EEE,FFF,GGG,HHH,III,JJJ,KKK,LLL"),
+            sd_required: true,
+        }
+    }
+
+    #[test]
+    fn the_longest_settings_fit_and_read_back() {
+        let settings = longest();
+        assert_eq!(settings.ssid.len(), 32);
+        let text = settings.render();
+        assert!(text.len() < MAX_LEN, "{} bytes", text.len());
+        assert!(other().parse(&text) == Ok(settings));
+    }
+
+    #[test]
+    fn rendered_defaults_read_back() {
+        let settings = other();
+        assert!(longest().parse(&settings.render()) == Ok(settings));
+    }
+}